@@ -0,0 +1,209 @@
+use crate::exr::{Channel, PixelType};
+use crate::scene::Scene;
+use crate::shapes::Ray;
+use crate::types::Fp;
+use crate::vecmath::{Color3F, Vec3F};
+
+/// Arbitrary output variables: passes rendered alongside the beauty image for compositing.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Aov {
+    /// Fraction of the light reflected by the first surface hit, see `Material::albedo`.
+    Albedo,
+    /// World space shading normal of the first surface hit, facing the camera, see
+    /// `RayIntersection::shading_normal`.
+    Normal,
+    /// Distance from the camera to the first surface hit, zero where nothing was hit.
+    Depth,
+    /// Light reflected by a diffuse first surface straight from a light or the sky.
+    DirectDiffuse,
+    /// Light reflected by a diffuse first surface after bouncing off other surfaces.
+    IndirectDiffuse,
+    /// Light reflected or refracted by a metal or glass first surface.
+    Specular,
+    /// Light emitted by the first surface hit, or the sky where nothing was hit.
+    Emission,
+    /// Index of the shape hit, plus one so that zero is left for nothing hit.
+    ObjectId,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 8] = [
+        Aov::Albedo,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::DirectDiffuse,
+        Aov::IndirectDiffuse,
+        Aov::Specular,
+        Aov::Emission,
+        Aov::ObjectId,
+    ];
+
+    /// Names of the channels of the AOV in an EXR layer, in the order of `AovSample::get`. Depth
+    /// and object ID have a single channel.
+    pub fn channel_names(&self) -> &'static [&'static str] {
+        match self {
+            Aov::Normal => &["X", "Y", "Z"],
+            Aov::Depth => &["Z"],
+            Aov::ObjectId => &["id"],
+            _ => &["R", "G", "B"],
+        }
+    }
+
+    /// Depth and object IDs need more precision than half floats.
+    pub fn needs_float(&self) -> bool {
+        matches!(self, Aov::Depth | Aov::ObjectId)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::DirectDiffuse => "direct_diffuse",
+            Aov::IndirectDiffuse => "indirect_diffuse",
+            Aov::Specular => "specular",
+            Aov::Emission => "emission",
+            Aov::ObjectId => "object_id",
+        }
+    }
+}
+
+/// The AOVs of one camera ray, or of a pixel once the samples are averaged.
+///
+/// The lighting passes add up to the beauty image, but only integrators that can tell the paths
+/// apart fill them, see `Integrator::radiance_aovs`.
+#[derive(Copy, Clone, Default)]
+pub struct AovSample {
+    pub albedo: Color3F,
+    pub normal: Vec3F,
+    pub depth: Fp,
+    pub direct_diffuse: Color3F,
+    pub indirect_diffuse: Color3F,
+    pub specular: Color3F,
+    pub emission: Color3F,
+    pub object_id: u32,
+}
+
+impl AovSample {
+    /// Fill the AOVs of the first surface hit by `ray`, the ones that don't depend on lighting.
+    pub fn set_surface(&mut self, scene: &Scene, ray: &Ray) {
+        let (intersection, object_index) = scene.ray_intersect_object(ray, &(0.001..Fp::MAX));
+        if !intersection.hit {
+            return;
+        }
+
+        let material = intersection.material.unwrap();
+        self.albedo = material.albedo(ray, &intersection);
+        self.normal = intersection.shading_normal;
+        self.depth = intersection.t * ray.direction.length();
+        self.object_id = object_index as u32 + 1;
+    }
+
+    /// Add the AOVs of another sample of the same pixel. Object IDs can't be averaged, the pixel
+    /// keeps the first one that isn't zero.
+    pub fn add(&mut self, other: &AovSample) {
+        self.albedo += other.albedo;
+        self.normal += other.normal;
+        self.depth += other.depth;
+        self.direct_diffuse += other.direct_diffuse;
+        self.indirect_diffuse += other.indirect_diffuse;
+        self.specular += other.specular;
+        self.emission += other.emission;
+        if self.object_id == 0 {
+            self.object_id = other.object_id;
+        }
+    }
+
+    /// Scale all the AOVs but the object ID, e.g. to average summed samples.
+    pub fn scale(&mut self, scale: Fp) {
+        self.albedo = self.albedo * scale;
+        self.normal = self.normal * scale;
+        self.depth *= scale;
+        self.direct_diffuse = self.direct_diffuse * scale;
+        self.indirect_diffuse = self.indirect_diffuse * scale;
+        self.specular = self.specular * scale;
+        self.emission = self.emission * scale;
+    }
+
+    /// The value of `aov` as a color. Depth and object ID are repeated in the three channels.
+    pub fn get(&self, aov: Aov) -> Color3F {
+        match aov {
+            Aov::Albedo => self.albedo,
+            Aov::Normal => self.normal,
+            Aov::Depth => Color3F::new(self.depth, self.depth, self.depth),
+            Aov::DirectDiffuse => self.direct_diffuse,
+            Aov::IndirectDiffuse => self.indirect_diffuse,
+            Aov::Specular => self.specular,
+            Aov::Emission => self.emission,
+            Aov::ObjectId => {
+                let id = self.object_id as Fp;
+                Color3F::new(id, id, id)
+            }
+        }
+    }
+}
+
+/// The AOVs of a whole image as EXR layers named after the AOVs, e.g. `albedo.R`, stored as
+/// `pixel_type` unless they need floats.
+pub fn aov_exr_channels(aovs: &[AovSample], pixel_type: PixelType) -> Vec<Channel> {
+    let mut channels = Vec::new();
+    for aov in Aov::ALL {
+        let pixel_type = if aov.needs_float() {
+            PixelType::Float
+        } else {
+            pixel_type
+        };
+        for (axis, channel_name) in aov.channel_names().iter().enumerate() {
+            let values = aovs.iter().map(|sample| sample.get(aov).axis(axis));
+            let name = format!("{}.{}", aov.name(), channel_name);
+            channels.push(Channel::new(&name, pixel_type, values));
+        }
+    }
+    channels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::{Integrator, PathTracer, Splats};
+    use crate::vecmath::luminance;
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    // Splitting the first bounce into passes must not change what the path tracer converges to.
+    #[test]
+    fn lighting_aovs_add_up_to_path_traced_radiance() {
+        let scene = Scene::cornell_box();
+        let size = 16;
+        let spp = 128;
+        let camera = Scene::cornell_box_camera(size, size);
+        let path_tracer = PathTracer::new(&scene);
+
+        let mut rand = SmallRng::seed_from_u64(11);
+        let mut splats = Splats::new(size, size);
+        let mut radiance = Color3F::zero();
+        let mut passes = Color3F::zero();
+        for row in 0..size {
+            for col in 0..size {
+                for _ in 0..spp {
+                    let (rx, ry) = (rand.gen_range(0.0..1.0), rand.gen_range(0.0..1.0));
+                    let ray = camera.gen_ray(col, row, rx, ry, &mut rand);
+                    radiance += path_tracer.radiance(&ray, &mut splats, &mut rand);
+
+                    let mut aovs = AovSample::default();
+                    path_tracer.radiance_aovs(&ray, &mut splats, &mut rand, &mut aovs);
+                    passes +=
+                        aovs.direct_diffuse + aovs.indirect_diffuse + aovs.specular + aovs.emission;
+                }
+            }
+        }
+
+        let expected = luminance(&radiance);
+        let actual = luminance(&passes);
+        assert!(
+            (actual - expected).abs() < 0.05 * expected,
+            "passes add up to {} instead of {}",
+            actual,
+            expected
+        );
+    }
+}
@@ -0,0 +1,529 @@
+use crate::camera::Camera;
+use crate::integrator::{Integrator, Splats};
+use crate::lights::{LightEmitters, LightPoint, UnsupportedEmitter};
+use crate::materials::Material;
+use crate::scene::Scene;
+use crate::shapes::{Ray, RayIntersection};
+use crate::types::Fp;
+use crate::vecmath::{dot, Color3F, Vec3F};
+use rand::RngCore;
+
+#[cfg(not(feature = "use-f64"))]
+use std::f32::consts::PI;
+#[cfg(feature = "use-f64")]
+use std::f64::consts::PI;
+
+#[derive(Copy, Clone, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+/// A vertex of a camera or light subpath, following the notation of chapter 16.3 of "Physically
+/// Based Rendering" (3rd edition).
+#[derive(Copy, Clone)]
+struct Vertex<'a> {
+    kind: VertexKind,
+    p: Vec3F,
+    /// On a surface, the shading normal facing the previous vertex of the subpath. On a light, the
+    /// normal of the light's surface. On the camera, the viewing direction.
+    n: Vec3F,
+    /// The geometric normal, which only differs from `n` on a surface with a bent shading normal.
+    ng: Vec3F,
+    material: Option<&'a Material>,
+    /// Reflectance of a diffuse surface.
+    albedo: Color3F,
+    /// Radiance emitted by a light, or by an emissive surface the camera subpath ran into.
+    emission: Color3F,
+    /// Contribution of the subpath up to this vertex, divided by the probability of sampling it.
+    beta: Color3F,
+    /// Whether the surface scatters light in a single direction, e.g. glass. Such a vertex can't
+    /// be connected to the other subpath.
+    delta: bool,
+    /// Probability density, over area, of sampling this vertex from the previous one.
+    pdf_fwd: Fp,
+    /// Probability density, over area, of sampling this vertex from the next one, i.e. if the
+    /// path had been built from the other end.
+    pdf_rev: Fp,
+}
+
+impl<'a> Vertex<'a> {
+    fn camera(p: Vec3F, forward: Vec3F, beta: Color3F) -> Self {
+        Self {
+            kind: VertexKind::Camera,
+            p,
+            n: forward,
+            ng: forward,
+            material: None,
+            albedo: Color3F::zero(),
+            emission: Color3F::zero(),
+            beta,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn light(point: &LightPoint) -> Self {
+        Self {
+            kind: VertexKind::Light,
+            p: point.p,
+            n: point.normal,
+            ng: point.normal,
+            material: None,
+            albedo: Color3F::zero(),
+            emission: point.emission,
+            beta: point.emission / point.pdf_pos,
+            delta: false,
+            pdf_fwd: point.pdf_pos,
+            pdf_rev: 0.0,
+        }
+    }
+
+    // The vertex where `ray` hit the surface at `intersection`.
+    fn surface(ray: &Ray, intersection: &RayIntersection<'a>, beta: Color3F) -> Self {
+        let material = intersection.material.unwrap();
+        let albedo = match material {
+            Material::Diffuse(mat) => mat.tex_color(ray, intersection),
+            _ => Color3F::zero(),
+        };
+
+        Self {
+            kind: VertexKind::Surface,
+            p: intersection.hit_point,
+            n: intersection.shading_normal,
+            ng: intersection.normal,
+            material: Some(material),
+            albedo,
+            emission: material.emit(),
+            beta,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    /// Whether a subpath ending at this vertex can be joined to a vertex of the other subpath.
+    fn is_connectible(&self) -> bool {
+        match self.kind {
+            VertexKind::Surface => matches!(self.material, Some(Material::Diffuse(_))),
+            _ => true,
+        }
+    }
+
+    /// BSDF of the surface for light going between this vertex and `p`. Only diffuse surfaces
+    /// are connectible, and they reflect on the side of `n` only.
+    fn f(&self, p: &Vec3F) -> Color3F {
+        if self.kind == VertexKind::Surface && dot(&self.n, &(*p - self.p)) > 0.0 {
+            self.albedo / PI
+        } else {
+            Color3F::zero()
+        }
+    }
+
+    /// Convert `pdf_dir`, a density over solid angle of sampling this vertex from `from`, to a
+    /// density over area.
+    fn convert_density(&self, pdf_dir: Fp, from: &Vec3F) -> Fp {
+        let w = self.p - *from;
+        let dist_sqr = w.length_squared();
+        if dist_sqr == 0.0 {
+            return 0.0;
+        }
+        // The camera isn't a surface, so there's no foreshortening.
+        if self.kind == VertexKind::Camera {
+            pdf_dir / dist_sqr
+        } else {
+            pdf_dir * dot(&self.n, &w).abs() / (dist_sqr * Fp::sqrt(dist_sqr))
+        }
+    }
+}
+
+/// Bidirectional path tracing. For each camera ray, a subpath is traced from the camera and
+/// another one from a light, and every vertex of the camera subpath is connected to every vertex
+/// of the light subpath. The contributions of the resulting paths are weighted by multiple
+/// importance sampling, so that each path is mostly counted through the strategy that samples it
+/// best, e.g. caustics through light subpaths connected to the camera.
+///
+/// CSG shapes and instances can't be sampled as lights, see `LightEmitters`, and scenes with such
+/// lights are refused.
+pub struct Bdpt<'a> {
+    scene: &'a Scene,
+    camera: &'a Camera,
+    /// Maximum number of bounces of a path.
+    max_depth: usize,
+    emitters: LightEmitters<'a>,
+}
+
+impl<'a> Bdpt<'a> {
+    pub fn new(
+        scene: &'a Scene,
+        camera: &'a Camera,
+        max_depth: usize,
+    ) -> Result<Self, UnsupportedEmitter> {
+        Ok(Self {
+            scene,
+            camera,
+            max_depth,
+            emitters: LightEmitters::new(scene.lights())?,
+        })
+    }
+
+    // Density over area of sampling `next` from `vertex`, given that the subpath reached `vertex`
+    // from the other side. Diffuse surfaces sample directions around the normal facing where the
+    // light comes from, which is `n` unless the path goes through the surface, in which case
+    // the BSDF is zero anyway.
+    fn pdf(&self, vertex: &Vertex, next: &Vertex) -> Fp {
+        let dir = next.p - vertex.p;
+        let pdf_dir = match vertex.kind {
+            VertexKind::Camera => self.camera.pdf_dir(&vertex.p, &dir),
+            VertexKind::Light => LightEmitters::pdf_dir(&vertex.n, &dir),
+            VertexKind::Surface => match vertex.material {
+                Some(material @ Material::Diffuse(_)) => {
+                    material.scattering_pdf(&vertex.n, &vertex.ng, &Ray::new(vertex.p, dir, 0.0))
+                }
+                _ => 0.0,
+            },
+        };
+        next.convert_density(pdf_dir, &vertex.p)
+    }
+
+    // Extend `path` by following `ray` and scattering off the surfaces it hits, until it reaches
+    // `max_vertices` vertices. `beta` and `pdf_dir` are the throughput of the path and the
+    // density over solid angle of `ray` when it leaves the last vertex of `path`. Return the
+    // radiance from the background, weighted by the throughput, if the path escapes the scene.
+    fn random_walk<R: rand::Rng>(
+        &self,
+        mut ray: Ray,
+        mut beta: Color3F,
+        mut pdf_dir: Fp,
+        path: &mut Vec<Vertex<'a>>,
+        max_vertices: usize,
+        rand: &mut R,
+    ) -> Color3F {
+        while path.len() < max_vertices {
+            let intersection = self.scene.ray_intersect(&ray, &(0.001..Fp::MAX));
+            if !intersection.hit {
+                return beta * self.scene.background(&ray);
+            }
+
+            let mut vertex = Vertex::surface(&ray, &intersection, beta);
+            vertex.pdf_fwd = vertex.convert_density(pdf_dir, &path.last().unwrap().p);
+            path.push(vertex);
+            if path.len() >= max_vertices {
+                break;
+            }
+
+            let material = intersection.material.unwrap();
+            let Some(scattered) = Scene::scatter(&ray, &intersection, material, rand) else {
+                break;
+            };
+
+            let pdf_rev_dir;
+            if scattered.skip_pdf {
+                path.last_mut().unwrap().delta = true;
+                beta = beta * scattered.albedo;
+                pdf_dir = 0.0;
+                pdf_rev_dir = 0.0;
+            } else {
+                let scattering_pdf = material.scattering_pdf(
+                    &intersection.shading_normal,
+                    &intersection.normal,
+                    &scattered.ray,
+                );
+                beta = beta * scattered.albedo * scattering_pdf / scattered.probability;
+                pdf_dir = scattered.probability;
+                let reverse = Ray::new(intersection.hit_point, -ray.direction, ray.time);
+                pdf_rev_dir = material.scattering_pdf(
+                    &intersection.shading_normal,
+                    &intersection.normal,
+                    &reverse,
+                );
+            }
+
+            let n = path.len();
+            path[n - 2].pdf_rev = path[n - 2].convert_density(pdf_rev_dir, &path[n - 1].p);
+            ray = scattered.ray;
+        }
+
+        Color3F::zero()
+    }
+
+    // Trace the camera subpath starting with `ray`. Return the radiance from the background the
+    // subpath runs into, which no other strategy can find.
+    fn camera_subpath<R: rand::Rng>(
+        &self,
+        ray: &Ray,
+        path: &mut Vec<Vertex<'a>>,
+        rand: &mut R,
+    ) -> Color3F {
+        let beta = Color3F::new(1.0, 1.0, 1.0);
+        path.push(Vertex::camera(ray.origin, self.camera.forward(), beta));
+        let pdf_dir = self.camera.pdf_dir(&ray.origin, &ray.direction);
+        self.random_walk(*ray, beta, pdf_dir, path, self.max_depth + 2, rand)
+    }
+
+    fn light_subpath<R: rand::Rng>(&self, time: Fp, path: &mut Vec<Vertex<'a>>, rand: &mut R) {
+        let Some(point) = self.emitters.sample_point(time, rand) else {
+            return;
+        };
+        path.push(Vertex::light(&point));
+
+        let (dir, pdf_dir) = LightEmitters::sample_dir(&point, rand);
+        let cos_theta = dot(&point.normal, &dir).abs();
+        let beta = point.emission * cos_theta / (point.pdf_pos * pdf_dir);
+        let ray = Ray::new(point.p, dir, time);
+        self.random_walk(ray, beta, pdf_dir, path, self.max_depth + 1, rand);
+    }
+
+    // Whether nothing blocks the segment between `a` and `b`.
+    fn visible(&self, a: &Vec3F, b: &Vec3F, time: Fp) -> bool {
+        let w = *b - *a;
+        let dist = w.length();
+        let ray = Ray::new(*a, w / dist, time);
+        !self.scene.ray_intersect(&ray, &(0.001..(dist - 0.001))).hit
+    }
+
+    // The geometry term between two vertices on surfaces, without the visibility.
+    fn geometry(a: &Vertex, b: &Vertex) -> Fp {
+        let w = b.p - a.p;
+        let dist_sqr = w.length_squared();
+        dot(&a.n, &w).abs() * dot(&b.n, &w).abs() / (dist_sqr * dist_sqr)
+    }
+
+    // The contribution of the path made of the first `s` vertices of `light_path` and the first
+    // `t` vertices of `camera_path`. With `t == 1`, the contribution lands on the pixel the light
+    // subpath is seen through, and it's added to `splats` instead of being returned.
+    #[allow(clippy::too_many_arguments)]
+    fn connect<R: rand::Rng>(
+        &self,
+        light_path: &[Vertex<'a>],
+        camera_path: &[Vertex<'a>],
+        s: usize,
+        t: usize,
+        time: Fp,
+        splats: &mut Splats,
+        rand: &mut R,
+    ) -> Color3F {
+        // For `s == 1` and `t == 1`, the light or camera endpoint is sampled anew, so that it fits
+        // the other subpath.
+        let mut sampled = None;
+        let mut raster = None;
+
+        let l = if s == 0 {
+            // The camera subpath ran into a light.
+            let pt = &camera_path[t - 1];
+            pt.beta * pt.emission
+        } else if t == 1 {
+            let qs = &light_path[s - 1];
+            if !qs.is_connectible() {
+                return Color3F::zero();
+            }
+            let lens_point = self.camera.sample_lens(rand);
+            let Some(position) = self.camera.raster_position(&lens_point, &qs.p) else {
+                return Color3F::zero();
+            };
+
+            let w = lens_point - qs.p;
+            let dist_sqr = w.length_squared();
+            let cos_q = dot(&qs.n, &w).abs() / dist_sqr.sqrt();
+            let cos_c = dot(&self.camera.forward(), &(-w)) / dist_sqr.sqrt();
+            let importance = self.camera.importance(&lens_point, &(-w)) * cos_c;
+            let l = qs.beta * qs.f(&lens_point) * (importance * cos_q / dist_sqr);
+
+            sampled = Some(Vertex::camera(
+                lens_point,
+                self.camera.forward(),
+                Color3F::new(importance, importance, importance),
+            ));
+            raster = Some(position);
+            l
+        } else if s == 1 {
+            let pt = &camera_path[t - 1];
+            if !pt.is_connectible() {
+                return Color3F::zero();
+            }
+            let Some(point) = self.emitters.sample_point(time, rand) else {
+                return Color3F::zero();
+            };
+            let light_vertex = Vertex::light(&point);
+            sampled = Some(light_vertex);
+            pt.beta * pt.f(&light_vertex.p) * light_vertex.beta * Self::geometry(pt, &light_vertex)
+        } else {
+            let qs = &light_path[s - 1];
+            let pt = &camera_path[t - 1];
+            if !qs.is_connectible() || !pt.is_connectible() {
+                return Color3F::zero();
+            }
+            qs.beta * qs.f(&pt.p) * pt.f(&qs.p) * pt.beta * Self::geometry(qs, pt)
+        };
+
+        if l.approx_zero() {
+            return Color3F::zero();
+        }
+
+        // Every strategy except `s == 0` connects two vertices, which must see each other.
+        if s > 0 {
+            let qs = if s == 1 { sampled.as_ref() } else { None }.unwrap_or(&light_path[s - 1]);
+            let pt = if t == 1 { sampled.as_ref() } else { None }.unwrap_or(&camera_path[t - 1]);
+            if !self.visible(&qs.p, &pt.p, time) {
+                return Color3F::zero();
+            }
+        }
+
+        let l = l * self.mis_weight(light_path, camera_path, sampled.as_ref(), s, t);
+        if let Some((x, y)) = raster {
+            splats.add(x, y, l);
+            Color3F::zero()
+        } else {
+            l
+        }
+    }
+
+    // The balance heuristic weight of the strategy joining `s` light vertices with `t` camera
+    // vertices: its probability density of sampling the path, divided by the sum of the densities
+    // of all the strategies that could have sampled the same path.
+    fn mis_weight(
+        &self,
+        light_path: &[Vertex<'a>],
+        camera_path: &[Vertex<'a>],
+        sampled: Option<&Vertex<'a>>,
+        s: usize,
+        t: usize,
+    ) -> Fp {
+        if s + t == 2 {
+            return 1.0;
+        }
+
+        // Work on copies so that the densities can be updated as if the subpaths were joined.
+        let mut light: Vec<Vertex> = light_path[..s].to_vec();
+        let mut camera: Vec<Vertex> = camera_path[..t].to_vec();
+        if let Some(sampled) = sampled {
+            if s == 1 {
+                light[0] = *sampled;
+            } else if t == 1 {
+                camera[0] = *sampled;
+            }
+        }
+
+        // The densities of the connected endpoints, and of the vertices before them, sampled from
+        // the other subpath.
+        let pt = camera[t - 1];
+        camera[t - 1].pdf_rev = if s > 0 {
+            self.pdf(&light[s - 1], &pt)
+        } else {
+            self.emitters.pdf_pos(&pt.emission)
+        };
+        if t > 1 {
+            camera[t - 2].pdf_rev = if s > 0 {
+                self.pdf(&pt, &camera[t - 2])
+            } else {
+                let pdf_dir = LightEmitters::pdf_dir(&pt.n, &(camera[t - 2].p - pt.p));
+                camera[t - 2].convert_density(pdf_dir, &pt.p)
+            };
+        }
+        if s > 0 {
+            let qs = light[s - 1];
+            light[s - 1].pdf_rev = self.pdf(&pt, &qs);
+            if s > 1 {
+                light[s - 2].pdf_rev = self.pdf(&qs, &light[s - 2]);
+            }
+        }
+
+        // Zero densities come from delta vertices, which are skipped anyway.
+        let remap0 = |pdf: Fp| if pdf != 0.0 { pdf } else { 1.0 };
+
+        // Each ratio is the density of the strategy with one more light vertex or one more camera
+        // vertex, relative to the density of this strategy.
+        let mut sum_ri = 0.0;
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            ri *= remap0(camera[i].pdf_rev) / remap0(camera[i].pdf_fwd);
+            if !camera[i].delta && !camera[i - 1].delta {
+                sum_ri += ri;
+            }
+        }
+
+        ri = 1.0;
+        for i in (0..s).rev() {
+            ri *= remap0(light[i].pdf_rev) / remap0(light[i].pdf_fwd);
+            let delta_before = i > 0 && light[i - 1].delta;
+            if !light[i].delta && !delta_before {
+                sum_ri += ri;
+            }
+        }
+
+        1.0 / (1.0 + sum_ri)
+    }
+
+    fn trace<R: rand::Rng>(&self, ray: &Ray, splats: &mut Splats, rand: &mut R) -> Color3F {
+        let mut camera_path = Vec::with_capacity(self.max_depth + 2);
+        let mut light_path = Vec::with_capacity(self.max_depth + 1);
+
+        let mut l = self.camera_subpath(ray, &mut camera_path, rand);
+        self.light_subpath(ray.time, &mut light_path, rand);
+
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                // The camera can't be seen by the camera, and a path of `s + t` vertices bounces
+                // `s + t - 2` times.
+                if s + t < 2 || (s == 1 && t == 1) || s + t - 2 > self.max_depth {
+                    continue;
+                }
+                l += self.connect(&light_path, &camera_path, s, t, ray.time, splats, rand);
+            }
+        }
+
+        l
+    }
+}
+
+impl<'a> Integrator for Bdpt<'a> {
+    fn radiance(&self, ray: &Ray, splats: &mut Splats, mut rand: &mut dyn RngCore) -> Color3F {
+        self.trace(ray, splats, &mut rand)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::PathTracer;
+    use crate::vecmath::luminance;
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    // Average of the image rendered by `integrator` with `spp` samples per pixel.
+    fn mean_radiance(integrator: &dyn Integrator, camera: &Camera, size: u32, spp: u32) -> Fp {
+        let mut rand = SmallRng::seed_from_u64(7);
+        let mut splats = Splats::new(size, size);
+        let mut sum = Color3F::zero();
+        for row in 0..size {
+            for col in 0..size {
+                for _ in 0..spp {
+                    let (rx, ry) = (rand.gen_range(0.0..1.0), rand.gen_range(0.0..1.0));
+                    let ray = camera.gen_ray(col, row, rx, ry, &mut rand);
+                    sum += integrator.radiance(&ray, &mut splats, &mut rand);
+                }
+            }
+        }
+        for splat in splats.pixels.iter() {
+            sum += *splat;
+        }
+        luminance(&sum) / (size * size * spp) as Fp
+    }
+
+    #[test]
+    fn bdpt_matches_path_tracer() {
+        let scene = Scene::cornell_box();
+        let size = 16;
+        let camera = Scene::cornell_box_camera(size, size);
+
+        let expected = mean_radiance(&PathTracer::new(&scene), &camera, size, 256);
+        let actual = mean_radiance(&Bdpt::new(&scene, &camera, 50).unwrap(), &camera, size, 64);
+        assert!(
+            (actual - expected).abs() < 0.05 * expected,
+            "mean radiance {} instead of {}",
+            actual,
+            expected
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use crate::shapes::{Aabb, Ray, RayIntersection, Shape};
+use crate::types::Fp;
+use std::ops::Range;
+use std::sync::Arc;
+
+pub struct BvhLeaf {
+    pub aabb: Aabb,
+    pub shape_index: usize,
+}
+
+pub struct BvhLink {
+    pub aabb: Aabb,
+    pub left: Arc<BvhNode>,
+    pub right: Arc<BvhNode>,
+}
+
+pub enum BvhNode {
+    Leaf(BvhLeaf),
+    Link(BvhLink),
+}
+
+impl BvhNode {
+    pub fn aabb(&self) -> &Aabb {
+        match self {
+            BvhNode::Leaf(leaf) => &leaf.aabb,
+            BvhNode::Link(link) => &link.aabb,
+        }
+    }
+}
+
+/// Build a BVH over `shapes`. `shapes` is reordered so that shapes close to each other end up in
+/// the same subtree.
+pub fn build_bvh(shapes: &mut [Shape], shape_start: usize) -> Arc<BvhNode> {
+    if shapes.len() == 1 {
+        Arc::new(BvhNode::Leaf(BvhLeaf {
+            aabb: shapes[0].calc_aabb(),
+            shape_index: shape_start,
+        }))
+    } else {
+        // Split along the axis where the centroids spread the most.
+        let centroids: Vec<_> = shapes.iter().map(|s| s.calc_aabb().centroid()).collect();
+        let axis = Aabb::from_points(&centroids).longest_axis();
+        shapes.sort_by(|a, b| {
+            let ca = a.calc_aabb().centroid().axis(axis);
+            let cb = b.calc_aabb().centroid().axis(axis);
+            ca.total_cmp(&cb)
+        });
+
+        let middle = shapes.len() / 2;
+        let (left_shapes, right_shapes) = shapes.split_at_mut(middle);
+        let left = build_bvh(left_shapes, shape_start);
+        let right = build_bvh(right_shapes, shape_start + middle);
+
+        Arc::new(BvhNode::Link(BvhLink {
+            aabb: Aabb::merge(left.aabb(), right.aabb()),
+            left,
+            right,
+        }))
+    }
+}
+
+pub fn bvh_ray_intersect<'a>(
+    bvh_node: &BvhNode,
+    shapes: &'a [Shape],
+    ray: &Ray,
+    limits: &Range<Fp>,
+) -> (RayIntersection<'a>, usize) {
+    let mut cost = 0;
+    bvh_ray_intersect_with_cost(bvh_node, shapes, ray, limits, &mut cost)
+}
+
+/// Same as `bvh_ray_intersect`, and add the number of AABBs and shapes tested against `ray` to
+/// `cost`. A shape made of other shapes, e.g. an instance, counts as one.
+pub fn bvh_ray_intersect_with_cost<'a>(
+    bvh_node: &BvhNode,
+    shapes: &'a [Shape],
+    ray: &Ray,
+    limits: &Range<Fp>,
+    cost: &mut usize,
+) -> (RayIntersection<'a>, usize) {
+    *cost += 1;
+    if bvh_node.aabb().ray_intersect(ray) {
+        match bvh_node {
+            BvhNode::Leaf(leaf) => {
+                *cost += 1;
+                (
+                    shapes[leaf.shape_index].ray_intersect(ray, limits),
+                    leaf.shape_index,
+                )
+            }
+            BvhNode::Link(link) => {
+                let (left_intersection, left_sphere_index) =
+                    bvh_ray_intersect_with_cost(&link.left, shapes, ray, limits, cost);
+                let right_limits = limits.start..if left_intersection.hit {
+                    left_intersection.t
+                } else {
+                    limits.end
+                };
+                let (right_intersection, right_sphere_index) =
+                    bvh_ray_intersect_with_cost(&link.right, shapes, ray, &right_limits, cost);
+                if right_intersection.hit {
+                    (right_intersection, right_sphere_index)
+                } else {
+                    (left_intersection, left_sphere_index)
+                }
+            }
+        }
+    } else {
+        (
+            RayIntersection {
+                hit: false,
+                ..Default::default()
+            },
+            0,
+        )
+    }
+}
+
+/// Call `f` with the index of every shape whose AABB is hit by `ray`. Unlike `bvh_ray_intersect`
+/// it doesn't stop at the nearest shape.
+pub fn bvh_for_each_candidate<F: FnMut(usize)>(bvh_node: &BvhNode, ray: &Ray, f: &mut F) {
+    if bvh_node.aabb().ray_intersect(ray) {
+        match bvh_node {
+            BvhNode::Leaf(leaf) => f(leaf.shape_index),
+            BvhNode::Link(link) => {
+                bvh_for_each_candidate(&link.left, ray, f);
+                bvh_for_each_candidate(&link.right, ray, f);
+            }
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use crate::shapes::Ray;
 use crate::types::Fp;
-use crate::vecmath::{cross, Vec3F};
+use crate::vecmath::{cross, dot, Vec3F};
 #[cfg(not(feature = "use-64bit-float"))]
 use std::f32::consts::PI;
 #[cfg(feature = "use-64bit-float")]
@@ -15,6 +15,20 @@ pub struct Camera {
 
     defocus_disk_u: Vec3F,
     defocus_disk_v: Vec3F,
+
+    shutter_open: Fp,
+    shutter_close: Fp,
+
+    // Used to project points in the scene back onto the image.
+    pixel_width: u32,
+    pixel_height: u32,
+    forward: Vec3F,
+    focus_length: Fp,
+    viewport_upper_left: Vec3F,
+    viewport_area: Fp,
+
+    // Angle between the rays through the centers of neighboring pixels.
+    pixel_spread_angle: Fp,
 }
 
 #[derive(Default)]
@@ -30,6 +44,9 @@ pub struct CameraBuilder {
     focus_length: Fp,
 
     defocus_angle: Fp,
+
+    shutter_open: Fp,
+    shutter_close: Fp,
 }
 
 impl Camera {
@@ -56,7 +73,76 @@ impl Camera {
 
         let ray_origin = self.defocus_disk_sample(rand);
 
-        Ray::new(ray_origin, (pixel_center + random_sample) - ray_origin)
+        // Pick a random moment while the shutter is open.
+        let ray_time = if self.shutter_close > self.shutter_open {
+            rand.gen_range(self.shutter_open..self.shutter_close)
+        } else {
+            self.shutter_open
+        };
+
+        Ray::new(
+            ray_origin,
+            (pixel_center + random_sample) - ray_origin,
+            ray_time,
+        )
+        .with_cone(0.0, self.pixel_spread_angle)
+    }
+
+    /// The direction the camera looks at, normalized.
+    pub fn forward(&self) -> Vec3F {
+        self.forward
+    }
+
+    /// Where the ray from `lens_point` through `p` crosses the image, in pixels. Return `None` if
+    /// it falls outside of the image.
+    pub fn raster_position(&self, lens_point: &Vec3F, p: &Vec3F) -> Option<(Fp, Fp)> {
+        let dir = *p - *lens_point;
+        let forward_dist = dot(&dir, &self.forward);
+        if forward_dist <= 0.0 {
+            return None;
+        }
+
+        // The lens and the focus plane are `focus_length` apart along the viewing direction.
+        let on_viewport =
+            *lens_point + dir * (self.focus_length / forward_dist) - self.viewport_upper_left;
+        let x = dot(&on_viewport, &self.viewport_delta_u) / self.viewport_delta_u.length_squared();
+        let y = dot(&on_viewport, &self.viewport_delta_v) / self.viewport_delta_v.length_squared();
+
+        if x >= 0.0 && x < self.pixel_width as Fp && y >= 0.0 && y < self.pixel_height as Fp {
+            Some((x, y))
+        } else {
+            None
+        }
+    }
+
+    /// The probability density, over solid angle, of the camera casting a ray from `lens_point`
+    /// in direction `dir`. The rays of all the pixels are counted together, i.e. the density
+    /// integrates to one over the whole image.
+    pub fn pdf_dir(&self, lens_point: &Vec3F, dir: &Vec3F) -> Fp {
+        if self
+            .raster_position(lens_point, &(*lens_point + *dir))
+            .is_none()
+        {
+            return 0.0;
+        }
+
+        // Rays are spread uniformly over the viewport, and a patch of the viewport subtends a
+        // solid angle of area * cos(a) / distance^2, where distance = focus_length / cos(a).
+        let cos_theta = dot(&dir.normalized(), &self.forward);
+        self.focus_length * self.focus_length / (self.viewport_area * cos_theta.powi(3))
+    }
+
+    /// The importance emitted by the camera from a point on its lens towards `dir`, the
+    /// counterpart of the radiance emitted by lights. Like `pdf_dir`, it's normalized over the
+    /// whole image, and it's divided by the density of picking the point on the lens.
+    pub fn importance(&self, lens_point: &Vec3F, dir: &Vec3F) -> Fp {
+        let cos_theta = dot(&dir.normalized(), &self.forward);
+        self.pdf_dir(lens_point, dir) / cos_theta
+    }
+
+    /// Return a random point on the lens.
+    pub fn sample_lens<R: rand::Rng>(&self, rand: &mut R) -> Vec3F {
+        self.defocus_disk_sample(rand)
     }
 
     fn defocus_disk_sample<R: rand::Rng>(&self, rand: &mut R) -> Vec3F {
@@ -115,6 +201,16 @@ impl CameraBuilder {
         self
     }
 
+    /// The time interval during which the shutter is open. Rays are cast at random moments within
+    /// [open, close), which blurs moving shapes. Moving shapes are keyed at time 0.0 and 1.0, and
+    /// their bounds only cover that interval, so the shutter must open and close within it.
+    pub fn shutter(mut self, open: Fp, close: Fp) -> CameraBuilder {
+        assert!(0.0 <= open && open <= close && close <= 1.0);
+        self.shutter_open = open;
+        self.shutter_close = close;
+        self
+    }
+
     pub fn build(self) -> Camera {
         let aspect_ratio = (self.pixel_width as Fp) / (self.pixel_height as Fp);
 
@@ -151,6 +247,15 @@ impl CameraBuilder {
             viewport_delta_v,
             defocus_disk_u: camera_x * defocus_disk_radius,
             defocus_disk_v: camera_y * defocus_disk_radius,
+            shutter_open: self.shutter_open,
+            shutter_close: self.shutter_close,
+            pixel_width: self.pixel_width,
+            pixel_height: self.pixel_height,
+            forward: -camera_z,
+            focus_length: self.focus_length,
+            viewport_upper_left,
+            viewport_area: viewport_width * viewport_height,
+            pixel_spread_angle: 2.0 * fov_tangent / self.pixel_height as Fp,
         }
     }
 }
@@ -0,0 +1,292 @@
+use crate::types::Fp;
+use libc::{c_int, c_uchar, c_void};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+#[link(name = "stb_image")]
+extern "C" {
+    fn stbi_zlib_compress(
+        data: *mut c_uchar,
+        data_len: c_int,
+        out_len: *mut c_int,
+        quality: c_int,
+    ) -> *mut c_uchar;
+}
+
+/// How the values of a channel are stored.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PixelType {
+    /// 16-bit floats, enough for colors and half the size.
+    Half,
+    /// 32-bit floats, for data that needs the precision such as depth or IDs.
+    Float,
+}
+
+impl PixelType {
+    fn id(&self) -> i32 {
+        match self {
+            PixelType::Half => 1,
+            PixelType::Float => 2,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Compression {
+    None,
+    /// Deflate over blocks of 16 scanlines, lossless.
+    Zip,
+}
+
+impl Compression {
+    fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zip => 3,
+        }
+    }
+
+    fn lines_per_block(&self) -> usize {
+        match self {
+            Compression::None => 1,
+            Compression::Zip => 16,
+        }
+    }
+}
+
+/// One channel of an EXR image. Layers are channels sharing a prefix, e.g. `albedo.R`,
+/// `albedo.G` and `albedo.B`.
+pub struct Channel {
+    pub name: String,
+    pub pixel_type: PixelType,
+    /// Values of the pixels, row by row from the top.
+    pub values: Vec<f32>,
+}
+
+impl Channel {
+    // The cast is only needed when `Fp` is f64.
+    #[allow(clippy::unnecessary_cast)]
+    pub fn new<I: IntoIterator<Item = Fp>>(name: &str, pixel_type: PixelType, values: I) -> Self {
+        Self {
+            name: name.to_string(),
+            pixel_type,
+            values: values.into_iter().map(|value| value as f32).collect(),
+        }
+    }
+}
+
+/// Convert to the nearest 16-bit float, ties to even. Values too large become infinities.
+pub fn f32_to_half(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    if exponent == 0xff {
+        // Infinities stay infinities and NaNs stay NaNs.
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+
+    if exponent <= 0 {
+        // Subnormal, or too small even for that.
+        if exponent < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        let half = mantissa >> shift;
+        let rest = mantissa & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        let round_up = rest > halfway || (rest == halfway && half & 1 == 1);
+        return sign | (half + round_up as u32) as u16;
+    }
+
+    // Rounding up may carry into the exponent, up to infinity, which is what we want.
+    let half = ((exponent as u32) << 10) | (mantissa >> 13);
+    let rest = mantissa & 0x1fff;
+    let round_up = rest > 0x1000 || (rest == 0x1000 && half & 1 == 1);
+    sign | (half + round_up as u32) as u16
+}
+
+fn write_attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend(name.as_bytes());
+    header.push(0);
+    header.extend(kind.as_bytes());
+    header.push(0);
+    header.extend((value.len() as i32).to_le_bytes());
+    header.extend(value);
+}
+
+fn box2i(width: usize, height: usize) -> Vec<u8> {
+    [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect()
+}
+
+// Reorder and delta-encode the bytes of a block the way the ZIP compression of OpenEXR expects
+// them before deflating: first the bytes at even offsets, then the ones at odd offsets.
+fn zip_predict(raw: &[u8]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = raw.iter().step_by(2).copied().collect();
+    bytes.extend(raw.iter().skip(1).step_by(2));
+
+    let mut previous = bytes.first().copied().unwrap_or(0);
+    for byte in bytes.iter_mut().skip(1) {
+        let current = *byte;
+        *byte = current.wrapping_sub(previous).wrapping_add(128);
+        previous = current;
+    }
+    bytes
+}
+
+fn zlib_compress(data: &mut [u8]) -> Vec<u8> {
+    let mut out_len = 0;
+    unsafe {
+        let out = stbi_zlib_compress(data.as_mut_ptr(), data.len() as c_int, &mut out_len, 8);
+        let compressed = std::slice::from_raw_parts(out, out_len as usize).to_vec();
+        libc::free(out as *mut c_void);
+        compressed
+    }
+}
+
+/// Write a single-part scanline OpenEXR file. Each channel holds `width * height` values, in any
+/// order; they are sorted by name as the format requires.
+pub fn write_exr(
+    filename: &Path,
+    width: usize,
+    height: usize,
+    mut channels: Vec<Channel>,
+    compression: Compression,
+) -> io::Result<()> {
+    assert!(channels
+        .iter()
+        .all(|channel| channel.values.len() == width * height));
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    // Magic number, then version 2 with no flags: single-part, scanlines, short names.
+    let mut header = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+
+    let mut channel_list = Vec::new();
+    for channel in channels.iter() {
+        channel_list.extend(channel.name.as_bytes());
+        channel_list.push(0);
+        channel_list.extend(channel.pixel_type.id().to_le_bytes());
+        // pLinear and reserved bytes, then x and y sampling.
+        channel_list.extend([0, 0, 0, 0]);
+        channel_list.extend(1i32.to_le_bytes());
+        channel_list.extend(1i32.to_le_bytes());
+    }
+    channel_list.push(0);
+
+    write_attribute(&mut header, "channels", "chlist", &channel_list);
+    write_attribute(
+        &mut header,
+        "compression",
+        "compression",
+        &[compression.id()],
+    );
+    write_attribute(&mut header, "dataWindow", "box2i", &box2i(width, height));
+    write_attribute(&mut header, "displayWindow", "box2i", &box2i(width, height));
+    write_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    write_attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1.0f32.to_le_bytes(),
+    );
+    write_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    write_attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1.0f32.to_le_bytes(),
+    );
+    header.push(0);
+
+    // Each block holds the scanlines of all the channels one after the other, line by line.
+    let lines_per_block = compression.lines_per_block();
+    let mut blocks = Vec::new();
+    for first_line in (0..height).step_by(lines_per_block) {
+        let lines = first_line..usize::min(first_line + lines_per_block, height);
+        let mut raw = Vec::new();
+        for line in lines {
+            for channel in channels.iter() {
+                let values = &channel.values[(line * width)..((line + 1) * width)];
+                for &value in values {
+                    match channel.pixel_type {
+                        PixelType::Half => raw.extend(f32_to_half(value).to_le_bytes()),
+                        PixelType::Float => raw.extend(value.to_le_bytes()),
+                    }
+                }
+            }
+        }
+
+        let data = match compression {
+            Compression::None => raw,
+            Compression::Zip => {
+                // Blocks that don't shrink are stored as they are.
+                let compressed = zlib_compress(&mut zip_predict(&raw));
+                if compressed.len() < raw.len() {
+                    compressed
+                } else {
+                    raw
+                }
+            }
+        };
+        blocks.push((first_line, data));
+    }
+
+    let mut out = BufWriter::new(File::create(filename)?);
+    out.write_all(&header)?;
+
+    // The offset table, from the start of the file to each block.
+    let mut offset = (header.len() + blocks.len() * 8) as u64;
+    for (_, data) in blocks.iter() {
+        out.write_all(&offset.to_le_bytes())?;
+        offset += (8 + data.len()) as u64;
+    }
+
+    for (first_line, data) in blocks.iter() {
+        out.write_all(&(*first_line as i32).to_le_bytes())?;
+        out.write_all(&(data.len() as i32).to_le_bytes())?;
+        out.write_all(data)?;
+    }
+
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_conversion_rounds_to_nearest() {
+        assert_eq!(f32_to_half(0.0), 0x0000);
+        assert_eq!(f32_to_half(-0.0), 0x8000);
+        assert_eq!(f32_to_half(1.0), 0x3c00);
+        assert_eq!(f32_to_half(-2.0), 0xc000);
+        assert_eq!(f32_to_half(0.5), 0x3800);
+        assert_eq!(f32_to_half(65504.0), 0x7bff);
+        // Halfway between 65504 and infinity rounds to even, which is infinity.
+        assert_eq!(f32_to_half(65520.0), 0x7c00);
+        assert_eq!(f32_to_half(1e10), 0x7c00);
+        assert_eq!(f32_to_half(f32::INFINITY), 0x7c00);
+        assert!(f32_to_half(f32::NAN) & 0x7fff > 0x7c00);
+        // Smallest subnormal, and values rounding to it or to zero.
+        assert_eq!(f32_to_half(5.960_464_5e-8), 0x0001);
+        assert_eq!(f32_to_half(4.0e-8), 0x0001);
+        assert_eq!(f32_to_half(2.0e-8), 0x0000);
+        // Largest subnormal and smallest normal.
+        assert_eq!(f32_to_half(6.097_555e-5), 0x03ff);
+        assert_eq!(f32_to_half(6.103_515_6e-5), 0x0400);
+        // 1 + 2^-11 is halfway between 1 and the next half, and rounds to even.
+        assert_eq!(f32_to_half(1.0 + 1.0 / 2048.0), 0x3c00);
+        assert_eq!(f32_to_half(1.0 + 3.0 / 2048.0), 0x3c02);
+    }
+}
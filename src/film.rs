@@ -0,0 +1,136 @@
+use crate::exr::{Channel, PixelType};
+use crate::image::{write_hdr, Image, ImageError};
+use crate::integrator::Splats;
+use crate::types::Fp;
+use crate::vecmath::{Color3F, Color3U8};
+use std::path::Path;
+
+pub fn nan_to_zero(color: &Color3F) -> Color3F {
+    Color3F::new(
+        if color.x != color.x { 0.0 } else { color.x },
+        if color.y != color.y { 0.0 } else { color.y },
+        if color.z != color.z { 0.0 } else { color.z },
+    )
+}
+
+/// One pixel of a `Film`: the sums of the samples traced through it, so that more can be added.
+#[derive(Copy, Clone, Default)]
+pub struct FilmPixel {
+    /// Sum of the linear radiance of the samples.
+    pub color_sum: Color3F,
+    /// Sum of the coverage of the samples, one for a sample that hit a surface and zero for one
+    /// that went to the background. Only collected by films with alpha.
+    pub alpha_sum: Fp,
+    pub sample_count: u32,
+    /// Radiance splatted onto the pixel by the paths of other pixels, already weighted.
+    pub splat: Color3F,
+}
+
+impl FilmPixel {
+    pub fn add_sample(&mut self, color: Color3F, alpha: Fp) {
+        self.color_sum += color;
+        self.alpha_sum += alpha;
+        self.sample_count += 1;
+    }
+
+    /// Add the samples and splats of `other`, e.g. the same pixel traced in another pass.
+    pub fn merge(&mut self, other: &FilmPixel) {
+        self.color_sum += other.color_sum;
+        self.alpha_sum += other.alpha_sum;
+        self.sample_count += other.sample_count;
+        self.splat += other.splat;
+    }
+
+    /// Average radiance of the samples, plus the splats.
+    pub fn color(&self) -> Color3F {
+        if self.sample_count == 0 {
+            self.splat
+        } else {
+            self.color_sum / self.sample_count as Fp + self.splat
+        }
+    }
+
+    /// Fraction of the samples that hit a surface.
+    pub fn alpha(&self) -> Fp {
+        if self.sample_count == 0 {
+            0.0
+        } else {
+            self.alpha_sum / self.sample_count as Fp
+        }
+    }
+}
+
+/// A high dynamic range image the renderer collects linear radiance into. Nothing is clamped or
+/// quantized until the film is written out, and pixels keep the sums of their samples, so that a
+/// render can be refined progressively.
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    /// Whether the coverage of the samples is collected as alpha.
+    pub has_alpha: bool,
+    pub pixels: Vec<FilmPixel>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32, has_alpha: bool) -> Self {
+        Self {
+            width,
+            height,
+            has_alpha,
+            pixels: vec![FilmPixel::default(); (width * height) as usize],
+        }
+    }
+
+    pub fn row_mut(&mut self, row: u32) -> &mut [FilmPixel] {
+        let start = (row * self.width) as usize;
+        &mut self.pixels[start..(start + self.width as usize)]
+    }
+
+    /// Add the splats of a rendering thread, scaled by `scale`, usually one over the number of
+    /// samples per pixel. NaN components are dropped.
+    pub fn add_splats(&mut self, splats: &Splats, scale: Fp) {
+        for (pixel, splat) in self.pixels.iter_mut().zip(splats.pixels.iter()) {
+            pixel.splat += nan_to_zero(splat) * scale;
+        }
+    }
+
+    /// Map the pixels to [0, 1] with `tonemap`, e.g. the sRGB encoding, and quantize them into
+    /// an 8-bit image. Whatever `tonemap` leaves out of [0, 1] is clamped.
+    pub fn to_image<F: Fn(&Color3F) -> Color3F>(&self, tonemap: F) -> Image {
+        let mut image = Image::new(self.width, self.height);
+        for (i, pixel) in self.pixels.iter().enumerate() {
+            let row = i as u32 / self.width;
+            let col = i as u32 % self.width;
+            image.write_pixel(row, col, Color3U8::from(tonemap(&pixel.color())));
+        }
+        image
+    }
+
+    /// The linear radiance of the pixels as EXR channels `R`, `G` and `B`, and the coverage as
+    /// `A` if the film has alpha.
+    pub fn exr_channels(&self, pixel_type: PixelType) -> Vec<Channel> {
+        let colors: Vec<Color3F> = self.pixels.iter().map(|pixel| pixel.color()).collect();
+        let mut channels: Vec<Channel> = ["R", "G", "B"]
+            .iter()
+            .enumerate()
+            .map(|(axis, name)| {
+                Channel::new(
+                    name,
+                    pixel_type,
+                    colors.iter().map(|color| color.axis(axis)),
+                )
+            })
+            .collect();
+        if self.has_alpha {
+            let alphas = self.pixels.iter().map(|pixel| pixel.alpha());
+            channels.push(Channel::new("A", pixel_type, alphas));
+        }
+        channels
+    }
+
+    /// Write the linear radiance of the pixels into a Radiance HDR (RGBE) file.
+    pub fn write_hdr(&self, filename: &Path) -> Result<(), ImageError> {
+        let colors: Vec<Color3F> = self.pixels.iter().map(|pixel| pixel.color()).collect();
+        write_hdr(filename, self.width, self.height, &colors)
+    }
+}
@@ -1,9 +1,11 @@
 use crate::types::Fp;
 use crate::vecmath::{Color3F, Color3U8};
 use libc::{c_char, c_int, c_void};
-use std::ffi::CString;
-use std::mem;
-use std::path::Path;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 #[link(name = "stb_image")]
 extern "C" {
@@ -16,15 +18,93 @@ extern "C" {
     ) -> c_int;
 }
 
+#[link(name = "stb_image")]
+extern "C" {
+    fn stbi_write_png(
+        filename: *const c_char,
+        w: c_int,
+        h: c_int,
+        comp: c_int,
+        data: *const c_void,
+        stride_in_bytes: c_int,
+    ) -> c_int;
+}
+
+#[link(name = "stb_image")]
+extern "C" {
+    fn stbi_write_jpg(
+        filename: *const c_char,
+        w: c_int,
+        h: c_int,
+        comp: c_int,
+        data: *const c_void,
+        quality: c_int,
+    ) -> c_int;
+}
+
+#[link(name = "stb_image")]
+extern "C" {
+    fn stbi_write_tga(
+        filename: *const c_char,
+        w: c_int,
+        h: c_int,
+        comp: c_int,
+        data: *const c_void,
+    ) -> c_int;
+}
+
+#[link(name = "stb_image")]
+extern "C" {
+    fn stbi_write_hdr(
+        filename: *const c_char,
+        w: c_int,
+        h: c_int,
+        comp: c_int,
+        data: *const f32,
+    ) -> c_int;
+}
+
 #[link(name = "stb_image")]
 extern "C" {
     fn stbi_load(
         filename: *const c_char,
-        w: *const c_int,
-        h: *const c_int,
-        comp_n: *const c_int,
+        w: *mut c_int,
+        h: *mut c_int,
+        comp_n: *mut c_int,
+        desire_comp_n: c_int,
+    ) -> *mut u8;
+}
+
+#[link(name = "stb_image")]
+extern "C" {
+    fn stbi_load_16(
+        filename: *const c_char,
+        w: *mut c_int,
+        h: *mut c_int,
+        comp_n: *mut c_int,
         desire_comp_n: c_int,
-    ) -> *const c_char;
+    ) -> *mut u16;
+}
+
+#[link(name = "stb_image")]
+extern "C" {
+    fn stbi_loadf(
+        filename: *const c_char,
+        w: *mut c_int,
+        h: *mut c_int,
+        comp_n: *mut c_int,
+        desire_comp_n: c_int,
+    ) -> *mut f32;
+}
+
+#[link(name = "stb_image")]
+extern "C" {
+    fn stbi_is_hdr(filename: *const c_char) -> c_int;
+}
+
+#[link(name = "stb_image")]
+extern "C" {
+    fn stbi_is_16_bit(filename: *const c_char) -> c_int;
 }
 
 #[link(name = "stb_image")]
@@ -32,114 +112,583 @@ extern "C" {
     fn stbi_image_free(data: *mut c_void);
 }
 
-pub const IMAGE_PIXEL_SIZE: usize = 3;
+#[link(name = "stb_image")]
+extern "C" {
+    fn stbi_failure_reason() -> *const c_char;
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    NotFound(PathBuf),
+    /// stb couldn't decode the file, `reason` is what `stbi_failure_reason` says.
+    Decode {
+        path: PathBuf,
+        reason: String,
+    },
+    UnsupportedChannelCount {
+        path: PathBuf,
+        channels: u32,
+    },
+    /// Only 8-bit images can be written, high dynamic range ones go through `write_hdr`.
+    NotEightBit(PathBuf),
+    /// stb takes C strings, so paths have to be valid UTF-8.
+    NonUtf8Path(PathBuf),
+    Write(PathBuf),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::NotFound(path) => write!(f, "image '{}' not found", path.display()),
+            ImageError::Decode { path, reason } => {
+                write!(f, "can't decode image '{}': {}", path.display(), reason)
+            }
+            ImageError::UnsupportedChannelCount { path, channels } => write!(
+                f,
+                "image '{}' has an unsupported number of channels: {}",
+                path.display(),
+                channels
+            ),
+            ImageError::NotEightBit(path) => write!(
+                f,
+                "image '{}' can't be written, it isn't 8-bit",
+                path.display()
+            ),
+            ImageError::NonUtf8Path(path) => {
+                write!(f, "image path '{}' isn't valid UTF-8", path.display())
+            }
+            ImageError::Write(path) => write!(f, "can't write image '{}'", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+fn path_to_cstring(path: &Path) -> Result<CString, ImageError> {
+    path.to_str()
+        .and_then(|path| CString::new(path).ok())
+        .ok_or_else(|| ImageError::NonUtf8Path(path.to_path_buf()))
+}
+
+// Copy the pixels stb loaded, if it could, and free them.
+unsafe fn take_stb_pixels<T: Copy>(
+    data: *mut T,
+    width: c_int,
+    height: c_int,
+    channels: c_int,
+) -> Option<Vec<T>> {
+    if data.is_null() {
+        return None;
+    }
+    let len = (width * height * channels) as usize;
+    let pixels = std::slice::from_raw_parts(data, len).to_vec();
+    stbi_image_free(data as *mut c_void);
+    Some(pixels)
+}
+
+// stb writers return zero on failure.
+fn write_result(result: c_int, path: &Path) -> Result<(), ImageError> {
+    if result != 0 {
+        Ok(())
+    } else {
+        Err(ImageError::Write(path.to_path_buf()))
+    }
+}
+
+/// The 8-bit formats an `Image` can be written in.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ImageFormat {
+    Bmp,
+    Png,
+    /// Lossy, `quality` goes from 1 to 100.
+    Jpeg {
+        quality: u8,
+    },
+    Tga,
+    /// Binary PPM (P6).
+    Ppm,
+}
+
+impl ImageFormat {
+    /// The format for the extension of `filename`, with `jpeg_quality` for JPEG.
+    pub fn from_path(filename: &Path, jpeg_quality: u8) -> Option<Self> {
+        let extension = filename.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "bmp" => Some(ImageFormat::Bmp),
+            "png" => Some(ImageFormat::Png),
+            "jpg" | "jpeg" => Some(ImageFormat::Jpeg {
+                quality: jpeg_quality,
+            }),
+            "tga" => Some(ImageFormat::Tga),
+            "ppm" => Some(ImageFormat::Ppm),
+            _ => None,
+        }
+    }
+}
+
+/// How the color channels of 8-bit and 16-bit images are encoded. Float images are always linear.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ColorSpace {
+    /// Gamma encoded, the way color images such as albedo maps are usually stored.
+    Srgb,
+    /// Stored as is, for data such as roughness or normal maps.
+    Linear,
+}
+
+/// Decode an sRGB value in [0, 1] into linear light.
+pub fn srgb_to_linear(value: f64) -> f64 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Encode linear light in [0, 1] into an sRGB value, the inverse of `srgb_to_linear`.
+pub fn linear_to_srgb(value: f64) -> f64 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// The decoded 8-bit sRGB values.
+fn srgb_lut() -> &'static [Fp; 256] {
+    static LUT: OnceLock<[Fp; 256]> = OnceLock::new();
+    LUT.get_or_init(|| std::array::from_fn(|i| srgb_to_linear(i as f64 / 255.0) as Fp))
+}
+
+/// The channels of all the pixels, row by row from the top, in the type they were loaded as.
+pub enum PixelData {
+    /// 8-bit channels, from 0 to 255.
+    U8(Vec<u8>),
+    /// 16-bit channels, from 0 to 65535.
+    U16(Vec<u16>),
+    /// Linear floats of high dynamic range images, not limited to [0, 1].
+    F32(Vec<f32>),
+}
 
+/// An image of 1 (gray), 2 (gray and alpha), 3 (RGB) or 4 (RGBA) channels.
 pub struct Image {
-    from_file: bool,
     pub width: u32,
     pub height: u32,
-    pub pixels: Vec<[u8; IMAGE_PIXEL_SIZE]>,
+    pub channels: u32,
+    pub data: PixelData,
 }
 
 impl Image {
+    /// A black 8-bit RGB image.
     pub fn new(width: u32, height: u32) -> Image {
-        let size = (width * height) as usize;
+        let size = (width * height * 3) as usize;
         Image {
-            from_file: false,
             width,
             height,
-            pixels: vec![[0, 0, 0]; size],
+            channels: 3,
+            data: PixelData::U8(vec![0; size]),
         }
     }
 
-    pub fn from_file<P: AsRef<Path>>(file_path: P) -> Image {
-        let src_file_path_cstr = CString::new(file_path.as_ref().to_str().unwrap()).unwrap();
+    /// An 8-bit RGBA image, transparent black.
+    pub fn new_rgba(width: u32, height: u32) -> Image {
+        let size = (width * height * 4) as usize;
+        Image {
+            width,
+            height,
+            channels: 4,
+            data: PixelData::U8(vec![0; size]),
+        }
+    }
+
+    /// Load an image the way it's stored: high dynamic range files as floats, 16-bit files as
+    /// 16-bit channels, and everything else as 8-bit channels.
+    pub fn from_file<P: AsRef<Path>>(file_path: P) -> Result<Image, ImageError> {
+        let file_path = file_path.as_ref();
+        let src_file_path_cstr = path_to_cstring(file_path)?;
+        let path = src_file_path_cstr.as_ptr();
         let mut image_width = 0;
         let mut image_height = 0;
         let mut image_components = 0;
 
-        let image_data = unsafe {
-            stbi_load(
-                src_file_path_cstr.into_raw(),
-                &mut image_width,
-                &mut image_height,
-                &mut image_components,
-                0,
-            )
+        let data = unsafe {
+            if stbi_is_hdr(path) != 0 {
+                let data = stbi_loadf(
+                    path,
+                    &mut image_width,
+                    &mut image_height,
+                    &mut image_components,
+                    0,
+                );
+                take_stb_pixels(data, image_width, image_height, image_components)
+                    .map(PixelData::F32)
+            } else if stbi_is_16_bit(path) != 0 {
+                let data = stbi_load_16(
+                    path,
+                    &mut image_width,
+                    &mut image_height,
+                    &mut image_components,
+                    0,
+                );
+                take_stb_pixels(data, image_width, image_height, image_components)
+                    .map(PixelData::U16)
+            } else {
+                let data = stbi_load(
+                    path,
+                    &mut image_width,
+                    &mut image_height,
+                    &mut image_components,
+                    0,
+                );
+                take_stb_pixels(data, image_width, image_height, image_components)
+                    .map(PixelData::U8)
+            }
         };
-        assert!(image_data != std::ptr::null());
-        assert!(image_components == IMAGE_PIXEL_SIZE as c_int);
+        let Some(data) = data else {
+            if !file_path.is_file() {
+                return Err(ImageError::NotFound(file_path.to_path_buf()));
+            }
+            let reason = unsafe { CStr::from_ptr(stbi_failure_reason()) };
+            return Err(ImageError::Decode {
+                path: file_path.to_path_buf(),
+                reason: reason.to_string_lossy().into_owned(),
+            });
+        };
+        if !(1..=4).contains(&image_components) {
+            return Err(ImageError::UnsupportedChannelCount {
+                path: file_path.to_path_buf(),
+                channels: image_components as u32,
+            });
+        }
 
-        Image {
-            from_file: true,
+        Ok(Image {
             width: image_width as u32,
             height: image_height as u32,
-            pixels: unsafe {
-                let pixel_size: usize = (image_width * image_height).try_into().unwrap();
-                Vec::from_raw_parts(
-                    image_data as *mut [u8; IMAGE_PIXEL_SIZE],
-                    pixel_size,
-                    pixel_size,
-                )
-            },
+            channels: image_components as u32,
+            data,
+        })
+    }
+
+    // Channel at `index` of the data, scaled to [0, 1] unless it's a float. The cast is only
+    // needed when `Fp` is f64.
+    #[allow(clippy::unnecessary_cast)]
+    fn channel(&self, index: usize) -> Fp {
+        match &self.data {
+            PixelData::U8(data) => data[index] as Fp / 255.0,
+            PixelData::U16(data) => data[index] as Fp / 65535.0,
+            PixelData::F32(data) => data[index] as Fp,
         }
     }
 
-    pub fn pixel_at_uv(&self, u: Fp, v: Fp) -> Color3F {
-        let w = (u * self.width as Fp) as u32;
-        let w = if w >= self.width { self.width - 1 } else { w };
-        let h = (v * self.height as Fp) as u32;
-        let h = if h >= self.height { self.height - 1 } else { h };
-        let index = h * self.width + w;
-        let pixel: Color3U8 = self.pixels[index as usize].into();
-        pixel.into()
+    /// The color space the pixels are most likely stored in: HDR images are linear, the others
+    /// sRGB encoded.
+    pub fn native_color_space(&self) -> ColorSpace {
+        match self.data {
+            PixelData::F32(_) => ColorSpace::Linear,
+            PixelData::U8(_) | PixelData::U16(_) => ColorSpace::Srgb,
+        }
     }
 
-    #[allow(dead_code)]
+    // Color channel at `index` of the data, decoded from `color_space` into linear values.
+    fn color_channel(&self, index: usize, color_space: ColorSpace) -> Fp {
+        match (&self.data, color_space) {
+            (PixelData::U8(data), ColorSpace::Srgb) => srgb_lut()[data[index] as usize],
+            (PixelData::U16(data), ColorSpace::Srgb) => {
+                srgb_to_linear(data[index] as f64 / 65535.0) as Fp
+            }
+            _ => self.channel(index),
+        }
+    }
+
+    /// Color and alpha of the pixel at column `x` and row `y`, the color decoded from
+    /// `color_space`. Gray is repeated in the three color channels, and images without alpha are
+    /// opaque. Alpha is always linear.
+    pub fn texel(&self, x: u32, y: u32, color_space: ColorSpace) -> (Color3F, Fp) {
+        let index = ((y * self.width + x) * self.channels) as usize;
+        match self.channels {
+            1 | 2 => {
+                let gray = self.color_channel(index, color_space);
+                let alpha = if self.channels == 2 {
+                    self.channel(index + 1)
+                } else {
+                    1.0
+                };
+                (Color3F::new(gray, gray, gray), alpha)
+            }
+            _ => {
+                let color = Color3F::new(
+                    self.color_channel(index, color_space),
+                    self.color_channel(index + 1, color_space),
+                    self.color_channel(index + 2, color_space),
+                );
+                let alpha = if self.channels == 4 {
+                    self.channel(index + 3)
+                } else {
+                    1.0
+                };
+                (color, alpha)
+            }
+        }
+    }
+
+    /// Write a pixel of an 8-bit RGB image.
     pub fn write_pixel(&mut self, row: u32, col: u32, pixel: Color3U8) {
-        self.pixels[(row * self.width + col) as usize] = pixel.into();
+        assert!(self.channels == 3);
+        let PixelData::U8(data) = &mut self.data else {
+            panic!("only 8-bit images can be written to");
+        };
+        let index = ((row * self.width + col) * 3) as usize;
+        data[index..(index + 3)].copy_from_slice(&[pixel.x, pixel.y, pixel.z]);
+    }
+
+    /// Write a pixel of an 8-bit RGBA image.
+    pub fn write_pixel_rgba(&mut self, row: u32, col: u32, pixel: Color3U8, alpha: u8) {
+        assert!(self.channels == 4);
+        let PixelData::U8(data) = &mut self.data else {
+            panic!("only 8-bit images can be written to");
+        };
+        let index = ((row * self.width + col) * 4) as usize;
+        data[index..(index + 4)].copy_from_slice(&[pixel.x, pixel.y, pixel.z, alpha]);
     }
 
-    pub fn write_row(&mut self, row_index: u32, row: &[[u8; IMAGE_PIXEL_SIZE]]) {
-        assert!(row_index < self.height);
+    // The channels of an 8-bit image, for the writers.
+    fn bytes(&self, filename: &Path) -> Result<&[u8], ImageError> {
+        match &self.data {
+            PixelData::U8(data) => Ok(data),
+            _ => Err(ImageError::NotEightBit(filename.to_path_buf())),
+        }
+    }
 
-        let pixel_start = (row_index * self.width) as usize;
-        for i in 0..row.len() {
-            if (i as u32) >= self.width {
-                break;
-            }
-            self.pixels[pixel_start + i] = row[i];
+    pub fn write(&self, filename: &Path, format: ImageFormat) -> Result<(), ImageError> {
+        match format {
+            ImageFormat::Bmp => self.write_bmp(filename),
+            ImageFormat::Png => self.write_png(filename),
+            ImageFormat::Jpeg { quality } => self.write_jpg(filename, quality),
+            ImageFormat::Tga => self.write_tga(filename),
+            ImageFormat::Ppm => self.write_ppm(filename),
         }
     }
 
-    pub fn write_bmp(&self, filename: &Path) -> Result<(), ()> {
-        let filename = CString::new(filename.to_str().unwrap()).unwrap();
+    pub fn write_png(&self, filename: &Path) -> Result<(), ImageError> {
+        let filename_cstr = path_to_cstring(filename)?;
+        let data = self.bytes(filename)?;
+        let result = unsafe {
+            stbi_write_png(
+                filename_cstr.as_ptr(),
+                self.width as c_int,
+                self.height as c_int,
+                self.channels as c_int,
+                data.as_ptr() as *const c_void,
+                (self.width * self.channels) as c_int,
+            )
+        };
+
+        write_result(result, filename)
+    }
+
+    pub fn write_jpg(&self, filename: &Path, quality: u8) -> Result<(), ImageError> {
+        let filename_cstr = path_to_cstring(filename)?;
+        let data = self.bytes(filename)?;
+        let result = unsafe {
+            stbi_write_jpg(
+                filename_cstr.as_ptr(),
+                self.width as c_int,
+                self.height as c_int,
+                self.channels as c_int,
+                data.as_ptr() as *const c_void,
+                quality as c_int,
+            )
+        };
+
+        write_result(result, filename)
+    }
+
+    pub fn write_tga(&self, filename: &Path) -> Result<(), ImageError> {
+        let filename_cstr = path_to_cstring(filename)?;
+        let data = self.bytes(filename)?;
+        let result = unsafe {
+            stbi_write_tga(
+                filename_cstr.as_ptr(),
+                self.width as c_int,
+                self.height as c_int,
+                self.channels as c_int,
+                data.as_ptr() as *const c_void,
+            )
+        };
+
+        write_result(result, filename)
+    }
+
+    /// stb has no PPM writer, but the format is simple enough: a text header, then the pixels.
+    /// Gray images are written as PGM, and images with alpha aren't supported.
+    pub fn write_ppm(&self, filename: &Path) -> Result<(), ImageError> {
+        let magic = match self.channels {
+            1 => "P5",
+            3 => "P6",
+            channels => {
+                return Err(ImageError::UnsupportedChannelCount {
+                    path: filename.to_path_buf(),
+                    channels,
+                })
+            }
+        };
+        let mut data = format!("{}\n{} {}\n255\n", magic, self.width, self.height).into_bytes();
+        data.extend(self.bytes(filename)?);
+        fs::write(filename, data).map_err(|_| ImageError::Write(filename.to_path_buf()))
+    }
+
+    pub fn write_bmp(&self, filename: &Path) -> Result<(), ImageError> {
+        let filename_cstr = path_to_cstring(filename)?;
+        let data = self.bytes(filename)?;
         let result = unsafe {
             stbi_write_bmp(
-                filename.into_raw(),
+                filename_cstr.as_ptr(),
                 self.width as c_int,
                 self.height as c_int,
-                IMAGE_PIXEL_SIZE as c_int,
-                self.pixels.as_ptr() as *const c_void,
+                self.channels as c_int,
+                data.as_ptr() as *const c_void,
             )
         };
 
-        if result != 0 {
-            Result::Ok(())
-        } else {
-            Result::Err(())
-        }
+        write_result(result, filename)
     }
 }
 
-impl Drop for Image {
-    fn drop(&mut self) {
-        if self.from_file {
-            let to_drop = mem::replace(&mut self.pixels, Vec::new());
-            let image_data = to_drop.leak();
-            unsafe {
-                stbi_image_free(image_data.as_ptr() as *mut c_void);
-            }
+/// Write linear colors, row by row from the top, into a Radiance HDR (RGBE) file.
+// The casts are only needed when `Fp` is f64.
+#[allow(clippy::unnecessary_cast)]
+pub fn write_hdr(
+    filename: &Path,
+    width: u32,
+    height: u32,
+    pixels: &[Color3F],
+) -> Result<(), ImageError> {
+    assert!(pixels.len() == (width * height) as usize);
+    let data: Vec<f32> = pixels
+        .iter()
+        .flat_map(|pixel| [pixel.x as f32, pixel.y as f32, pixel.z as f32])
+        .collect();
+
+    let filename_cstr = path_to_cstring(filename)?;
+    let result = unsafe {
+        stbi_write_hdr(
+            filename_cstr.as_ptr(),
+            width as c_int,
+            height as c_int,
+            3,
+            data.as_ptr(),
+        )
+    };
+
+    write_result(result, filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loading_reports_why_it_failed() {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+
+        let missing = manifest_dir.join("images/missing.png");
+        assert!(matches!(
+            Image::from_file(&missing),
+            Err(ImageError::NotFound(_))
+        ));
+
+        match Image::from_file(manifest_dir.join("Cargo.toml")) {
+            Err(ImageError::Decode { reason, .. }) => assert!(!reason.is_empty()),
+            _ => panic!("a TOML file shouldn't decode as an image"),
+        }
+
+        let image = Image::from_file(manifest_dir.join("images/checker-test.ppm")).unwrap();
+        assert!(image.width > 0 && image.height > 0);
+    }
+
+    #[test]
+    fn images_load_in_their_own_pixel_format() {
+        let dir = std::env::temp_dir();
+
+        let gray_alpha_path = dir.join("rt-weekends-gray-alpha.png");
+        let gray_alpha = Image {
+            width: 2,
+            height: 1,
+            channels: 2,
+            data: PixelData::U8(vec![255, 0, 51, 255]),
+        };
+        gray_alpha.write_png(&gray_alpha_path).unwrap();
+        let image = Image::from_file(&gray_alpha_path).unwrap();
+        assert!(matches!(image.data, PixelData::U8(_)));
+        assert_eq!(image.channels, 2);
+        assert_eq!(
+            image.texel(0, 0, ColorSpace::Linear),
+            (Color3F::new(1.0, 1.0, 1.0), 0.0)
+        );
+        assert_eq!(
+            image.texel(1, 0, ColorSpace::Linear),
+            (Color3F::new(0.2, 0.2, 0.2), 1.0)
+        );
+
+        // PGM files with a maximum value over 255 are 16-bit.
+        let gray_16_path = dir.join("rt-weekends-gray-16.pgm");
+        let mut gray_16 = b"P5\n2 1\n65535\n".to_vec();
+        gray_16.extend([0x00, 0x00, 0xff, 0xff]);
+        fs::write(&gray_16_path, gray_16).unwrap();
+        let image = Image::from_file(&gray_16_path).unwrap();
+        assert!(matches!(image.data, PixelData::U16(_)));
+        assert_eq!(
+            image.texel(1, 0, ColorSpace::Linear),
+            (Color3F::new(1.0, 1.0, 1.0), 1.0)
+        );
+
+        let hdr_path = dir.join("rt-weekends-float.hdr");
+        write_hdr(&hdr_path, 1, 1, &[Color3F::new(4.0, 0.5, 0.0)]).unwrap();
+        let image = Image::from_file(&hdr_path).unwrap();
+        assert!(matches!(image.data, PixelData::F32(_)));
+        assert_eq!(
+            image.texel(0, 0, ColorSpace::Linear),
+            (Color3F::new(4.0, 0.5, 0.0), 1.0)
+        );
+        // Float images are linear whatever they are tagged as.
+        assert_eq!(
+            image.texel(0, 0, ColorSpace::Srgb),
+            (Color3F::new(4.0, 0.5, 0.0), 1.0)
+        );
+    }
+
+    #[test]
+    fn srgb_decoding_matches_the_transfer_function() {
+        let lut = srgb_lut();
+        assert_eq!(lut[0], 0.0);
+        assert_eq!(lut[255], 1.0);
+        // The linear segment, and half the code values being about a fifth of the light.
+        assert!((lut[10] - 10.0 / 255.0 / 12.92).abs() < 1e-7);
+        assert!((lut[128] - 0.215_861).abs() < 1e-6);
+        assert!(lut.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let image = Image {
+            width: 1,
+            height: 1,
+            channels: 4,
+            data: PixelData::U8(vec![128, 128, 128, 128]),
+        };
+        let (color, alpha) = image.texel(0, 0, ColorSpace::Srgb);
+        assert_eq!(color, Color3F::new(lut[128], lut[128], lut[128]));
+        // Alpha isn't decoded.
+        assert_eq!(alpha, 128.0 / 255.0);
+    }
+
+    #[test]
+    fn srgb_encoding_inverts_decoding() {
+        for i in 0..=255 {
+            let value = i as f64 / 255.0;
+            assert!((linear_to_srgb(srgb_to_linear(value)) - value).abs() < 1e-12);
+        }
+        // Every 8-bit value of a texture survives being decoded and written out again.
+        let lut = srgb_lut();
+        for (i, linear) in lut.iter().enumerate() {
+            let encoded = linear_to_srgb(*linear as f64);
+            assert_eq!((encoded * 255.0).round() as usize, i);
         }
     }
 }
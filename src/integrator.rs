@@ -0,0 +1,383 @@
+use crate::aov::AovSample;
+use crate::materials::Material;
+use crate::scene::{PdfCosineHemisphere, ScatterResult, Scene};
+use crate::shapes::{Ray, RayIntersection};
+use crate::types::Fp;
+use crate::vecmath::{from_local_to_world_space, Color3F};
+use rand::RngCore;
+
+/// Contributions to pixels other than the one being traced, e.g. from light paths connected to
+/// the camera. Each rendering thread keeps its own, and they are added to the image at the end.
+pub struct Splats {
+    pub width: u32,
+    pub height: u32,
+    /// Empty until the first splat, so that integrators which never splat don't hold a copy of
+    /// the image per thread.
+    pub pixels: Vec<Color3F>,
+}
+
+impl Splats {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: Vec::new(),
+        }
+    }
+
+    /// Add `color` to the pixel at the raster position (`x`, `y`).
+    pub fn add(&mut self, x: Fp, y: Fp, color: Color3F) {
+        if self.pixels.is_empty() {
+            self.pixels = vec![Color3F::zero(); (self.width * self.height) as usize];
+        }
+        let col = u32::min(x as u32, self.width - 1);
+        let row = u32::min(y as u32, self.height - 1);
+        self.pixels[(row * self.width + col) as usize] += color;
+    }
+}
+
+/// An algorithm estimating the light arriving at the camera.
+pub trait Integrator: Sync {
+    /// Estimate the radiance arriving at the camera along `ray`, a ray cast through one pixel.
+    /// Contributions to other pixels are added to `splats`, weighted like the radiance returned
+    /// for one camera ray.
+    fn radiance(&self, ray: &Ray, splats: &mut Splats, rand: &mut dyn RngCore) -> Color3F;
+
+    /// Like `radiance`, also splitting the radiance into the lighting passes of `aovs` if the
+    /// integrator can tell the paths apart. By default they are left untouched.
+    fn radiance_aovs(
+        &self,
+        ray: &Ray,
+        splats: &mut Splats,
+        rand: &mut dyn RngCore,
+        _aovs: &mut AovSample,
+    ) -> Color3F {
+        self.radiance(ray, splats, rand)
+    }
+
+    /// Number of passes the image is rendered in, the samples of each pixel split evenly among
+    /// them. The film averages the passes.
+    fn pass_count(&self) -> usize {
+        1
+    }
+
+    /// Get ready to render pass `pass`, before any of its rows are traced.
+    fn begin_pass(&mut self, _pass: usize) {}
+}
+
+/// Unidirectional path tracing from the camera. At diffuse surfaces, rays are scattered towards
+/// the lights and the attractors of the scene as often as the material's own distribution picks
+/// them.
+pub struct PathTracer<'a> {
+    scene: &'a Scene,
+}
+
+impl<'a> PathTracer<'a> {
+    const MAX_DEPTH: u32 = 50;
+
+    pub fn new(scene: &'a Scene) -> Self {
+        Self { scene }
+    }
+
+    // Sample a direction from an equal mixture of the lights, the attractors and the material's
+    // own scattering distribution, and return it along with the PDF of the mixture. Return `None`
+    // if a sampler was chosen that can't reach the shading point, in which case the sample
+    // contributes nothing.
+    fn pdf_mixure_sample<R: rand::Rng>(
+        &self,
+        intersection: &RayIntersection,
+        scattered: &ScatterResult,
+        material: &Material,
+        rand: &mut R,
+    ) -> Option<(Ray, Fp)> {
+        let samplers = [self.scene.lights(), self.scene.attractors()];
+        let sampler_count = samplers
+            .iter()
+            .filter(|sampler| !sampler.is_empty())
+            .count();
+        if sampler_count == 0 {
+            return Some((scattered.ray, scattered.probability));
+        }
+
+        let origin = intersection.hit_point;
+        let normal = intersection.shading_normal;
+        let time = scattered.ray.time;
+        let weight = 1.0 / (sampler_count + 1) as Fp;
+
+        let choice = rand.gen_range(0..=sampler_count);
+        let ray = if choice < sampler_count {
+            let sampler = samplers
+                .iter()
+                .filter(|sampler| !sampler.is_empty())
+                .nth(choice)
+                .unwrap();
+            let random_dir = sampler.gen_random_dir(&origin, &normal, time, rand)?;
+            let cone = &scattered.ray;
+            Ray::new(origin, random_dir, time).with_cone(cone.cone_width, cone.cone_spread)
+        } else {
+            scattered.ray
+        };
+
+        let pdf = weight * material.scattering_pdf(&normal, &intersection.normal, &ray)
+            + samplers
+                .iter()
+                .filter(|sampler| !sampler.is_empty())
+                .map(|sampler| weight * sampler.pdf_value(&ray, &normal))
+                .sum::<Fp>();
+        Some((ray, pdf))
+    }
+
+    // Continue the path at `intersection`. Return the scattered ray, the weight of the light it
+    // brings back and whether it was reflected or refracted specularly, or `None` if the path
+    // ends there.
+    fn bounce<R: rand::Rng>(
+        &self,
+        ray: &Ray,
+        intersection: &RayIntersection,
+        material: &Material,
+        rand: &mut R,
+    ) -> Option<(Ray, Color3F, bool)> {
+        let scattered = Scene::scatter(ray, intersection, material, rand)?;
+        if scattered.skip_pdf {
+            return Some((scattered.ray, scattered.albedo, true));
+        }
+
+        match self.pdf_mixure_sample(intersection, &scattered, material, rand) {
+            Some((scattered_ray, pdf_value)) if pdf_value > 0.0 => {
+                let scattering_pdf = material.scattering_pdf(
+                    &intersection.shading_normal,
+                    &intersection.normal,
+                    &scattered_ray,
+                );
+                let weight = scattered.albedo * scattering_pdf / pdf_value;
+                Some((scattered_ray, weight, false))
+            }
+            _ => None,
+        }
+    }
+
+    fn trace<R: rand::Rng>(&self, ray: &Ray, rand: &mut R, depth: u32) -> Color3F {
+        if depth > Self::MAX_DEPTH {
+            return Color3F::zero();
+        }
+
+        let limits = 0.001..Fp::MAX;
+        let nearest_intersection = self.scene.ray_intersect(ray, &limits);
+        if !nearest_intersection.hit {
+            return self.scene.background(ray);
+        }
+
+        let material = nearest_intersection.material.unwrap();
+        let emission_color = material.emit();
+        match self.bounce(ray, &nearest_intersection, material, rand) {
+            Some((scattered_ray, weight, _)) => {
+                emission_color + weight * self.trace(&scattered_ray, rand, depth + 1)
+            }
+            None => emission_color,
+        }
+    }
+
+    // Like `trace` for a camera ray, with the light split into the lighting AOVs by how it is
+    // reflected off the first surface, and whether it bounced again before.
+    fn trace_aovs<R: rand::Rng>(&self, ray: &Ray, rand: &mut R, aovs: &mut AovSample) -> Color3F {
+        let limits = 0.001..Fp::MAX;
+        let intersection = self.scene.ray_intersect(ray, &limits);
+        if !intersection.hit {
+            aovs.emission = self.scene.background(ray);
+            return aovs.emission;
+        }
+
+        let material = intersection.material.unwrap();
+        aovs.emission = material.emit();
+        let Some((scattered_ray, weight, specular)) =
+            self.bounce(ray, &intersection, material, rand)
+        else {
+            return aovs.emission;
+        };
+
+        if specular {
+            aovs.specular = weight * self.trace(&scattered_ray, rand, 1);
+            return aovs.emission + aovs.specular;
+        }
+
+        let next_intersection = self.scene.ray_intersect(&scattered_ray, &limits);
+        if !next_intersection.hit {
+            aovs.direct_diffuse = weight * self.scene.background(&scattered_ray);
+        } else {
+            let next_material = next_intersection.material.unwrap();
+            aovs.direct_diffuse = weight * next_material.emit();
+            if let Some((next_ray, next_weight, _)) =
+                self.bounce(&scattered_ray, &next_intersection, next_material, rand)
+            {
+                aovs.indirect_diffuse = weight * next_weight * self.trace(&next_ray, rand, 2);
+            }
+        }
+        aovs.emission + aovs.direct_diffuse + aovs.indirect_diffuse
+    }
+}
+
+impl<'a> Integrator for PathTracer<'a> {
+    fn radiance(&self, ray: &Ray, _splats: &mut Splats, mut rand: &mut dyn RngCore) -> Color3F {
+        self.trace(ray, &mut rand, 0)
+    }
+
+    fn radiance_aovs(
+        &self,
+        ray: &Ray,
+        _splats: &mut Splats,
+        mut rand: &mut dyn RngCore,
+        aovs: &mut AovSample,
+    ) -> Color3F {
+        self.trace_aovs(ray, &mut rand, aovs)
+    }
+}
+
+/// What `DebugIntegrator` shows of the first surface hit by each camera ray.
+#[derive(Copy, Clone)]
+pub enum DebugView {
+    /// Normal facing the camera, in world space, mapped from [-1, 1] to [0, 1].
+    Normal,
+    /// Texture coordinates, u in red and v in green.
+    Uv,
+    /// Distance from the camera, white up close and black at `max_distance` and beyond.
+    Depth { max_distance: Fp },
+    /// Fraction of the light reflected by the surface, see `Material::albedo`.
+    Albedo,
+    /// A different color for each material of the scene. Materials not listed by the scene are
+    /// black.
+    MaterialId,
+    /// Number of AABBs and shapes tested to find the surface, from blue to red, red for
+    /// `max_cost` and more. Misses are counted too.
+    BvhCost { max_cost: usize },
+}
+
+/// Shows properties of the surfaces seen by the camera instead of their lighting, to find out
+/// what is wrong with a scene. Rays that hit nothing are black.
+pub struct DebugIntegrator<'a> {
+    scene: &'a Scene,
+    view: DebugView,
+}
+
+/// A color for `id`, far from the colors of the ids close to it.
+pub fn id_color(id: usize) -> Color3F {
+    let hash = (id as u32 + 1).wrapping_mul(2654435761);
+    Color3F::new(
+        (hash & 0xff) as Fp / 255.0,
+        ((hash >> 8) & 0xff) as Fp / 255.0,
+        ((hash >> 16) & 0xff) as Fp / 255.0,
+    )
+}
+
+// `value` in [0, 1] mapped from blue through green to red.
+fn heatmap(value: Fp) -> Color3F {
+    let value = value.clamp(0.0, 1.0);
+    Color3F::new(
+        Fp::max(0.0, 2.0 * value - 1.0),
+        1.0 - (2.0 * value - 1.0).abs(),
+        Fp::max(0.0, 1.0 - 2.0 * value),
+    )
+}
+
+impl DebugView {
+    /// Whether the view shows values, e.g. normals or depths, rather than colors. Values are
+    /// meant to be seen as they are, not sRGB encoded like colors.
+    pub fn shows_values(&self) -> bool {
+        !matches!(self, DebugView::Albedo)
+    }
+}
+
+impl<'a> DebugIntegrator<'a> {
+    pub fn new(scene: &'a Scene, view: DebugView) -> Self {
+        Self { scene, view }
+    }
+
+    fn color(&self, ray: &Ray) -> Color3F {
+        let limits = 0.001..Fp::MAX;
+
+        if let DebugView::BvhCost { max_cost } = self.view {
+            let cost = self.scene.ray_intersect_cost(ray, &limits);
+            return heatmap(cost as Fp / max_cost as Fp);
+        }
+
+        let intersection = self.scene.ray_intersect(ray, &limits);
+        if !intersection.hit {
+            return Color3F::zero();
+        }
+        let material = intersection.material.unwrap();
+
+        match self.view {
+            DebugView::Normal => 0.5 * (intersection.shading_normal + Color3F::new(1.0, 1.0, 1.0)),
+            DebugView::Uv => Color3F::new(intersection.u, intersection.v, 0.0),
+            DebugView::Depth { max_distance } => {
+                let distance = intersection.t * ray.direction.length();
+                let brightness = 1.0 - Fp::min(distance / max_distance, 1.0);
+                Color3F::new(brightness, brightness, brightness)
+            }
+            DebugView::Albedo => material.albedo(ray, &intersection),
+            DebugView::MaterialId => self
+                .scene
+                .material_id(material)
+                .map_or(Color3F::zero(), id_color),
+            DebugView::BvhCost { .. } => unreachable!(),
+        }
+    }
+}
+
+impl<'a> Integrator for DebugIntegrator<'a> {
+    fn radiance(&self, ray: &Ray, _splats: &mut Splats, _rand: &mut dyn RngCore) -> Color3F {
+        self.color(ray)
+    }
+}
+
+/// Ambient occlusion: how much of the sky above the first surface hit is left uncovered by the
+/// rest of the scene, from white for an open surface to black for a fully enclosed one. Quick to
+/// render, it shows the geometry and the contact shadows of a scene without its materials and
+/// lights.
+pub struct AmbientOcclusion<'a> {
+    scene: &'a Scene,
+    /// Number of rays cast around the normal for each camera ray.
+    samples: u32,
+    /// Surfaces further away than this don't occlude.
+    max_distance: Fp,
+}
+
+impl<'a> AmbientOcclusion<'a> {
+    pub fn new(scene: &'a Scene, samples: u32, max_distance: Fp) -> Self {
+        Self {
+            scene,
+            samples,
+            max_distance,
+        }
+    }
+
+    // Rays are cosine-weighted, so that the fraction of them escaping is the irradiance from a
+    // uniform white sky, as seen on a white diffuse surface.
+    fn occlusion<R: rand::Rng>(&self, ray: &Ray, rand: &mut R) -> Color3F {
+        let intersection = self.scene.ray_intersect(ray, &(0.001..Fp::MAX));
+        if !intersection.hit || self.samples == 0 {
+            return Color3F::zero();
+        }
+
+        let pdf = PdfCosineHemisphere::new();
+        let unoccluded = (0..self.samples)
+            .filter(|_| {
+                let sample = pdf.gen_sample(rand);
+                let dir = from_local_to_world_space(&intersection.shading_normal, &sample.dir);
+                let occlusion_ray = Ray::new(intersection.hit_point, dir, ray.time);
+                !self
+                    .scene
+                    .ray_intersect(&occlusion_ray, &(0.001..self.max_distance))
+                    .hit
+            })
+            .count();
+
+        let brightness = unoccluded as Fp / self.samples as Fp;
+        Color3F::new(brightness, brightness, brightness)
+    }
+}
+
+impl<'a> Integrator for AmbientOcclusion<'a> {
+    fn radiance(&self, ray: &Ray, _splats: &mut Splats, mut rand: &mut dyn RngCore) -> Color3F {
+        self.occlusion(ray, &mut rand)
+    }
+}
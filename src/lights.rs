@@ -0,0 +1,632 @@
+use crate::bvh::{build_bvh, bvh_for_each_candidate, BvhNode};
+use crate::scene::PdfCosineHemisphere;
+use crate::shapes::{Aabb, Ray, Shape};
+use crate::types::Fp;
+use crate::vecmath::{cross, dot, from_local_to_world_space, luminance, Color3F, Vec3F};
+use std::fmt;
+use std::sync::Arc;
+
+#[cfg(not(feature = "use-f64"))]
+use std::f32::consts::PI;
+#[cfg(feature = "use-f64")]
+use std::f64::consts::PI;
+
+/// With fewer lights than this, picking a light by power alone is cheap and good enough. With
+/// more, a light BVH is built so that lights close to the shading point are favored.
+const LIGHT_BVH_MIN_LIGHTS: usize = 8;
+
+/// Bounds of the light emitted by a group of lights: where the lights are, how much power they
+/// emit and which directions they emit towards. Used to estimate how much a group of lights
+/// contributes to a shading point, as described in "Importance Sampling of Many Lights with
+/// Adaptive Tree Splitting" by Conty and Kulla.
+#[derive(Copy, Clone)]
+struct LightBounds {
+    aabb: Aabb,
+    power: Fp,
+    /// Axis of the cone bounding the surface normals of the lights.
+    axis: Vec3F,
+    /// Cosine of the angle between `axis` and the normals furthest away from it.
+    cos_theta_o: Fp,
+    /// Cosine of the angle from a surface normal beyond which no light is emitted.
+    cos_theta_e: Fp,
+    /// Whether the lights emit from both sides of their surfaces.
+    two_sided: bool,
+}
+
+// cos(a - b) where a - b is clamped to be no less than 0.
+fn cos_sub_clamped(sin_a: Fp, cos_a: Fp, sin_b: Fp, cos_b: Fp) -> Fp {
+    if cos_a > cos_b {
+        1.0
+    } else {
+        cos_a * cos_b + sin_a * sin_b
+    }
+}
+
+// sin(a - b) where a - b is clamped to be no less than 0.
+fn sin_sub_clamped(sin_a: Fp, cos_a: Fp, sin_b: Fp, cos_b: Fp) -> Fp {
+    if cos_a > cos_b {
+        0.0
+    } else {
+        sin_a * cos_b - cos_a * sin_b
+    }
+}
+
+fn sin_from_cos(cos: Fp) -> Fp {
+    Fp::sqrt(Fp::max(0.0, 1.0 - cos * cos))
+}
+
+/// The smallest cone containing the two cones, each given by its axis and the cosine of its
+/// spread angle.
+fn union_cones(a_axis: &Vec3F, a_cos: Fp, b_axis: &Vec3F, b_cos: Fp) -> (Vec3F, Fp) {
+    let theta_a = a_cos.clamp(-1.0, 1.0).acos();
+    let theta_b = b_cos.clamp(-1.0, 1.0).acos();
+    let theta_d = dot(a_axis, b_axis).clamp(-1.0, 1.0).acos();
+
+    // One cone contains the other.
+    if Fp::min(theta_d + theta_b, PI) <= theta_a {
+        return (*a_axis, a_cos);
+    }
+    if Fp::min(theta_d + theta_a, PI) <= theta_b {
+        return (*b_axis, b_cos);
+    }
+
+    let theta_o = 0.5 * (theta_a + theta_d + theta_b);
+    let rotation_axis = cross(a_axis, b_axis);
+    if theta_o >= PI || rotation_axis.length_squared() == 0.0 {
+        return (*a_axis, -1.0);
+    }
+
+    // Rotate `a_axis` towards `b_axis` so that the new cone touches the far side of both cones.
+    let theta_r = theta_o - theta_a;
+    let k = rotation_axis.normalized();
+    let axis = theta_r.cos() * *a_axis + theta_r.sin() * cross(&k, a_axis);
+    (axis.normalized(), theta_o.cos())
+}
+
+impl LightBounds {
+    fn from_shape(shape: &Shape) -> Self {
+        // Flat shapes emit around their normal, on both sides. The normals of other shapes can
+        // point anywhere.
+        let (axis, cos_theta_o, two_sided) = match shape {
+            Shape::Quad(q) => (q.normal, 1.0, true),
+            Shape::Disk(d) => (d.normal, 1.0, true),
+            _ => (Vec3F::new(0.0, 0.0, 1.0), -1.0, false),
+        };
+
+        Self {
+            aabb: shape.calc_aabb(),
+            power: shape.emitted_power(),
+            axis,
+            cos_theta_o,
+            // Diffuse lights emit over the hemisphere around the normal.
+            cos_theta_e: 0.0,
+            two_sided,
+        }
+    }
+
+    fn union(a: &LightBounds, b: &LightBounds) -> Self {
+        // Lights that don't emit shouldn't widen the cones.
+        let (axis, cos_theta_o, cos_theta_e, two_sided) = if a.power == 0.0 {
+            (b.axis, b.cos_theta_o, b.cos_theta_e, b.two_sided)
+        } else if b.power == 0.0 {
+            (a.axis, a.cos_theta_o, a.cos_theta_e, a.two_sided)
+        } else {
+            let (axis, cos_theta_o) = union_cones(&a.axis, a.cos_theta_o, &b.axis, b.cos_theta_o);
+            (
+                axis,
+                cos_theta_o,
+                Fp::min(a.cos_theta_e, b.cos_theta_e),
+                a.two_sided || b.two_sided,
+            )
+        };
+
+        Self {
+            aabb: Aabb::merge(&a.aabb, &b.aabb),
+            power: a.power + b.power,
+            axis,
+            cos_theta_o,
+            cos_theta_e,
+            two_sided,
+        }
+    }
+
+    /// A conservative estimate of the light arriving at point `p` with surface normal `normal`.
+    /// Only the ratios between the importance of different bounds are meaningful.
+    fn importance(&self, p: &Vec3F, normal: &Vec3F) -> Fp {
+        let (center, radius) = self.aabb.bounding_sphere();
+        let to_p = *p - center;
+        let dist_squared = to_p.length_squared();
+        // Avoid blowing up when `p` is close to or inside the lights.
+        let d2 = Fp::max(dist_squared, radius);
+
+        let wi = if dist_squared > 0.0 {
+            to_p / Fp::sqrt(dist_squared)
+        } else {
+            self.axis
+        };
+
+        // Angle between the cone axis and the direction to `p`.
+        let mut cos_theta_w = dot(&self.axis, &wi);
+        if self.two_sided {
+            cos_theta_w = cos_theta_w.abs();
+        }
+        let sin_theta_w = sin_from_cos(cos_theta_w);
+
+        // Half angle of the cone of directions from `p` towards the bounds.
+        let cos_theta_b = if dist_squared < radius * radius {
+            -1.0
+        } else {
+            sin_from_cos(radius / Fp::sqrt(dist_squared))
+        };
+        let sin_theta_b = sin_from_cos(cos_theta_b);
+
+        // The smallest angle between `wi` and any normal in the cone, then reduced by the extent
+        // of the bounds.
+        let sin_theta_o = sin_from_cos(self.cos_theta_o);
+        let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let cos_theta_p = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta_p <= self.cos_theta_e {
+            return 0.0;
+        }
+
+        // The smallest angle between the surface normal at `p` and the directions to the bounds.
+        let cos_theta_i = dot(&wi, normal).abs();
+        let sin_theta_i = sin_from_cos(cos_theta_i);
+        let cos_theta_ip = cos_sub_clamped(sin_theta_i, cos_theta_i, sin_theta_b, cos_theta_b);
+
+        Fp::max(0.0, self.power * cos_theta_p * cos_theta_ip / d2)
+    }
+}
+
+struct LightBvhLeaf {
+    bounds: LightBounds,
+    light_index: usize,
+}
+
+struct LightBvhLink {
+    bounds: LightBounds,
+    left: Arc<LightBvhNode>,
+    right: Arc<LightBvhNode>,
+}
+
+enum LightBvhNode {
+    Leaf(LightBvhLeaf),
+    Link(LightBvhLink),
+}
+
+impl LightBvhNode {
+    fn bounds(&self) -> &LightBounds {
+        match self {
+            LightBvhNode::Leaf(leaf) => &leaf.bounds,
+            LightBvhNode::Link(link) => &link.bounds,
+        }
+    }
+
+    /// Build the light BVH with the same topology as `node`, a BVH built over `lights`.
+    fn build(node: &BvhNode, lights: &[Shape]) -> Arc<Self> {
+        match node {
+            BvhNode::Leaf(leaf) => Arc::new(LightBvhNode::Leaf(LightBvhLeaf {
+                bounds: LightBounds::from_shape(&lights[leaf.shape_index]),
+                light_index: leaf.shape_index,
+            })),
+            BvhNode::Link(link) => {
+                let left = Self::build(&link.left, lights);
+                let right = Self::build(&link.right, lights);
+                Arc::new(LightBvhNode::Link(LightBvhLink {
+                    bounds: LightBounds::union(left.bounds(), right.bounds()),
+                    left,
+                    right,
+                }))
+            }
+        }
+    }
+}
+
+enum LightSelection {
+    /// Pick a light in proportion to a fixed weight, its power or its area.
+    Weighted {
+        /// Probability of picking each light.
+        pmf: Vec<Fp>,
+        /// Cumulative sum of `pmf`, for picking a light by binary search.
+        cdf: Vec<Fp>,
+        /// Used to find the lights a ray passes by when querying the PDF, instead of visiting
+        /// every light.
+        bvh: Option<Arc<BvhNode>>,
+    },
+    /// Pick a light by walking down a light BVH from the root, choosing a child in proportion to
+    /// its importance to the shading point.
+    Bvh(Arc<LightBvhNode>),
+}
+
+/// Picks a light in proportion to its estimated contribution, so that bright lights get most of
+/// the samples, and dim, tiny or far away lights don't add noise.
+pub struct LightSampler {
+    lights: Vec<Shape>,
+    selection: LightSelection,
+}
+
+impl LightSampler {
+    /// Lights picked in proportion to their power, or with a light BVH if there are many.
+    pub fn new(lights: Vec<Shape>) -> Self {
+        Self::build(lights, Shape::emitted_power, true)
+    }
+
+    /// Shapes that don't need to emit, e.g. attractors, picked in proportion to their area.
+    pub fn new_by_area(shapes: Vec<Shape>) -> Self {
+        Self::build(shapes, Shape::area, false)
+    }
+
+    fn build(mut lights: Vec<Shape>, weight: fn(&Shape) -> Fp, allow_light_bvh: bool) -> Self {
+        let bvh = if lights.is_empty() {
+            None
+        } else {
+            Some(build_bvh(&mut lights, 0))
+        };
+
+        let mut weights: Vec<Fp> = lights.iter().map(weight).collect();
+        let total: Fp = weights.iter().sum();
+
+        if let Some(bvh) = &bvh {
+            // The light BVH can't pick lights that don't emit at all.
+            if allow_light_bvh && lights.len() >= LIGHT_BVH_MIN_LIGHTS && total > 0.0 {
+                let selection = LightSelection::Bvh(LightBvhNode::build(bvh, &lights));
+                return Self { lights, selection };
+            }
+        }
+
+        if total > 0.0 {
+            weights.iter_mut().for_each(|w| *w /= total);
+        } else {
+            // Degenerate shapes only, pick them uniformly.
+            let uniform = 1.0 / lights.len() as Fp;
+            weights.iter_mut().for_each(|w| *w = uniform);
+        }
+
+        let mut sum = 0.0;
+        let cdf = weights
+            .iter()
+            .map(|w| {
+                sum += w;
+                sum
+            })
+            .collect();
+
+        Self {
+            lights,
+            selection: LightSelection::Weighted {
+                pmf: weights,
+                cdf,
+                bvh,
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    // Pick a light for the shading point `origin` with surface normal `normal`, and return the
+    // index of the light. Return `None` if no light can reach the shading point.
+    fn pick<R: rand::Rng>(&self, origin: &Vec3F, normal: &Vec3F, rand: &mut R) -> Option<usize> {
+        match &self.selection {
+            LightSelection::Weighted { cdf, .. } => {
+                let r = rand.gen_range(0.0..1.0);
+                // Rounding may leave the last entry of `cdf` slightly below 1.0.
+                Some(cdf.partition_point(|&c| c <= r).min(self.lights.len() - 1))
+            }
+            LightSelection::Bvh(root) => {
+                let mut node = root;
+                loop {
+                    match node.as_ref() {
+                        LightBvhNode::Leaf(leaf) => {
+                            return if leaf.bounds.importance(origin, normal) > 0.0 {
+                                Some(leaf.light_index)
+                            } else {
+                                None
+                            };
+                        }
+                        LightBvhNode::Link(link) => {
+                            let left = link.left.bounds().importance(origin, normal);
+                            let right = link.right.bounds().importance(origin, normal);
+                            if left + right <= 0.0 {
+                                return None;
+                            }
+                            node = if rand.gen_range(0.0..1.0) < left / (left + right) {
+                                &link.left
+                            } else {
+                                &link.right
+                            };
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Generate a direction from `origin`, a point on a surface with `normal`, towards a point on
+    /// one of the lights.
+    pub fn gen_random_dir<R: rand::Rng>(
+        &self,
+        origin: &Vec3F,
+        normal: &Vec3F,
+        time: Fp,
+        rand: &mut R,
+    ) -> Option<Vec3F> {
+        let light_index = self.pick(origin, normal, rand)?;
+        Some(self.lights[light_index].gen_random_dir(origin, time, rand))
+    }
+
+    /// The probability density of `gen_random_dir` generating the direction of `ray`, where
+    /// `normal` is the surface normal at the origin of the ray.
+    pub fn pdf_value(&self, ray: &Ray, normal: &Vec3F) -> Fp {
+        match &self.selection {
+            LightSelection::Weighted { pmf, bvh, .. } => {
+                let mut pdf = 0.0;
+                if let Some(bvh) = bvh {
+                    // A light only contributes if the ray hits it, which implies hitting its AABB.
+                    bvh_for_each_candidate(bvh, ray, &mut |light_index| {
+                        pdf += pmf[light_index] * self.lights[light_index].pdf_value(ray);
+                    });
+                }
+                pdf
+            }
+            LightSelection::Bvh(root) => self.light_bvh_pdf(root, ray, normal, 1.0),
+        }
+    }
+
+    // Walk down the branches of the light BVH that `ray` passes through, accumulating the
+    // probability of `pick` choosing each branch.
+    fn light_bvh_pdf(&self, node: &LightBvhNode, ray: &Ray, normal: &Vec3F, pmf: Fp) -> Fp {
+        if !node.bounds().aabb.ray_intersect(ray) {
+            return 0.0;
+        }
+
+        match node {
+            LightBvhNode::Leaf(leaf) => {
+                if leaf.bounds.importance(&ray.origin, normal) > 0.0 {
+                    pmf * self.lights[leaf.light_index].pdf_value(ray)
+                } else {
+                    0.0
+                }
+            }
+            LightBvhNode::Link(link) => {
+                let left = link.left.bounds().importance(&ray.origin, normal);
+                let right = link.right.bounds().importance(&ray.origin, normal);
+                if left + right <= 0.0 {
+                    return 0.0;
+                }
+                let left_pmf = pmf * left / (left + right);
+                let right_pmf = pmf * right / (left + right);
+                self.light_bvh_pdf(&link.left, ray, normal, left_pmf)
+                    + self.light_bvh_pdf(&link.right, ray, normal, right_pmf)
+            }
+        }
+    }
+}
+
+/// A point on a light, to start a path from.
+#[derive(Copy, Clone)]
+pub struct LightPoint {
+    pub p: Vec3F,
+    /// Normal of the light's surface.
+    pub normal: Vec3F,
+    /// Radiance emitted from the point.
+    pub emission: Color3F,
+    /// Probability density, over area, of picking the point among all the lights.
+    pub pdf_pos: Fp,
+}
+
+/// A light that `LightEmitters` can't pick points on, named by the kind of its shape.
+#[derive(Debug)]
+pub struct UnsupportedEmitter(pub &'static str);
+
+impl fmt::Display for UnsupportedEmitter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "paths can't be traced from a {} light, only from lights of a single shape",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedEmitter {}
+
+/// Picks points on the lights to trace paths from, as opposed to `LightSampler` which picks
+/// directions towards the lights from a shading point. A light is picked in proportion to its
+/// power, and a point uniformly by area on it, see `Shape::sample_point`. CSG shapes and instances
+/// can't be picked this way, a scene with such lights is refused rather than rendered without
+/// them.
+///
+/// Lights emit from both sides of their surface, the same as `Scene::trace` sees them, with a
+/// cosine distribution on each side.
+pub struct LightEmitters<'a> {
+    lights: Vec<&'a Shape>,
+    /// Cumulative sum of the lights' power divided by `total_power`.
+    cdf: Vec<Fp>,
+    total_power: Fp,
+}
+
+impl<'a> LightEmitters<'a> {
+    pub fn new(sampler: &'a LightSampler) -> Result<Self, UnsupportedEmitter> {
+        if let Some(light) = sampler
+            .lights
+            .iter()
+            .find(|light| matches!(light, Shape::Csg(_) | Shape::Instance(_)))
+        {
+            return Err(UnsupportedEmitter(light.name()));
+        }
+        let lights: Vec<&Shape> = sampler.lights.iter().collect();
+        let total_power: Fp = lights.iter().map(|light| light.emitted_power()).sum();
+
+        let mut cdf = Vec::with_capacity(lights.len());
+        let mut sum = 0.0;
+        for light in lights.iter() {
+            sum += light.emitted_power() / total_power;
+            cdf.push(sum);
+        }
+
+        Ok(Self {
+            lights,
+            cdf,
+            total_power,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    pub fn sample_point<R: rand::Rng>(&self, time: Fp, rand: &mut R) -> Option<LightPoint> {
+        if self.lights.is_empty() {
+            return None;
+        }
+
+        let r = rand.gen_range(0.0..1.0);
+        // Rounding may leave the last entry of `cdf` slightly below 1.0.
+        let light = self.lights[self
+            .cdf
+            .partition_point(|&c| c <= r)
+            .min(self.lights.len() - 1)];
+        let (p, normal) = light.sample_point(time, rand)?;
+        let emission = match light {
+            Shape::Sphere(s) => s.material.emit(),
+            Shape::Quad(q) => q.material.emit(),
+            Shape::Disk(d) => d.material.emit(),
+            Shape::Cylinder(c) => c.material.emit(),
+            Shape::Cone(c) => c.material.emit(),
+            Shape::Torus(t) => t.material.emit(),
+            Shape::Csg(_) | Shape::Instance(_) => unreachable!(),
+        };
+
+        Some(LightPoint {
+            p,
+            normal,
+            emission,
+            pdf_pos: self.pdf_pos(&emission),
+        })
+    }
+
+    /// Density over area of `sample_point` picking a point emitting `emission`. A light is picked
+    /// with probability power / total_power, where power = luminance(emission) * area * PI, and
+    /// the point on it with probability 1 / area.
+    pub fn pdf_pos(&self, emission: &Color3F) -> Fp {
+        if self.total_power > 0.0 {
+            luminance(emission) * PI / self.total_power
+        } else {
+            0.0
+        }
+    }
+
+    /// A direction for `point` to emit towards, and its density over solid angle.
+    pub fn sample_dir<R: rand::Rng>(point: &LightPoint, rand: &mut R) -> (Vec3F, Fp) {
+        let sample = PdfCosineHemisphere::new().gen_sample(rand);
+        let side = if rand.gen_bool(0.5) {
+            point.normal
+        } else {
+            -point.normal
+        };
+        (
+            from_local_to_world_space(&side, &sample.dir),
+            0.5 * sample.probability,
+        )
+    }
+
+    /// Density over solid angle of `sample_dir` emitting towards `dir` from a point with surface
+    /// normal `normal`.
+    pub fn pdf_dir(normal: &Vec3F, dir: &Vec3F) -> Fp {
+        dot(normal, &dir.normalized()).abs() / (2.0 * PI)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::materials::{Material, MaterialDiffuseLight};
+    use crate::shapes::Sphere;
+    use crate::vecmath::Color3F;
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    // `count` spheres of radius 0.5 in a ring above the origin, each brighter than the previous.
+    fn ring_of_lights(count: usize) -> Vec<Shape> {
+        (0..count)
+            .map(|i| {
+                let angle = 2.0 * PI * i as Fp / count as Fp;
+                let center = Vec3F::new(3.0 * angle.cos(), 5.0, 3.0 * angle.sin());
+                let emit = (i + 1) as Fp;
+                let material = Arc::new(Material::DiffuseLight(MaterialDiffuseLight::new(
+                    Color3F::new(emit, emit, emit),
+                )));
+                Shape::Sphere(Sphere::new(center, 0.5, material))
+            })
+            .collect()
+    }
+
+    // Check that `pdf_value` is the density of `gen_random_dir`: weighting the directions by
+    // one over their density, the directions hitting each light measure its solid angle.
+    fn assert_pick_matches_pdf(sampler: &LightSampler) {
+        let origin = Vec3F::zero();
+        let normal = Vec3F::new(0.0, 1.0, 0.0);
+        let lights = &sampler.lights;
+
+        let mut rand = SmallRng::seed_from_u64(1);
+        let samples = 100_000;
+        let mut solid_angles = vec![0.0; lights.len()];
+        for _ in 0..samples {
+            let dir = sampler
+                .gen_random_dir(&origin, &normal, 0.0, &mut rand)
+                .unwrap();
+            let ray = Ray::new(origin, dir, 0.0);
+            // Directions grazing a sphere may miss it by rounding.
+            let Some(hit_index) = lights
+                .iter()
+                .position(|light| light.ray_intersect(&ray, &(0.001..Fp::MAX)).hit)
+            else {
+                continue;
+            };
+            let pdf = sampler.pdf_value(&ray, &normal);
+            assert!(pdf > 0.0);
+            solid_angles[hit_index] += 1.0 / pdf / samples as Fp;
+        }
+
+        // Every light is at a distance of sqrt(3^2 + 5^2) from the origin.
+        let expected = 2.0 * PI * (1.0 - Fp::sqrt(1.0 - 0.25 / 34.0));
+        for solid_angle in solid_angles.iter() {
+            assert!(
+                (solid_angle - expected).abs() < 0.05 * expected,
+                "solid angle {} instead of {}",
+                solid_angle,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn lights_are_picked_by_power() {
+        let sampler = LightSampler::new(ring_of_lights(3));
+        let LightSelection::Weighted { pmf, .. } = &sampler.selection else {
+            panic!("a few lights are picked without a light BVH");
+        };
+        // The lights have the same area, so their power is proportional to their emission, and the
+        // brightest one is picked with a probability of 3 / (1 + 2 + 3).
+        let total: Fp = sampler
+            .lights
+            .iter()
+            .map(|light| light.emitted_power())
+            .sum();
+        for (light, p) in sampler.lights.iter().zip(pmf.iter()) {
+            assert!((p - light.emitted_power() / total).abs() < 1e-5);
+        }
+        let max_p = pmf.iter().fold(0.0, |a: Fp, &b| a.max(b));
+        assert!((max_p - 0.5).abs() < 1e-5);
+        assert_pick_matches_pdf(&sampler);
+    }
+
+    #[test]
+    fn light_bvh_pick_matches_pdf() {
+        let sampler = LightSampler::new(ring_of_lights(LIGHT_BVH_MIN_LIGHTS));
+        assert!(matches!(sampler.selection, LightSelection::Bvh(_)));
+        assert_pick_matches_pdf(&sampler);
+    }
+}
@@ -2,21 +2,39 @@
 
 extern crate rand;
 
+mod aov;
+mod bdpt;
+mod bvh;
 mod camera;
+mod exr;
+mod film;
 mod image;
+mod integrator;
+mod lights;
 mod materials;
+mod perlin;
+mod photon_map;
 mod scene;
 mod shapes;
 mod textures;
 mod types;
 mod vecmath;
 
+use aov::{aov_exr_channels, Aov, AovSample};
+use bdpt::Bdpt;
 use camera::Camera;
-use image::{Image, IMAGE_PIXEL_SIZE};
+use exr::{write_exr, Compression, PixelType};
+use film::{nan_to_zero, Film, FilmPixel};
+use image::{linear_to_srgb, write_hdr, Image, ImageFormat};
+use integrator::{
+    id_color, AmbientOcclusion, DebugIntegrator, DebugView, Integrator, PathTracer, Splats,
+};
+use lights::UnsupportedEmitter;
+use photon_map::PhotonMapping;
 use rand::{rngs::SmallRng, Rng, SeedableRng};
 use scene::Scene;
 use std::io::{stdout, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{
     atomic::{self, AtomicU32},
     Mutex,
@@ -24,47 +42,305 @@ use std::sync::{
 use std::thread;
 use std::time;
 use types::Fp;
-use vecmath::{Color3F, Color3U8, Vec3F};
-
-fn linear_to_gamma(color: &Color3F) -> Color3F {
-    Color3F::new(
-        if color.x > 0.0 { color.x.sqrt() } else { 0.0 },
-        if color.y > 0.0 { color.y.sqrt() } else { 0.0 },
-        if color.z > 0.0 { color.z.sqrt() } else { 0.0 },
-    )
+use vecmath::{Color3F, Color3U8};
+
+enum IntegratorKind {
+    PathTracing,
+    /// Bidirectional path tracing finds caustics, e.g. the one under the glass sphere of the
+    /// cornell box, much faster than tracing paths from the camera only.
+    Bdpt,
+    /// Photon mapping also finds caustics seen through glass, which no other integrator can.
+    PhotonMapping,
+    /// A grey pass showing the geometry of the scene, much faster than the other integrators.
+    AmbientOcclusion {
+        samples: u32,
+        max_distance: Fp,
+    },
+    /// Shows a property of the surfaces seen by the camera instead of their lighting.
+    Debug(DebugView),
+}
+
+const USAGE: &str = "usage: rt-weekends [OPTIONS] [INTEGRATOR]
+
+options:
+    --output FILE               where to write the image, rendered.bmp in the crate directory by
+                                default. The extension picks the format: .bmp, .png, .jpg,
+                                .tga, .ppm, .exr (OpenEXR, with alpha) or .hdr (Radiance RGBE)
+    --aovs                      also write the passes for compositing, as layers of the EXR
+                                image or as images next to the others
+    --exr-float                 store EXR colors as 32-bit floats instead of 16-bit ones
+    --exr-uncompressed          don't ZIP-compress EXR images
+    --jpeg-quality N            quality of JPEG images from 1 to 100, 90 by default
+
+integrators:
+    path                        path tracing (default)
+    bdpt                        bidirectional path tracing
+    photon                      progressive photon mapping
+    ao [SAMPLES [MAX_DISTANCE]] ambient occlusion, 16 rays per camera ray by default, occluded
+                                by any surface by default
+    normal | uv | depth | albedo | material-id | bvh-cost
+                                debug views of the surfaces seen by the camera";
+
+impl IntegratorKind {
+    /// Parse the command line arguments, the program name excluded.
+    fn from_args(args: &[String]) -> Result<Self, String> {
+        // Each integrator and the number of arguments it takes, its name included.
+        let (kind, arg_count) = match args.first().map(String::as_str) {
+            None | Some("path") => (IntegratorKind::PathTracing, 1),
+            Some("bdpt") => (IntegratorKind::Bdpt, 1),
+            Some("photon") => (IntegratorKind::PhotonMapping, 1),
+            Some("ao") => {
+                let samples = match args.get(1) {
+                    Some(arg) => arg
+                        .parse()
+                        .map_err(|_| format!("invalid number of samples '{}'", arg))?,
+                    None => 16,
+                };
+                let max_distance = match args.get(2) {
+                    Some(arg) => arg
+                        .parse()
+                        .map_err(|_| format!("invalid max distance '{}'", arg))?,
+                    None => Fp::MAX,
+                };
+                let kind = IntegratorKind::AmbientOcclusion {
+                    samples,
+                    max_distance,
+                };
+                (kind, 3)
+            }
+            Some("normal") => (IntegratorKind::Debug(DebugView::Normal), 1),
+            Some("uv") => (IntegratorKind::Debug(DebugView::Uv), 1),
+            Some("depth") => {
+                let view = DebugView::Depth {
+                    max_distance: 2000.0,
+                };
+                (IntegratorKind::Debug(view), 1)
+            }
+            Some("albedo") => (IntegratorKind::Debug(DebugView::Albedo), 1),
+            Some("material-id") => (IntegratorKind::Debug(DebugView::MaterialId), 1),
+            Some("bvh-cost") => {
+                let view = DebugView::BvhCost { max_cost: 100 };
+                (IntegratorKind::Debug(view), 1)
+            }
+            Some(name) => return Err(format!("unknown integrator '{}'", name)),
+        };
+
+        match args.get(arg_count) {
+            Some(arg) => Err(format!("unexpected argument '{}'", arg)),
+            None => Ok(kind),
+        }
+    }
 }
 
-fn nan_to_zero(color: &Color3F) -> Color3F {
-    Color3F::new(
-        if color.x != color.x { 0.0 } else { color.x },
-        if color.y != color.y { 0.0 } else { color.y },
-        if color.z != color.z { 0.0 } else { color.z },
-    )
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum OutputFormat {
+    /// Tonemapped into 8 bits per channel.
+    Ldr(ImageFormat),
+    Exr,
+    Hdr,
 }
 
-fn trace_row<R: rand::Rng>(
+impl OutputFormat {
+    fn from_path(path: &Path, jpeg_quality: u8) -> Result<Self, String> {
+        if let Some(format) = ImageFormat::from_path(path, jpeg_quality) {
+            return Ok(OutputFormat::Ldr(format));
+        }
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("exr") => Ok(OutputFormat::Exr),
+            Some("hdr") => Ok(OutputFormat::Hdr),
+            _ => Err(format!("unsupported image format '{}'", path.display())),
+        }
+    }
+}
+
+struct Options {
+    integrator: IntegratorKind,
+    output: PathBuf,
+    output_format: OutputFormat,
+    trace_aovs: bool,
+    exr_pixel_type: PixelType,
+    exr_compression: Compression,
+    jpeg_quality: u8,
+}
+
+impl Options {
+    /// Parse the command line arguments, the program name excluded. Options come before the
+    /// integrator.
+    fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut options = Options {
+            integrator: IntegratorKind::PathTracing,
+            output: PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("rendered.bmp"),
+            output_format: OutputFormat::Ldr(ImageFormat::Bmp),
+            trace_aovs: false,
+            exr_pixel_type: PixelType::Half,
+            exr_compression: Compression::Zip,
+            jpeg_quality: 90,
+        };
+
+        let mut args = args.iter().peekable();
+        while let Some(arg) = args.next_if(|arg| arg.starts_with("--")) {
+            match arg.as_str() {
+                "--output" => match args.next() {
+                    Some(path) => options.output = PathBuf::from(path),
+                    None => return Err("missing file after --output".to_string()),
+                },
+                "--aovs" => options.trace_aovs = true,
+                "--exr-float" => options.exr_pixel_type = PixelType::Float,
+                "--exr-uncompressed" => options.exr_compression = Compression::None,
+                "--jpeg-quality" => {
+                    options.jpeg_quality = match args.next().map(|arg| arg.parse()) {
+                        Some(Ok(quality @ 1..=100)) => quality,
+                        _ => return Err("--jpeg-quality needs a number from 1 to 100".to_string()),
+                    }
+                }
+                _ => return Err(format!("unknown option '{}'", arg)),
+            }
+        }
+
+        options.output_format = OutputFormat::from_path(&options.output, options.jpeg_quality)?;
+        options.integrator = IntegratorKind::from_args(&args.cloned().collect::<Vec<_>>())?;
+        Ok(options)
+    }
+}
+
+/// Encode a linear color with the sRGB curve, the same one textures are decoded with, so that
+/// they come out of an 8-bit image the way they went in.
+fn linear_to_srgb_color(color: &Color3F) -> Color3F {
+    let encode = |value: Fp| {
+        if value > 0.0 {
+            linear_to_srgb(value as f64) as Fp
+        } else {
+            0.0
+        }
+    };
+    Color3F::new(encode(color.x), encode(color.y), encode(color.z))
+}
+
+/// The color `aov` is shown with in an 8-bit image. Values such as normals are shown as they
+/// are, colors are sRGB encoded. `max_depth` is the depth shown as white.
+fn aov_display_color(aov: Aov, sample: &AovSample, max_depth: Fp) -> Color3F {
+    match aov {
+        Aov::Normal => 0.5 * (sample.normal + Color3F::new(1.0, 1.0, 1.0)),
+        Aov::Depth => {
+            let brightness = sample.depth / max_depth;
+            Color3F::new(brightness, brightness, brightness)
+        }
+        Aov::ObjectId if sample.object_id == 0 => Color3F::zero(),
+        Aov::ObjectId => id_color(sample.object_id as usize - 1),
+        _ => linear_to_srgb_color(&sample.get(aov)),
+    }
+}
+
+/// Trace the pixels of one row. If `row_aovs` is given, the AOVs of the pixels are traced too.
+/// If `with_alpha` is set, the coverage of the pixels is traced as alpha.
+#[allow(clippy::too_many_arguments)]
+fn trace_row(
     scene: &Scene,
+    integrator: &dyn Integrator,
     camera: &Camera,
     row_index: u32,
-    row_pixels: &mut [[u8; IMAGE_PIXEL_SIZE]],
+    row_pixels: &mut [FilmPixel],
+    with_alpha: bool,
+    mut row_aovs: Option<&mut [AovSample]>,
     pixel_samples: &[(Fp, Fp)],
     pixel_samples_scale: Fp,
-    rand: &mut R,
+    splats: &mut Splats,
+    rand: &mut SmallRng,
 ) {
     for col in 0..row_pixels.len() {
-        let mut pixel_color = Vec3F::zero();
+        let mut pixel = FilmPixel::default();
+        let mut pixel_aovs = AovSample::default();
 
         for rand_sample in pixel_samples.iter() {
             let ray = camera.gen_ray(col as u32, row_index, rand_sample.0, rand_sample.1, rand);
-            pixel_color += scene.trace(&ray, rand, 0);
+            let alpha = if with_alpha && scene.ray_intersect(&ray, &(0.001..Fp::MAX)).hit {
+                1.0
+            } else {
+                0.0
+            };
+            if row_aovs.is_some() {
+                let mut aovs = AovSample::default();
+                aovs.set_surface(scene, &ray);
+                let radiance = integrator.radiance_aovs(&ray, splats, rand, &mut aovs);
+                pixel.add_sample(nan_to_zero(&radiance), alpha);
+                pixel_aovs.add(&aovs);
+            } else {
+                let radiance = integrator.radiance(&ray, splats, rand);
+                pixel.add_sample(nan_to_zero(&radiance), alpha);
+            }
         }
 
-        pixel_color = pixel_color * pixel_samples_scale;
-        pixel_color = nan_to_zero(&pixel_color);
-        pixel_color = linear_to_gamma(&pixel_color);
+        row_pixels[col] = pixel;
+        if let Some(row_aovs) = row_aovs.as_deref_mut() {
+            pixel_aovs.scale(pixel_samples_scale);
+            row_aovs[col] = pixel_aovs;
+        }
+    }
+}
 
-        row_pixels[col] = Color3U8::from(pixel_color).into();
+/// Write the film, and the AOVs unless they weren't traced, in the format of the output file.
+fn write_output(
+    options: &Options,
+    film: &Film,
+    aovs: &[AovSample],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let image_path = &options.output;
+    match options.output_format {
+        OutputFormat::Ldr(format) => {
+            let image = match options.integrator {
+                // Shown as they are, like `aov_display_color` does for the AOVs.
+                IntegratorKind::Debug(view) if view.shows_values() => film.to_image(|color| *color),
+                _ => film.to_image(linear_to_srgb_color),
+            };
+            image.write(image_path, format)?;
+            if !aovs.is_empty() {
+                let max_depth = aovs.iter().map(|sample| sample.depth).fold(0.0, Fp::max);
+                for aov in Aov::ALL {
+                    let mut image = Image::new(film.width, film.height);
+                    for (i, sample) in aovs.iter().enumerate() {
+                        let row = i as u32 / film.width;
+                        let col = i as u32 % film.width;
+                        let color = aov_display_color(aov, sample, max_depth);
+                        image.write_pixel(row, col, Color3U8::from(color));
+                    }
+                    let extension = image_path.extension().unwrap().to_str().unwrap();
+                    let aov_path =
+                        image_path.with_extension(format!("{}.{}", aov.name(), extension));
+                    image.write(&aov_path, format)?;
+                }
+            }
+        }
+        OutputFormat::Exr => {
+            let mut channels = film.exr_channels(options.exr_pixel_type);
+            if !aovs.is_empty() {
+                channels.extend(aov_exr_channels(aovs, options.exr_pixel_type));
+            }
+            write_exr(
+                image_path,
+                film.width as usize,
+                film.height as usize,
+                channels,
+                options.exr_compression,
+            )
+            .map_err(|error| format!("can't write image '{}': {}", image_path.display(), error))?;
+        }
+        OutputFormat::Hdr => {
+            film.write_hdr(image_path)?;
+            // RGBE can't store negative values, the negative components of normals are lost.
+            if !aovs.is_empty() {
+                for aov in Aov::ALL {
+                    let values: Vec<Color3F> = aovs.iter().map(|sample| sample.get(aov)).collect();
+                    let aov_path = image_path.with_extension(format!("{}.hdr", aov.name()));
+                    write_hdr(&aov_path, film.width, film.height, &values)?;
+                }
+            }
+        }
     }
+    Ok(())
 }
 
 fn main() {
@@ -72,7 +348,24 @@ fn main() {
     const IMAGE_HEIGHT: u32 = 800;
     const PIXEL_SAMPLE_SIZE: usize = 400;
 
-    let image = Mutex::new(Image::new(IMAGE_WIDTH, IMAGE_HEIGHT));
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = match Options::from_args(&args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let trace_aovs = options.trace_aovs;
+
+    // Splats are added to the film once all the rows are traced.
+    let with_alpha = options.output_format == OutputFormat::Exr;
+    let film = Mutex::new(Film::new(IMAGE_WIDTH, IMAGE_HEIGHT, with_alpha));
+    let aovs = Mutex::new(if trace_aovs {
+        vec![AovSample::default(); (IMAGE_WIDTH * IMAGE_HEIGHT) as usize]
+    } else {
+        Vec::new()
+    });
 
     let scene = Scene::cornell_box();
 
@@ -89,113 +382,184 @@ fn main() {
 
     let camera = Scene::cornell_box_camera(IMAGE_WIDTH, IMAGE_HEIGHT);
 
+    let integrator: Result<Box<dyn Integrator>, UnsupportedEmitter> = match options.integrator {
+        IntegratorKind::PathTracing => Ok(Box::new(PathTracer::new(&scene))),
+        IntegratorKind::Bdpt => Bdpt::new(&scene, &camera, 10).map(|bdpt| Box::new(bdpt) as _),
+        IntegratorKind::PhotonMapping => PhotonMapping::new(&scene, 10, 200_000, 16, 8.0)
+            .map(|photon_mapping| Box::new(photon_mapping) as _),
+        IntegratorKind::AmbientOcclusion {
+            samples,
+            max_distance,
+        } => Ok(Box::new(AmbientOcclusion::new(
+            &scene,
+            samples,
+            max_distance,
+        ))),
+        IntegratorKind::Debug(view) => Ok(Box::new(DebugIntegrator::new(&scene, view))),
+    };
+    let mut integrator = match integrator {
+        Ok(integrator) => integrator,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    };
+
     let threads_num = thread::available_parallelism().unwrap().get() as u32;
     let rows_per_thread = IMAGE_HEIGHT / threads_num;
+    let pass_count = integrator.pass_count();
 
     println!(
-        "trace started (threads: {}, rows per thread: {})",
-        threads_num, rows_per_thread
+        "trace started (threads: {}, rows per thread: {}, passes: {})",
+        threads_num, rows_per_thread, pass_count
     );
 
     let rows_traced = AtomicU32::new(0);
+    let rows_total = IMAGE_HEIGHT * pass_count as u32;
+    let trace_start_ts = time::Instant::now();
 
-    thread::scope(|s| {
-        let rows_traced = &rows_traced;
+    for pass in 0..pass_count {
+        integrator.begin_pass(pass);
+        // AOVs don't change from one pass to the next, they are only traced in the first one.
+        let trace_aovs = trace_aovs && pass == 0;
 
-        let progress_thread = s.spawn(move || loop {
-            let rows_traced = rows_traced.load(atomic::Ordering::Relaxed);
-
-            print!(
-                "\rtrace progress: {:.2}%",
-                (rows_traced as Fp) / (IMAGE_HEIGHT as Fp) * 100.0
-            );
-            stdout().flush().unwrap();
-
-            if rows_traced == IMAGE_HEIGHT {
-                break;
-            }
-        });
+        thread::scope(|s| {
+            let rows_traced = &rows_traced;
+            let rows_traced_after_pass = IMAGE_HEIGHT * (pass as u32 + 1);
 
-        let trace_start_ts = time::Instant::now();
+            let progress_thread = s.spawn(move || loop {
+                let rows_traced = rows_traced.load(atomic::Ordering::Relaxed);
 
-        let mut trace_threads = Vec::with_capacity(threads_num as usize);
+                print!(
+                    "\rtrace progress: {:.2}%",
+                    (rows_traced as Fp) / (rows_total as Fp) * 100.0
+                );
+                stdout().flush().unwrap();
 
-        for thread_index in 0..threads_num {
-            let rows_num = if thread_index < (threads_num - 1) {
-                rows_per_thread
-            } else {
-                IMAGE_HEIGHT - rows_per_thread * (threads_num - 1)
-            };
-            let row_start_index = thread_index * rows_per_thread;
-
-            let image = &image;
-            let scene = &scene;
-            let camera = &camera;
-
-            trace_threads.push(s.spawn(move || {
-                let pixel_samples_scale = 1.0 / PIXEL_SAMPLE_SIZE as Fp;
-                let sqrt_spp = (PIXEL_SAMPLE_SIZE as Fp).sqrt(); // square root of number of samples per pixel (spp)
-                let inv_sqrt_spp = 1.0 / sqrt_spp;
-
-                let mut rand = SmallRng::seed_from_u64(1317);
-
-                // Stratified samples.
-                // Divide the pixel into a sqrt_spp by sqrt_spp grid. Pick a sample point in each
-                // grid cell.
-                // Assume the pixel has the size [0,0] to [1.0,1.0].
-                let sqrt_spp_u32 = sqrt_spp as u32;
-                let mut pixel_samples: Vec<(Fp, Fp)> = Vec::new();
-                pixel_samples.reserve((sqrt_spp_u32 * sqrt_spp_u32) as usize);
-                let mut si = 0.0;
-                let mut sj = 0.0;
-                while si < sqrt_spp {
-                    while sj < sqrt_spp {
-                        let rx_max = Fp::min((si + 1.0) * inv_sqrt_spp, 1.0);
-                        let rx = rand.gen_range((si * inv_sqrt_spp)..rx_max);
-                        let ry_max = Fp::min((sj + 1.0) * inv_sqrt_spp, 1.0);
-                        let ry = rand.gen_range((sj * inv_sqrt_spp)..ry_max);
-                        pixel_samples.push((rx, ry));
-                        sj += 1.0;
-                    }
-                    si += 1.0;
-                    sj = 0.0;
-                }
-
-                let mut row_pixels: Vec<[u8; IMAGE_PIXEL_SIZE]> = vec![];
-                row_pixels.resize(IMAGE_WIDTH as usize, [0, 0, 0]);
-
-                for r in 0..rows_num {
-                    let row_index = row_start_index + r;
-                    trace_row(
-                        scene,
-                        camera,
-                        row_index,
-                        &mut row_pixels,
-                        &pixel_samples,
-                        pixel_samples_scale,
-                        &mut rand,
-                    );
-                    image.lock().unwrap().write_row(row_index, &row_pixels);
-                    rows_traced.fetch_add(1, atomic::Ordering::SeqCst);
+                if rows_traced == rows_traced_after_pass {
+                    break;
                 }
-            }));
-        }
-
-        for thread in trace_threads.into_iter() {
-            assert!(thread.join().is_ok());
-        }
+            });
+
+            let mut trace_threads = Vec::with_capacity(threads_num as usize);
+
+            for thread_index in 0..threads_num {
+                let rows_num = if thread_index < (threads_num - 1) {
+                    rows_per_thread
+                } else {
+                    IMAGE_HEIGHT - rows_per_thread * (threads_num - 1)
+                };
+                let row_start_index = thread_index * rows_per_thread;
+
+                let film = &film;
+                let aovs = &aovs;
+                let scene = &scene;
+                let integrator = integrator.as_ref();
+                let camera = &camera;
+
+                trace_threads.push(s.spawn(move || {
+                    let sqrt_spp = (PIXEL_SAMPLE_SIZE as Fp).sqrt(); // square root of number of samples per pixel (spp)
+                    let inv_sqrt_spp = 1.0 / sqrt_spp;
+
+                    let mut rand = SmallRng::seed_from_u64(1317 + pass as u64);
+
+                    // Stratified samples.
+                    // Divide the pixel into a sqrt_spp by sqrt_spp grid. Pick a sample point in
+                    // each grid cell.
+                    // Assume the pixel has the size [0,0] to [1.0,1.0].
+                    let sqrt_spp_u32 = sqrt_spp as u32;
+                    let mut pixel_samples: Vec<(Fp, Fp)> = Vec::new();
+                    pixel_samples.reserve((sqrt_spp_u32 * sqrt_spp_u32) as usize);
+                    let mut si = 0.0;
+                    let mut sj = 0.0;
+                    while si < sqrt_spp {
+                        while sj < sqrt_spp {
+                            let rx_max = Fp::min((si + 1.0) * inv_sqrt_spp, 1.0);
+                            let rx = rand.gen_range((si * inv_sqrt_spp)..rx_max);
+                            let ry_max = Fp::min((sj + 1.0) * inv_sqrt_spp, 1.0);
+                            let ry = rand.gen_range((sj * inv_sqrt_spp)..ry_max);
+                            pixel_samples.push((rx, ry));
+                            sj += 1.0;
+                        }
+                        si += 1.0;
+                        sj = 0.0;
+                    }
+                    // Each pass takes every `pass_count`-th cell, so that the passes together
+                    // cover the grid.
+                    let pixel_samples: Vec<(Fp, Fp)> = pixel_samples
+                        .into_iter()
+                        .skip(pass)
+                        .step_by(pass_count)
+                        .collect();
+                    let pixel_samples_scale = 1.0 / pixel_samples.len() as Fp;
+
+                    let mut row_pixels = vec![FilmPixel::default(); IMAGE_WIDTH as usize];
+                    let with_alpha = film.lock().unwrap().has_alpha;
+                    let mut row_aovs =
+                        trace_aovs.then(|| vec![AovSample::default(); IMAGE_WIDTH as usize]);
+                    let mut splats = Splats::new(IMAGE_WIDTH, IMAGE_HEIGHT);
+
+                    for r in 0..rows_num {
+                        let row_index = row_start_index + r;
+                        trace_row(
+                            scene,
+                            integrator,
+                            camera,
+                            row_index,
+                            &mut row_pixels,
+                            with_alpha,
+                            row_aovs.as_deref_mut(),
+                            &pixel_samples,
+                            pixel_samples_scale,
+                            &mut splats,
+                            &mut rand,
+                        );
+                        let row_start = (row_index * IMAGE_WIDTH) as usize;
+                        for (pixel, traced) in film
+                            .lock()
+                            .unwrap()
+                            .row_mut(row_index)
+                            .iter_mut()
+                            .zip(row_pixels.iter())
+                        {
+                            pixel.merge(traced);
+                        }
+                        if let Some(row_aovs) = &row_aovs {
+                            aovs.lock().unwrap()[row_start..(row_start + row_aovs.len())]
+                                .copy_from_slice(row_aovs);
+                        }
+                        rows_traced.fetch_add(1, atomic::Ordering::SeqCst);
+                    }
 
-        let trace_time = trace_start_ts.elapsed();
+                    splats
+                }));
+            }
 
-        assert!(progress_thread.join().is_ok());
+            // The traced rows are copied into the film until the threads finish, so it can only
+            // be locked after they are joined.
+            let splats: Vec<_> = trace_threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect();
+            let mut film = film.lock().unwrap();
+            for splats in splats.iter() {
+                film.add_splats(splats, 1.0 / PIXEL_SAMPLE_SIZE as Fp);
+            }
 
-        println!(
-            "\ntrace completed in {} milliseconds",
-            trace_time.as_millis()
-        );
-    });
+            assert!(progress_thread.join().is_ok());
+        });
+    }
 
-    let image_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("rendered.bmp");
+    let trace_time = trace_start_ts.elapsed();
+    println!(
+        "\ntrace completed in {} milliseconds",
+        trace_time.as_millis()
+    );
 
-    image.lock().unwrap().write_bmp(&image_path).unwrap();
+    let film = film.lock().unwrap();
+    let aovs = aovs.lock().unwrap();
+    if let Err(error) = write_output(&options, &film, &aovs) {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
 }
@@ -1,7 +1,12 @@
+use crate::image::ImageError;
 use crate::shapes::{Ray, RayIntersection};
-use crate::textures::{Texture, TextureChecker, TextureImage, TextureSolidColor};
+use crate::textures::{
+    Projection, Texture, TextureChecker, TextureImage, TextureSolidColor, UvFootprint,
+};
 use crate::types::Fp;
-use crate::vecmath::{dot, Color3F, Vec3F, from_local_to_world_space};
+use crate::vecmath::{
+    cross, dot, from_local_to_world_space, local_basis, luminance, Color3F, Vec3F,
+};
 use std::path::Path;
 
 #[cfg(not(feature = "use-f64"))]
@@ -9,21 +14,63 @@ use std::f32::consts::PI;
 #[cfg(feature = "use-f64")]
 use std::f64::consts::PI;
 
+/// How a material bends the shading normal, to show detail that the shape doesn't have.
+pub enum NormalMap {
+    /// Normals in tangent space, encoded as colors from -1 at black to 1 at white: red along
+    /// `dpdu`, green along `dpdv` and blue along the normal. `strength` scales how far they lean
+    /// from the normal. The texture should be decoded linearly.
+    Tangent { map: Texture, strength: Fp },
+    /// Heights along the normal, the luminance of `height` times `scale` in world units, of which
+    /// only the slopes are shaded.
+    Bump { height: Texture, scale: Fp },
+}
+
+/// How the opacity of a material decides whether rays hit the surface.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum AlphaMode {
+    /// Hit where the opacity is at least `threshold`, for sharp outlines such as leaves.
+    Cutout { threshold: Fp },
+    /// Hit with the opacity as probability, for soft edges. The choice is a hash of the hit, so
+    /// that the same ray always makes the same one.
+    Stochastic,
+}
+
+/// Holes in a surface: the luminance of `texture`, from 0 where rays pass through to 1 where they
+/// hit.
+pub struct Opacity {
+    pub texture: Texture,
+    pub mode: AlphaMode,
+}
+
+/// What a material changes about the surface it's on, whatever the way it scatters light.
+#[derive(Default)]
+pub struct SurfaceMaps {
+    pub normal_map: Option<NormalMap>,
+    pub opacity: Option<Opacity>,
+    /// Where the textures of the material are looked up, the texture coordinates of the shapes if
+    /// `None`. Normal maps always use the ones of the shapes, which their tangents follow.
+    pub projection: Option<Projection>,
+}
+
 pub struct MaterialDiffuse {
     tex: Texture,
+    pub surface: SurfaceMaps,
 }
 
 pub struct MaterialMetal {
     pub albedo: Color3F,
     pub fuzz: Fp,
+    pub surface: SurfaceMaps,
 }
 
 pub struct MaterialDielectric {
     pub refrac_index: Fp,
+    pub surface: SurfaceMaps,
 }
 
 pub struct MaterialDiffuseLight {
     pub albedo: Color3F,
+    pub surface: SurfaceMaps,
 }
 
 pub enum Material {
@@ -37,32 +84,56 @@ impl MaterialDiffuse {
     pub fn new_solid_color(albedo: Color3F) -> Self {
         Self {
             tex: Texture::Solid(TextureSolidColor::new(albedo)),
+            surface: SurfaceMaps::default(),
         }
     }
 
     pub fn new_checker(even: Color3F, odd: Color3F, scale: Fp) -> Self {
         Self {
             tex: Texture::Checker(TextureChecker::new(even, odd, scale)),
+            surface: SurfaceMaps::default(),
         }
     }
 
-    pub fn from_image<P: AsRef<Path>>(image_path: P) -> Self {
+    pub fn from_image<P: AsRef<Path>>(image_path: P) -> Result<Self, ImageError> {
+        Ok(Self {
+            tex: Texture::Image(TextureImage::from_file(image_path)?),
+            surface: SurfaceMaps::default(),
+        })
+    }
+
+    pub fn from_texture(tex: Texture) -> Self {
         Self {
-            tex: Texture::Image(TextureImage::from_file(image_path)),
+            tex,
+            surface: SurfaceMaps::default(),
         }
     }
 
-    pub fn tex_color(&self, u: Fp, v: Fp, pos: Vec3F) -> Color3F {
-        self.tex.value(u, v, pos)
+    /// The color of the texture where `ray` hit the surface at `intersection`.
+    pub fn tex_color(&self, ray: &Ray, intersection: &RayIntersection) -> Color3F {
+        self.surface.texture_value(&self.tex, ray, intersection)
     }
 
-    pub fn scattering_pdf(&self, surface_normal: &Vec3F, scattered_ray: &Ray) -> Fp {
-        let cos_theta = dot(surface_normal, &scattered_ray.direction.normalized());
-        if cos_theta < 0.0 {
-            0.0
-        } else {
-            cos_theta / PI
+    /// Density of scattering into `scattered_ray`, a cosine lobe around `shading_normal` kept
+    /// above the surface of geometric normal `normal`. Directions of the lobe that a bent shading
+    /// normal sends into the surface are mirrored out of it, see `Scene::scatter`, so a direction
+    /// above the surface is reached either directly or as the mirror image of one below.
+    pub fn scattering_pdf(
+        &self,
+        shading_normal: &Vec3F,
+        normal: &Vec3F,
+        scattered_ray: &Ray,
+    ) -> Fp {
+        let dir = scattered_ray.direction.normalized();
+        let geometric_cos = dot(normal, &dir);
+        if geometric_cos < 0.0 {
+            return 0.0;
         }
+
+        let mirrored = dir - 2.0 * geometric_cos * *normal;
+        let cos_theta = Fp::max(dot(shading_normal, &dir), 0.0);
+        let mirrored_cos_theta = Fp::max(dot(shading_normal, &mirrored), 0.0);
+        (cos_theta + mirrored_cos_theta) / PI
     }
 }
 
@@ -77,13 +148,17 @@ impl MaterialMetal {
             } else {
                 fuzz
             },
+            surface: SurfaceMaps::default(),
         }
     }
 }
 
 impl MaterialDielectric {
     pub fn new(refrac_index: Fp) -> Self {
-        Self { refrac_index }
+        Self {
+            refrac_index,
+            surface: SurfaceMaps::default(),
+        }
     }
 
     pub fn reflectance(cos_in_angle: Fp, refrac_index: Fp) -> Fp {
@@ -96,7 +171,10 @@ impl MaterialDielectric {
 
 impl MaterialDiffuseLight {
     pub fn new(albedo: Color3F) -> Self {
-        Self { albedo }
+        Self {
+            albedo,
+            surface: SurfaceMaps::default(),
+        }
     }
 
     pub fn emit(&self, _u: Fp, _v: Fp, _p: Vec3F) -> Color3F {
@@ -104,10 +182,174 @@ impl MaterialDiffuseLight {
     }
 }
 
+// Step in texture coordinates of the finite differences of bump maps.
+const BUMP_DELTA: Fp = 0.0005;
+
+impl NormalMap {
+    /// The normal at `intersection` bent by the map, facing the same side as
+    /// `intersection.normal`. Textures are filtered over `footprint`.
+    pub fn shading_normal(&self, intersection: &RayIntersection, footprint: &UvFootprint) -> Vec3F {
+        // Maps are made for the outward side of the surface.
+        let (u, v, p) = (intersection.u, intersection.v, intersection.hit_point);
+        let n = if intersection.is_normal_outward {
+            intersection.normal
+        } else {
+            -intersection.normal
+        };
+        let bent = match self {
+            NormalMap::Tangent { map, strength } => {
+                let tangent = intersection.dpdu - dot(&n, &intersection.dpdu) * n;
+                let tangent = if tangent.length_squared() > 1e-12 {
+                    tangent.normalized()
+                } else {
+                    local_basis(&n)[0]
+                };
+                let bitangent = cross(&n, &tangent);
+                let bitangent = if dot(&bitangent, &intersection.dpdv) < 0.0 {
+                    -bitangent
+                } else {
+                    bitangent
+                };
+                let color = map.value(u, v, p, footprint);
+                let local = 2.0 * color - Color3F::new(1.0, 1.0, 1.0);
+                strength * local.x * tangent + strength * local.y * bitangent + local.z * n
+            }
+            NormalMap::Bump { height, scale } => {
+                let height_at = |u, v, p| scale * luminance(&height.value(u, v, p, footprint));
+                let h = height_at(u, v, p);
+                let dhdu = (height_at(u + BUMP_DELTA, v, p + BUMP_DELTA * intersection.dpdu) - h)
+                    / BUMP_DELTA;
+                let dhdv = (height_at(u, v + BUMP_DELTA, p + BUMP_DELTA * intersection.dpdv) - h)
+                    / BUMP_DELTA;
+                // The bumped surface is p + h * n, ignoring how n varies, which is small.
+                let bent = cross(
+                    &(intersection.dpdu + dhdu * n),
+                    &(intersection.dpdv + dhdv * n),
+                );
+                if dot(&bent, &n) < 0.0 {
+                    -bent
+                } else {
+                    bent
+                }
+            }
+        };
+        if bent.length_squared() < 1e-12 || bent.x.is_nan() {
+            return intersection.normal;
+        }
+        if intersection.is_normal_outward {
+            bent.normalized()
+        } else {
+            -bent.normalized()
+        }
+    }
+}
+
+impl SurfaceMaps {
+    /// The color of `texture` where `ray` hit the surface at `intersection`, filtered over the
+    /// footprint of the ray cone.
+    pub fn texture_value(
+        &self,
+        texture: &Texture,
+        ray: &Ray,
+        intersection: &RayIntersection,
+    ) -> Color3F {
+        match &self.projection {
+            None => {
+                let footprint = UvFootprint::from_ray_cone(ray, intersection);
+                texture.value(
+                    intersection.u,
+                    intersection.v,
+                    intersection.hit_point,
+                    &footprint,
+                )
+            }
+            Some(projection) => projection.value(
+                texture,
+                intersection.object_point,
+                intersection.object_normal,
+                intersection.hit_point,
+                ray.cone_width_at(intersection.t) * intersection.object_scale,
+            ),
+        }
+    }
+
+    /// Whether `ray` passes through a hole in the surface at `intersection`, see `Opacity`.
+    pub fn passes_through(&self, ray: &Ray, intersection: &RayIntersection) -> bool {
+        let Some(opacity_map) = &self.opacity else {
+            return false;
+        };
+        let opacity = luminance(&self.texture_value(&opacity_map.texture, ray, intersection));
+        match opacity_map.mode {
+            AlphaMode::Cutout { threshold } => opacity < threshold,
+            AlphaMode::Stochastic => {
+                hash_to_unit(&intersection.hit_point, &ray.direction) >= opacity
+            }
+        }
+    }
+}
+
+// A number in [0, 1) that looks random, from the bits of `a` and `b`.
+fn hash_to_unit(a: &Vec3F, b: &Vec3F) -> Fp {
+    let mut hash: u64 = 0;
+    for value in [a.x, a.y, a.z, b.x, b.y, b.z] {
+        // SplitMix64 steps.
+        hash = hash
+            .wrapping_add(u64::from(value.to_bits()))
+            .wrapping_add(0x9e37_79b9_7f4a_7c15);
+        hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        hash ^= hash >> 31;
+    }
+    // The top 24 bits fit in the mantissa of an f32.
+    (hash >> 40) as Fp / (1u64 << 24) as Fp
+}
+
 impl Material {
-    pub fn scattering_pdf(&self, surface_normal: &Vec3F, scattered_ray: &Ray) -> Fp {
+    /// The maps of the surface, see `SurfaceMaps`.
+    pub fn surface(&self) -> &SurfaceMaps {
         match self {
-            Material::Diffuse(mat) => mat.scattering_pdf(surface_normal, scattered_ray),
+            Material::Diffuse(mat) => &mat.surface,
+            Material::Metal(mat) => &mat.surface,
+            Material::Dielectric(mat) => &mat.surface,
+            Material::DiffuseLight(mat) => &mat.surface,
+        }
+    }
+
+    fn surface_mut(&mut self) -> &mut SurfaceMaps {
+        match self {
+            Material::Diffuse(mat) => &mut mat.surface,
+            Material::Metal(mat) => &mut mat.surface,
+            Material::Dielectric(mat) => &mut mat.surface,
+            Material::DiffuseLight(mat) => &mut mat.surface,
+        }
+    }
+
+    /// Bend the shading normal with `normal_map`.
+    pub fn with_normal_map(mut self, normal_map: NormalMap) -> Self {
+        self.surface_mut().normal_map = Some(normal_map);
+        self
+    }
+
+    /// Look the textures up at coordinates made by `projection` rather than the shapes.
+    pub fn with_projection(mut self, projection: Projection) -> Self {
+        self.surface_mut().projection = Some(projection);
+        self
+    }
+
+    /// Cut holes in the surface where `texture` is dark, see `Opacity`.
+    pub fn with_opacity(mut self, texture: Texture, mode: AlphaMode) -> Self {
+        self.surface_mut().opacity = Some(Opacity { texture, mode });
+        self
+    }
+
+    pub fn scattering_pdf(
+        &self,
+        shading_normal: &Vec3F,
+        normal: &Vec3F,
+        scattered_ray: &Ray,
+    ) -> Fp {
+        match self {
+            Material::Diffuse(mat) => mat.scattering_pdf(shading_normal, normal, scattered_ray),
             _ => 0.0,
         }
     }
@@ -118,5 +360,86 @@ impl Material {
             _ => Color3F::zero(),
         }
     }
+
+    /// The fraction of light the surface reflects where `ray` hit it at `intersection`. Lights
+    /// don't reflect, they report the color they emit, normalized so that its largest component
+    /// is one.
+    pub fn albedo(&self, ray: &Ray, intersection: &RayIntersection) -> Color3F {
+        match self {
+            Material::Diffuse(mat) => mat.tex_color(ray, intersection),
+            Material::Metal(mat) => mat.albedo,
+            Material::Dielectric(_) => Color3F::new(1.0, 1.0, 1.0),
+            Material::DiffuseLight(mat) => {
+                let max = Fp::max(mat.albedo.x, Fp::max(mat.albedo.y, mat.albedo.z));
+                if max > 0.0 {
+                    mat.albedo / max
+                } else {
+                    Color3F::zero()
+                }
+            }
+        }
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{ColorSpace, Image, PixelData};
+    use crate::shapes::Quad;
+    use crate::textures::{TextureFilter, WrapMode};
+    use std::sync::Arc;
+
+    #[test]
+    fn normal_maps_bend_the_shading_normal_only() {
+        // A unit square in the XY plane facing +Z, with u along X and v along Y.
+        let material = Arc::new(Material::Diffuse(MaterialDiffuse::new_solid_color(
+            Color3F::zero(),
+        )));
+        let quad = Quad::new(
+            Vec3F::zero(),
+            Vec3F::new(1.0, 0.0, 0.0),
+            Vec3F::new(0.0, 1.0, 0.0),
+            material,
+        );
+        let front = quad.ray_intersect(
+            &Ray::new(Vec3F::new(0.5, 0.5, 1.0), Vec3F::new(0.0, 0.0, -1.0), 0.0),
+            &(0.001..Fp::MAX),
+        );
+        let back = quad.ray_intersect(
+            &Ray::new(Vec3F::new(0.5, 0.5, -1.0), Vec3F::new(0.0, 0.0, 1.0), 0.0),
+            &(0.001..Fp::MAX),
+        );
+        assert_eq!(front.shading_normal, front.normal);
+        let close = |a: Vec3F, b: Vec3F| (a - b).length() < 1e-3;
+        let footprint = UvFootprint::default();
+
+        // Leaning halfway towards +u.
+        let tangent = NormalMap::Tangent {
+            map: Texture::Solid(TextureSolidColor::new(Color3F::new(0.75, 0.5, 1.0))),
+            strength: 1.0,
+        };
+        let leaning = Vec3F::new(0.5, 0.0, 1.0).normalized();
+        assert!(close(tangent.shading_normal(&front, &footprint), leaning));
+        // The map is on the outside, seen from the back it's mirrored to the ray's side.
+        assert!(close(tangent.shading_normal(&back, &footprint), -leaning));
+
+        // Heights rising from 0 to `scale` over the middle half of the square in u, so a slope
+        // of 2 * `scale` leaning the normal towards -u.
+        let ramp = Image {
+            width: 2,
+            height: 1,
+            channels: 1,
+            data: PixelData::U8(vec![0, 255]),
+        };
+        let bump = NormalMap::Bump {
+            height: Texture::Image(
+                TextureImage::from_image(&ramp, ColorSpace::Linear)
+                    .with_wrap(WrapMode::Clamp)
+                    .with_filter(TextureFilter::Bilinear),
+            ),
+            scale: 0.5,
+        };
+        let sloped = Vec3F::new(-1.0, 0.0, 1.0).normalized();
+        assert!(close(bump.shading_normal(&front, &footprint), sloped));
+    }
+}
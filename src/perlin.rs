@@ -0,0 +1,122 @@
+use crate::types::Fp;
+use crate::vecmath::{dot, Vec3F};
+use rand::{rngs::SmallRng, seq::SliceRandom, SeedableRng};
+
+const POINT_COUNT: usize = 256;
+
+/// Perlin gradient noise: random unit gradients on the integer lattice, blended smoothly in
+/// between. The same seed always gives the same noise.
+pub struct Perlin {
+    gradients: Vec<Vec3F>,
+    // One permutation of the lattice indices per axis, hashed together into a gradient index.
+    perm: [Vec<usize>; 3],
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Self {
+        let mut rand = SmallRng::seed_from_u64(seed);
+        let gradients = (0..POINT_COUNT)
+            .map(|_| loop {
+                let dir = Vec3F::random_fp_range(&mut rand, -1.0..1.0);
+                let len_sqr = dir.length_squared();
+                if (1e-8..=1.0).contains(&len_sqr) {
+                    break dir / Fp::sqrt(len_sqr);
+                }
+            })
+            .collect();
+        let mut permutation = || {
+            let mut perm: Vec<usize> = (0..POINT_COUNT).collect();
+            perm.shuffle(&mut rand);
+            perm
+        };
+        let perm = [permutation(), permutation(), permutation()];
+        Self { gradients, perm }
+    }
+
+    /// Noise at `p`, in [-1, 1] and 0 on the lattice points.
+    pub fn noise(&self, p: Vec3F) -> Fp {
+        let (x, y, z) = (Fp::floor(p.x), Fp::floor(p.y), Fp::floor(p.z));
+        let (fx, fy, fz) = (p.x - x, p.y - y, p.z - z);
+        let (i, j, k) = (x as i64, y as i64, z as i64);
+
+        let mut sum = 0.0;
+        for di in 0..2 {
+            for dj in 0..2 {
+                for dk in 0..2 {
+                    let index = self.perm[0][((i + di) & 255) as usize]
+                        ^ self.perm[1][((j + dj) & 255) as usize]
+                        ^ self.perm[2][((k + dk) & 255) as usize];
+                    let (wx, wy, wz) = (fx - di as Fp, fy - dj as Fp, fz - dk as Fp);
+                    let weight = fade(1.0 - wx.abs()) * fade(1.0 - wy.abs()) * fade(1.0 - wz.abs());
+                    sum += weight * dot(&self.gradients[index], &Vec3F::new(wx, wy, wz));
+                }
+            }
+        }
+        // The dot products are at most sqrt(3)/2 away from the lattice point.
+        (sum * 2.0 / Fp::sqrt(3.0)).clamp(-1.0, 1.0)
+    }
+
+    /// Fractional Brownian motion: `octaves` layers of noise, each twice the frequency and half
+    /// the amplitude of the previous one. In [-1, 1].
+    pub fn fbm(&self, p: Vec3F, octaves: u32) -> Fp {
+        self.octaves(p, octaves, |noise| noise)
+    }
+
+    /// Like `fbm`, but summing the absolute value of the noise, which creases it. In [0, 1].
+    pub fn turbulence(&self, p: Vec3F, octaves: u32) -> Fp {
+        self.octaves(p, octaves, Fp::abs)
+    }
+
+    fn octaves(&self, p: Vec3F, octaves: u32, layer: impl Fn(Fp) -> Fp) -> Fp {
+        let (mut sum, mut amplitude, mut total) = (0.0, 1.0, 0.0);
+        let mut p = p;
+        for _ in 0..octaves.max(1) {
+            sum += amplitude * layer(self.noise(p));
+            total += amplitude;
+            amplitude *= 0.5;
+            p = p * 2.0;
+        }
+        sum / total
+    }
+}
+
+// Smoothstep with zero first and second derivatives at 0 and 1, so that the blend between lattice
+// cells doesn't show.
+fn fade(t: Fp) -> Fp {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn noise_is_seeded_bounded_and_smooth() {
+        let perlin = Perlin::new(5);
+        let mut rand = SmallRng::seed_from_u64(1);
+        let mut spread = (Fp::MAX, Fp::MIN);
+        for _ in 0..1000 {
+            let p = Vec3F::random_fp_range(&mut rand, -20.0..20.0);
+            let noise = perlin.noise(p);
+            assert!((-1.0..=1.0).contains(&noise));
+            spread = (spread.0.min(noise), spread.1.max(noise));
+            assert_eq!(noise, Perlin::new(5).noise(p));
+
+            // Gradient noise vanishes on the lattice and doesn't jump.
+            let lattice = Vec3F::new(p.x.floor(), p.y.floor(), p.z.floor());
+            assert!(perlin.noise(lattice).abs() < 1e-5);
+            let step = Vec3F::new(1e-3, 0.0, 0.0);
+            assert!((perlin.noise(p + step) - noise).abs() < 1e-2);
+
+            let octaves = rand.gen_range(1..8);
+            assert!((-1.0..=1.0).contains(&perlin.fbm(p, octaves)));
+            assert!((0.0..=1.0).contains(&perlin.turbulence(p, octaves)));
+        }
+        assert!(spread.0 < -0.3 && spread.1 > 0.3, "{:?}", spread);
+        assert_ne!(
+            perlin.noise(Vec3F::new(0.5, 0.5, 0.5)),
+            Perlin::new(6).noise(Vec3F::new(0.5, 0.5, 0.5))
+        );
+    }
+}
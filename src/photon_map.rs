@@ -0,0 +1,395 @@
+use crate::integrator::{Integrator, Splats};
+use crate::lights::{LightEmitters, UnsupportedEmitter};
+use crate::materials::Material;
+use crate::scene::Scene;
+use crate::shapes::{Aabb, Ray};
+use crate::types::Fp;
+use crate::vecmath::{dot, Color3F, Vec3F};
+use rand::{rngs::SmallRng, RngCore, SeedableRng};
+use std::ops::Range;
+use std::thread;
+
+#[cfg(not(feature = "use-f64"))]
+use std::f32::consts::PI;
+#[cfg(feature = "use-f64")]
+use std::f64::consts::PI;
+
+#[derive(Copy, Clone)]
+pub struct Photon {
+    pub p: Vec3F,
+    /// Normal of the surface the photon landed on, on the side it came from.
+    pub normal: Vec3F,
+    /// Flux carried by the photon.
+    pub power: Color3F,
+}
+
+/// Photons stored in a balanced kd-tree, for finding the photons around a point.
+///
+/// The tree is implicit: the photons of a subtree are stored in a contiguous range, with the
+/// median along the splitting axis in the middle, the photons before it on one side of the
+/// splitting plane and the ones after it on the other side.
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    /// Splitting axis of the subtree whose median is the photon at the same index.
+    axes: Vec<u8>,
+}
+
+impl PhotonMap {
+    pub fn new(mut photons: Vec<Photon>) -> Self {
+        let mut axes = vec![0; photons.len()];
+        Self::build(&mut photons, &mut axes);
+        Self { photons, axes }
+    }
+
+    fn build(photons: &mut [Photon], axes: &mut [u8]) {
+        if photons.len() <= 1 {
+            return;
+        }
+
+        // Split along the axis where the photons spread the most.
+        let points: Vec<_> = photons.iter().map(|photon| photon.p).collect();
+        let axis = Aabb::from_points(&points).longest_axis();
+
+        let median = photons.len() / 2;
+        photons.select_nth_unstable_by(median, |a, b| a.p.axis(axis).total_cmp(&b.p.axis(axis)));
+        axes[median] = axis as u8;
+
+        let (left, right) = photons.split_at_mut(median);
+        let (left_axes, right_axes) = axes.split_at_mut(median);
+        Self::build(left, left_axes);
+        Self::build(&mut right[1..], &mut right_axes[1..]);
+    }
+
+    /// Call `f` with every photon within `radius` of `p`.
+    pub fn for_each_within<F: FnMut(&Photon)>(&self, p: &Vec3F, radius: Fp, mut f: F) {
+        self.for_each_within_range(0..self.photons.len(), p, radius * radius, &mut f);
+    }
+
+    fn for_each_within_range<F: FnMut(&Photon)>(
+        &self,
+        range: Range<usize>,
+        p: &Vec3F,
+        radius_sqr: Fp,
+        f: &mut F,
+    ) {
+        if range.is_empty() {
+            return;
+        }
+
+        let median = range.start + range.len() / 2;
+        let photon = &self.photons[median];
+        if (photon.p - *p).length_squared() <= radius_sqr {
+            f(photon);
+        }
+
+        if range.len() == 1 {
+            return;
+        }
+
+        // Visit the side of the splitting plane `p` is on first, and the other side only if the
+        // sphere around `p` crosses the plane.
+        let axis = self.axes[median] as usize;
+        let dist = p.axis(axis) - photon.p.axis(axis);
+        let (near, far) = if dist < 0.0 {
+            (range.start..median, (median + 1)..range.end)
+        } else {
+            ((median + 1)..range.end, range.start..median)
+        };
+        self.for_each_within_range(near, p, radius_sqr, f);
+        if dist * dist <= radius_sqr {
+            self.for_each_within_range(far, p, radius_sqr, f);
+        }
+    }
+}
+
+/// Progressive photon mapping, as in "Progressive Photon Mapping: A Probabilistic Approach" by
+/// Knaus and Zwicker. Photons are traced from the lights and stored where they land on diffuse
+/// surfaces. Camera rays go through specular surfaces until they land on a diffuse one, where the
+/// radiance is estimated from the density of the photons around.
+///
+/// The image is rendered in passes, each tracing its own photon map before its camera rays are
+/// gathered from it, and dropping it afterwards. The gather radius shrinks from one pass to the
+/// next, so that the bias of the estimate vanishes as more passes are averaged. This finds the
+/// paths that can't be sampled from either end alone, such as a caustic seen through glass.
+///
+/// Photons are traced at time 0.0, and lights made of CSG shapes or instances can't emit them, see
+/// `LightEmitters`. A sky background is only seen directly or through specular surfaces.
+pub struct PhotonMapping<'a> {
+    scene: &'a Scene,
+    emitters: LightEmitters<'a>,
+    /// Maximum number of bounces of camera rays and photons.
+    max_depth: usize,
+    photons_per_pass: usize,
+    pass_count: usize,
+    initial_radius: Fp,
+    /// The photon map of the current pass and its gather radius.
+    map: PhotonMap,
+    radius: Fp,
+}
+
+impl<'a> PhotonMapping<'a> {
+    /// How fast the gather radius shrinks. The area of the gather disk is multiplied by
+    /// (i + ALPHA) / (i + 1) after the i-th pass.
+    const ALPHA: Fp = 2.0 / 3.0;
+
+    /// Render in `pass_count` passes of `photons_per_pass` photons each. The gather radius starts
+    /// at `initial_radius`.
+    pub fn new(
+        scene: &'a Scene,
+        max_depth: usize,
+        photons_per_pass: usize,
+        pass_count: usize,
+        initial_radius: Fp,
+    ) -> Result<Self, UnsupportedEmitter> {
+        Ok(Self {
+            scene,
+            emitters: LightEmitters::new(scene.lights())?,
+            max_depth,
+            photons_per_pass,
+            pass_count,
+            initial_radius,
+            map: PhotonMap::new(Vec::new()),
+            radius: initial_radius,
+        })
+    }
+
+    // The gather radius of pass `pass`, counting from 0.
+    fn pass_radius(&self, pass: usize) -> Fp {
+        let mut radius_sqr = self.initial_radius * self.initial_radius;
+        for i in 1..=pass {
+            radius_sqr *= (i as Fp + Self::ALPHA) / (i as Fp + 1.0);
+        }
+        radius_sqr.sqrt()
+    }
+
+    // Trace the photons of pass `pass`, split among as many threads as the machine can run at once.
+    fn trace_pass_photons(&self, pass: usize) -> Vec<Photon> {
+        let threads_num = thread::available_parallelism().unwrap().get();
+        let photons_per_thread = self.photons_per_pass / threads_num;
+        // Each photon carries its share of the flux of all the photons of the pass.
+        let power_scale = 1.0 / self.photons_per_pass as Fp;
+
+        thread::scope(|s| {
+            let trace_threads: Vec<_> = (0..threads_num)
+                .map(|thread_index| {
+                    let count = if thread_index < threads_num - 1 {
+                        photons_per_thread
+                    } else {
+                        self.photons_per_pass - photons_per_thread * (threads_num - 1)
+                    };
+                    s.spawn(move || {
+                        let seed = 4021 + (pass * threads_num + thread_index) as u64;
+                        let mut rand = SmallRng::seed_from_u64(seed);
+                        Self::trace_photons(
+                            self.scene,
+                            &self.emitters,
+                            self.max_depth,
+                            count,
+                            power_scale,
+                            &mut rand,
+                        )
+                    })
+                })
+                .collect();
+
+            trace_threads
+                .into_iter()
+                .flat_map(|thread| thread.join().unwrap())
+                .collect()
+        })
+    }
+
+    // Trace `count` photons, each carrying `power_scale` of the flux its path samples.
+    fn trace_photons<R: rand::Rng>(
+        scene: &Scene,
+        emitters: &LightEmitters,
+        max_depth: usize,
+        count: usize,
+        power_scale: Fp,
+        rand: &mut R,
+    ) -> Vec<Photon> {
+        let mut photons = Vec::new();
+        if emitters.is_empty() {
+            return photons;
+        }
+
+        for _ in 0..count {
+            let Some(point) = emitters.sample_point(0.0, rand) else {
+                continue;
+            };
+            let (dir, pdf_dir) = LightEmitters::sample_dir(&point, rand);
+            let cos_theta = dot(&point.normal, &dir).abs();
+            let mut power = point.emission * cos_theta * power_scale / (point.pdf_pos * pdf_dir);
+            let mut ray = Ray::new(point.p, dir, 0.0);
+
+            for _ in 0..max_depth {
+                let intersection = scene.ray_intersect(&ray, &(0.001..Fp::MAX));
+                if !intersection.hit {
+                    break;
+                }
+
+                let material = intersection.material.unwrap();
+                if let Material::Diffuse(_) = material {
+                    photons.push(Photon {
+                        p: intersection.hit_point,
+                        normal: intersection.normal,
+                        power,
+                    });
+                }
+
+                let Some(scattered) = Scene::scatter(&ray, &intersection, material, rand) else {
+                    break;
+                };
+                power = if scattered.skip_pdf {
+                    power * scattered.albedo
+                } else {
+                    let scattering_pdf = material.scattering_pdf(
+                        &intersection.shading_normal,
+                        &intersection.normal,
+                        &scattered.ray,
+                    );
+                    power * scattered.albedo * scattering_pdf / scattered.probability
+                };
+                ray = scattered.ray;
+            }
+        }
+
+        photons
+    }
+
+    fn trace<R: rand::Rng>(&self, ray: &Ray, rand: &mut R) -> Color3F {
+        let radius = self.radius;
+        let mut ray = *ray;
+        let mut beta = Color3F::new(1.0, 1.0, 1.0);
+        let mut l = Color3F::zero();
+
+        for _ in 0..=self.max_depth {
+            let intersection = self.scene.ray_intersect(&ray, &(0.001..Fp::MAX));
+            if !intersection.hit {
+                return l + beta * self.scene.background(&ray);
+            }
+
+            let material = intersection.material.unwrap();
+            l += beta * material.emit();
+
+            if let Material::Diffuse(mat) = material {
+                // Density estimation: the flux of the photons around, spread over the gather
+                // disk and reflected towards the camera.
+                let mut flux = Color3F::zero();
+                self.map
+                    .for_each_within(&intersection.hit_point, radius, |photon| {
+                        if dot(&photon.normal, &intersection.normal) > 0.0 {
+                            flux += photon.power;
+                        }
+                    });
+                let albedo = mat.tex_color(&ray, &intersection);
+                return l + beta * albedo * flux / (PI * PI * radius * radius);
+            }
+
+            let Some(scattered) = Scene::scatter(&ray, &intersection, material, rand) else {
+                break;
+            };
+            beta = beta * scattered.albedo;
+            ray = scattered.ray;
+        }
+
+        l
+    }
+}
+
+impl<'a> Integrator for PhotonMapping<'a> {
+    fn radiance(&self, ray: &Ray, _splats: &mut Splats, mut rand: &mut dyn RngCore) -> Color3F {
+        self.trace(ray, &mut rand)
+    }
+
+    fn pass_count(&self) -> usize {
+        self.pass_count
+    }
+
+    fn begin_pass(&mut self, pass: usize) {
+        // Replacing the map drops the one of the previous pass.
+        self.map = PhotonMap::new(self.trace_pass_photons(pass));
+        self.radius = self.pass_radius(pass);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::integrator::PathTracer;
+    use crate::vecmath::luminance;
+    use rand::Rng;
+
+    // Average radiance over the image seen by `camera`, with `spp` samples per pixel in each of
+    // the integrator's passes.
+    fn mean_radiance(integrator: &mut dyn Integrator, camera: &Camera, size: u32, spp: u32) -> Fp {
+        let mut rand = SmallRng::seed_from_u64(7);
+        let mut splats = Splats::new(size, size);
+        let mut sum = Color3F::zero();
+        let pass_count = integrator.pass_count();
+        for pass in 0..pass_count {
+            integrator.begin_pass(pass);
+            for row in 0..size {
+                for col in 0..size {
+                    for _ in 0..spp {
+                        let (rx, ry) = (rand.gen_range(0.0..1.0), rand.gen_range(0.0..1.0));
+                        let ray = camera.gen_ray(col, row, rx, ry, &mut rand);
+                        sum += integrator.radiance(&ray, &mut splats, &mut rand);
+                    }
+                }
+            }
+        }
+        luminance(&sum) / (size * size * spp * pass_count as u32) as Fp
+    }
+
+    // Photon mapping is biased by the gather radius, which blurs the lighting, but the image
+    // should still be about as bright.
+    #[test]
+    fn photon_mapping_matches_path_tracer() {
+        let scene = Scene::cornell_box();
+        let size = 16;
+        let camera = Scene::cornell_box_camera(size, size);
+
+        let expected = mean_radiance(&mut PathTracer::new(&scene), &camera, size, 256);
+        let mut photon_mapping = PhotonMapping::new(&scene, 50, 20_000, 4, 10.0).unwrap();
+        let actual = mean_radiance(&mut photon_mapping, &camera, size, 16);
+        assert!(
+            (actual - expected).abs() < 0.1 * expected,
+            "mean radiance {} instead of {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn kd_tree_finds_photons_within_radius() {
+        let mut rand = SmallRng::seed_from_u64(3);
+        let photons: Vec<Photon> = (0..2000)
+            .map(|_| Photon {
+                p: Vec3F::random_fp_range(&mut rand, -1.0..1.0),
+                normal: Vec3F::new(0.0, 1.0, 0.0),
+                power: Color3F::new(1.0, 1.0, 1.0),
+            })
+            .collect();
+        let map = PhotonMap::new(photons.clone());
+
+        for _ in 0..100 {
+            let p = Vec3F::random_fp_range(&mut rand, -1.2..1.2);
+            let radius = rand.gen_range(0.05..0.5);
+
+            let mut found = Vec::new();
+            map.for_each_within(&p, radius, |photon| found.push(photon.p));
+            let mut expected: Vec<Vec3F> = photons
+                .iter()
+                .filter(|photon| (photon.p - p).length() <= radius)
+                .map(|photon| photon.p)
+                .collect();
+
+            let by_x = |a: &Vec3F, b: &Vec3F| a.axis(0).total_cmp(&b.axis(0));
+            found.sort_by(by_x);
+            expected.sort_by(by_x);
+            assert!(found == expected);
+        }
+    }
+}
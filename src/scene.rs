@@ -1,10 +1,20 @@
 use crate::camera::Camera;
+use crate::image::{ColorSpace, Image, ImageError};
+use crate::lights::LightSampler;
 use crate::materials::{
-    Material, MaterialDielectric, MaterialDiffuse, MaterialDiffuseLight, MaterialMetal,
+    AlphaMode, Material, MaterialDielectric, MaterialDiffuse, MaterialDiffuseLight, MaterialMetal,
+    NormalMap,
+};
+use crate::shapes::{
+    create_box_quads, Cone, Csg, CsgOperation, Cylinder, Disk, Instance, Quad, Ray,
+    RayIntersection, Shape, ShapeGroup, Sphere, Torus,
+};
+use crate::textures::{
+    ColorChannel, ColorRamp, NoiseKind, Projection, Texture, TextureChecker, TextureFilter,
+    TextureImage, TextureNoise, TextureSolidColor, UvFootprint, UvTransform, WrapMode,
 };
-use crate::shapes::{create_box_quads, Aabb, Quad, Ray, RayIntersection, Shape, Sphere};
 use crate::types::Fp;
-use crate::vecmath::{dot, Color3F, Vec3F, from_local_to_world_space, reflect};
+use crate::vecmath::{dot, from_local_to_world_space, reflect, Color3F, Color3U8, Mat4F, Vec3F};
 use rand::{rngs::SmallRng, Rng, SeedableRng};
 use std::ops::Range;
 use std::path::Path;
@@ -16,97 +26,13 @@ use std::f32::consts::PI;
 #[cfg(feature = "use-f64")]
 use std::f64::consts::PI;
 
-pub struct BvhLeaf {
-    pub aabb: Aabb,
-    pub shape_index: usize,
-}
-
-pub struct BvhLink {
-    pub aabb: Aabb,
-    pub left: Arc<BvhNode>,
-    pub right: Arc<BvhNode>,
-}
-
-pub enum BvhNode {
-    Leaf(BvhLeaf),
-    Link(BvhLink),
-}
-
-impl BvhNode {
-    pub fn aabb(&self) -> &Aabb {
-        match self {
-            BvhNode::Leaf(leaf) => &leaf.aabb,
-            BvhNode::Link(link) => &link.aabb,
-        }
-    }
-}
-
-fn build_bvh(shapes: &[Shape], shape_start: usize) -> Arc<BvhNode> {
-    if shapes.len() == 1 {
-        Arc::new(BvhNode::Leaf(BvhLeaf {
-            aabb: shapes[0].calc_aabb(),
-            shape_index: shape_start,
-        }))
-    } else {
-        let middle = shapes.len() / 2;
-        let left = build_bvh(&shapes[..middle], shape_start);
-        let right = build_bvh(&shapes[middle..], shape_start + middle);
-
-        Arc::new(BvhNode::Link(BvhLink {
-            aabb: Aabb::merge(&left.aabb(), &right.aabb()),
-            left,
-            right,
-        }))
-    }
-}
-
-fn bvh_ray_intersect(
-    bvh_node: Arc<BvhNode>,
-    shapes: &[Shape],
-    ray: &Ray,
-    limits: &Range<Fp>,
-) -> (RayIntersection, usize) {
-    if bvh_node.aabb().ray_intersect(ray) {
-        match &*bvh_node {
-            BvhNode::Leaf(leaf) => (
-                shapes[leaf.shape_index].ray_intersect(ray, limits),
-                leaf.shape_index,
-            ),
-            BvhNode::Link(link) => {
-                let (left_intersection, left_sphere_index) =
-                    bvh_ray_intersect(Arc::clone(&link.left), shapes, ray, limits);
-                let right_limits = limits.start..if left_intersection.hit {
-                    left_intersection.t
-                } else {
-                    limits.end
-                };
-                let (right_intersection, right_sphere_index) =
-                    bvh_ray_intersect(Arc::clone(&link.right), shapes, ray, &right_limits);
-                if right_intersection.hit {
-                    (right_intersection, right_sphere_index)
-                } else {
-                    (left_intersection, left_sphere_index)
-                }
-            }
-        }
-    } else {
-        (
-            RayIntersection {
-                hit: false,
-                ..Default::default()
-            },
-            0,
-        )
-    }
-}
-
-struct PdfSample {
-    dir: Vec3F,
-    probability: Fp,
+pub struct PdfSample {
+    pub dir: Vec3F,
+    pub probability: Fp,
 }
 
 // p(a, b) = cos(a)/PI, a is polar angle, b is azimuthal angle.
-struct PdfCosineHemisphere {}
+pub struct PdfCosineHemisphere {}
 
 impl PdfCosineHemisphere {
     pub fn new() -> Self {
@@ -149,23 +75,62 @@ fn refract(in_dir: &Vec3F, normal: &Vec3F, refrac_index: Fp) -> Vec3F {
     refrac_dir_perp + refrac_dir_parallel
 }
 
+// Bend the shading normal of `intersection` with the normal map of its material, if any.
+fn apply_normal_map(ray: &Ray, intersection: &mut RayIntersection) {
+    let Some(material) = intersection.material.filter(|_| intersection.hit) else {
+        return;
+    };
+    if let Some(normal_map) = &material.surface().normal_map {
+        let footprint = UvFootprint::from_ray_cone(ray, intersection);
+        intersection.shading_normal = normal_map.shading_normal(intersection, &footprint);
+    }
+}
+
 pub struct ScatterResult {
     pub ray: Ray,
     pub albedo: Color3F,
     pub probability: Fp,
+    /// Whether `ray` is the only direction the material scatters into, so that it's followed as
+    /// is rather than mixed with samples towards the lights, see `PathTracer::bounce`.
     pub skip_pdf: bool,
 }
 
 pub struct Scene {
     materials: Vec<Arc<Material>>,
-    shapes: Vec<Shape>,
-    lights: Vec<Shape>,
-    // bvh: Arc<BvhNode>,
+    shapes: ShapeGroup,
+    /// The shapes in `shapes` that emit light.
+    lights: LightSampler,
+    /// Shapes that scattered rays are sent towards more often, besides the lights.
+    attractors: LightSampler,
     is_background_sky: bool,
 }
 
 impl Scene {
-    const TRACE_MAX_DEPTH: u32 = 50;
+    /// Create a scene out of `shapes`, sampling the ones that emit light as lights.
+    ///
+    /// `attractors` are shapes that don't emit light but are worth sending more rays towards,
+    /// e.g. glass objects that focus light. Only their geometry is used, so they don't need to be
+    /// part of `shapes`, and their materials are ignored.
+    fn new(
+        materials: Vec<Arc<Material>>,
+        shapes: Vec<Shape>,
+        attractors: Vec<Shape>,
+        is_background_sky: bool,
+    ) -> Self {
+        let lights = shapes
+            .iter()
+            .filter(|shape| shape.emitted_power() > 0.0)
+            .cloned()
+            .collect();
+
+        Self {
+            materials,
+            shapes: ShapeGroup::new(shapes),
+            lights: LightSampler::new(lights),
+            attractors: LightSampler::new_by_area(attractors),
+            is_background_sky,
+        }
+    }
 
     #[allow(dead_code)]
     pub fn one_sphere() -> Self {
@@ -179,13 +144,7 @@ impl Scene {
             Arc::clone(&materials[0]),
         ))];
 
-        Self {
-            materials,
-            shapes,
-            lights: Vec::new(),
-            // bvh: build_bvh(&shapes, 0),
-            is_background_sky: true,
-        }
+        Self::new(materials, shapes, Vec::new(), true)
     }
 
     #[allow(dead_code)]
@@ -211,20 +170,14 @@ impl Scene {
             )),
         ];
 
-        Self {
-            materials,
-            shapes,
-            lights: Vec::new(),
-            // bvh: build_bvh(&shapes, 0),
-            is_background_sky: true,
-        }
+        Self::new(materials, shapes, Vec::new(), true)
     }
 
     #[allow(dead_code)]
-    pub fn two_globes() -> Scene {
+    pub fn two_globes() -> Result<Scene, ImageError> {
         let mat = Arc::new(Material::Diffuse(MaterialDiffuse::from_image(Path::new(
             "images/earthmap.jpg",
-        ))));
+        ))?));
         let materials = vec![Arc::clone(&mat)];
 
         let globes = vec![
@@ -240,13 +193,378 @@ impl Scene {
             )),
         ];
 
-        Self {
-            materials,
-            shapes: globes,
-            lights: Vec::new(),
-            // bvh: build_bvh(&globes, 0),
-            is_background_sky: true,
+        Ok(Self::new(materials, globes, Vec::new(), true))
+    }
+
+    /// Strips of floor receding from the camera, tiled with the same image filtered differently,
+    /// in front of a wall with a tilted checker. From left to right: nearest (clamped, so not
+    /// tiled), bilinear, trilinear and EWA.
+    #[allow(dead_code)]
+    pub fn texture_filters() -> Result<Scene, ImageError> {
+        let image = Image::from_file(Path::new("images/checker-test.ppm"))?;
+        let textures = [
+            TextureImage::from_image(&image, ColorSpace::Srgb)
+                .with_wrap(WrapMode::Clamp)
+                .with_filter(TextureFilter::Nearest),
+            TextureImage::from_image(&image, ColorSpace::Srgb)
+                .with_wrap(WrapMode::Mirror)
+                .with_filter(TextureFilter::Bilinear),
+            TextureImage::from_image(&image, ColorSpace::Srgb)
+                .with_filter(TextureFilter::Trilinear),
+            TextureImage::from_image(&image, ColorSpace::Srgb).with_filter(TextureFilter::Ewa {
+                max_anisotropy: 16.0,
+            }),
+        ];
+
+        // One tile as long as the strip is wide.
+        let tiling = UvTransform::new((1.0, 60.0 / 1.8), 0.0, (0.0, 0.0));
+
+        let mut materials = Vec::new();
+        let mut shapes = Vec::new();
+        for (i, texture) in textures.into_iter().enumerate() {
+            let mat = Arc::new(Material::Diffuse(MaterialDiffuse::from_texture(
+                Texture::Image(texture.with_uv_transform(tiling)),
+            )));
+            shapes.push(Shape::Quad(Quad::new(
+                Vec3F::new(-4.0 + 2.0 * i as Fp, 0.0, 2.0),
+                Vec3F::new(1.8, 0.0, 0.0),
+                Vec3F::new(0.0, 0.0, -60.0),
+                Arc::clone(&mat),
+            )));
+            materials.push(mat);
         }
+
+        let mat_wall = Arc::new(Material::Diffuse(MaterialDiffuse::from_texture(
+            Texture::Checker(TextureChecker::new_uv(
+                Color3F::new(0.8, 0.8, 0.8),
+                Color3F::new(0.2, 0.3, 0.1),
+                UvTransform::new((10.0, 4.0), 30.0, (0.0, 0.0)),
+            )),
+        )));
+        shapes.push(Shape::Quad(Quad::new(
+            Vec3F::new(-30.0, 0.0, -58.0),
+            Vec3F::new(60.0, 0.0, 0.0),
+            Vec3F::new(0.0, 24.0, 0.0),
+            Arc::clone(&mat_wall),
+        )));
+        materials.push(mat_wall);
+
+        Ok(Self::new(materials, shapes, Vec::new(), true))
+    }
+
+    /// A row of spheres with the noise textures, from left to right: Perlin noise, fBm,
+    /// turbulence, marble and wood, on marble ground.
+    #[allow(dead_code)]
+    pub fn noise_textures() -> Scene {
+        let textures = [
+            TextureNoise::new(NoiseKind::Perlin, 1).with_scale(4.0),
+            TextureNoise::new(NoiseKind::Fbm, 2)
+                .with_scale(2.0)
+                .with_colors(Color3F::new(0.2, 0.4, 0.9), Color3F::new(1.0, 1.0, 1.0)),
+            TextureNoise::new(NoiseKind::Turbulence, 3).with_scale(2.0),
+            TextureNoise::new(NoiseKind::Marble, 4).with_scale(4.0),
+            TextureNoise::new(NoiseKind::Wood, 5)
+                .with_scale(6.0)
+                .with_octaves(3)
+                .with_colors(Color3F::new(0.3, 0.15, 0.05), Color3F::new(0.7, 0.45, 0.2)),
+        ];
+
+        let mut materials = Vec::new();
+        let mut shapes = Vec::new();
+        for (i, texture) in textures.into_iter().enumerate() {
+            let mat = Arc::new(Material::Diffuse(MaterialDiffuse::from_texture(
+                Texture::Noise(texture),
+            )));
+            shapes.push(Shape::Sphere(Sphere::new(
+                Vec3F::new(-4.4 + 2.2 * i as Fp, 1.0, 0.0),
+                1.0,
+                Arc::clone(&mat),
+            )));
+            materials.push(mat);
+        }
+
+        let mat_ground = Arc::new(Material::Diffuse(MaterialDiffuse::from_texture(
+            Texture::Noise(TextureNoise::new(NoiseKind::Marble, 6)),
+        )));
+        shapes.push(Shape::Sphere(Sphere::new(
+            Vec3F::new(0.0, -1000.0, 0.0),
+            1000.0,
+            Arc::clone(&mat_ground),
+        )));
+        materials.push(mat_ground);
+
+        Self::new(materials, shapes, Vec::new(), true)
+    }
+
+    /// Two rows of spheres with textures composed from the earth image. Top row: a checker and
+    /// the earth mixed by thresholded noise, the earth times turbulence, the earth plus red, and
+    /// the earth inverted. Bottom row: the red, green and blue channels of the earth, and its
+    /// luminance through a heat map.
+    #[allow(dead_code)]
+    pub fn texture_nodes() -> Result<Scene, ImageError> {
+        let image = Image::from_file(Path::new("images/earthmap.jpg"))?;
+        let earth = || Texture::Image(TextureImage::from_image(&image, ColorSpace::Srgb));
+        let threshold = ColorRamp::new(vec![
+            (0.45, Color3F::zero()),
+            (0.55, Color3F::new(1.0, 1.0, 1.0)),
+        ]);
+        let heat = ColorRamp::new(vec![
+            (0.0, Color3F::new(0.0, 0.0, 0.5)),
+            (0.2, Color3F::new(0.9, 0.1, 0.0)),
+            (0.5, Color3F::new(1.0, 0.9, 0.2)),
+        ]);
+        let textures = [
+            Texture::mix(
+                Texture::Checker(TextureChecker::new_uv(
+                    Color3F::new(0.9, 0.9, 0.9),
+                    Color3F::new(0.2, 0.3, 0.1),
+                    UvTransform::new((16.0, 8.0), 0.0, (0.0, 0.0)),
+                )),
+                earth(),
+                Texture::Noise(TextureNoise::new(NoiseKind::Fbm, 7).with_scale(2.0))
+                    .remapped(threshold),
+            ),
+            Texture::product(
+                earth(),
+                Texture::Noise(TextureNoise::new(NoiseKind::Turbulence, 8).with_scale(3.0))
+                    .remapped(ColorRamp::new(vec![
+                        (0.0, Color3F::new(0.5, 0.5, 0.5)),
+                        (0.5, Color3F::new(2.0, 2.0, 2.0)),
+                    ])),
+            ),
+            Texture::sum(
+                earth(),
+                Texture::Solid(TextureSolidColor::new(Color3F::new(0.3, 0.0, 0.0))),
+            ),
+            earth().inverted(),
+            earth().channel(ColorChannel::Red),
+            earth().channel(ColorChannel::Green),
+            earth().channel(ColorChannel::Blue),
+            earth().channel(ColorChannel::Luminance).remapped(heat),
+        ];
+
+        let mut materials = Vec::new();
+        let mut shapes = Vec::new();
+        for (i, texture) in textures.into_iter().enumerate() {
+            let mat = Arc::new(Material::Diffuse(MaterialDiffuse::from_texture(texture)));
+            let (row, column) = (i / 4, i % 4);
+            shapes.push(Shape::Sphere(Sphere::new(
+                Vec3F::new(-3.3 + 2.2 * column as Fp, 3.4 - 2.4 * row as Fp, 0.0),
+                1.0,
+                Arc::clone(&mat),
+            )));
+            materials.push(mat);
+        }
+
+        let mat_ground = Arc::new(Material::Diffuse(MaterialDiffuse::new_solid_color(
+            Color3F::new(0.5, 0.5, 0.5),
+        )));
+        shapes.push(Shape::Sphere(Sphere::new(
+            Vec3F::new(0.0, -1000.0, 0.0),
+            1000.0,
+            Arc::clone(&mat_ground),
+        )));
+        materials.push(mat_ground);
+
+        Ok(Self::new(materials, shapes, Vec::new(), true))
+    }
+
+    /// Spheres whose detail is only in their shading normals, from left to right: the earth
+    /// bumped by its own luminance, gray bumped by turbulence, and metal with a tangent space
+    /// normal map of tilted tiles. The ground is bumped by Perlin noise.
+    #[allow(dead_code)]
+    pub fn normal_maps() -> Result<Scene, ImageError> {
+        let image = Image::from_file(Path::new("images/earthmap.jpg"))?;
+        let earth = || Texture::Image(TextureImage::from_image(&image, ColorSpace::Srgb));
+        let gray = |c| Material::Diffuse(MaterialDiffuse::new_solid_color(Color3F::new(c, c, c)));
+        let materials = vec![
+            Arc::new(
+                Material::Diffuse(MaterialDiffuse::from_texture(earth())).with_normal_map(
+                    NormalMap::Bump {
+                        height: earth(),
+                        scale: 0.02,
+                    },
+                ),
+            ),
+            Arc::new(gray(0.7).with_normal_map(NormalMap::Bump {
+                height: Texture::Noise(TextureNoise::new(NoiseKind::Turbulence, 9).with_scale(3.0)),
+                scale: 0.1,
+            })),
+            Arc::new(
+                Material::Metal(MaterialMetal::new(Color3F::new(0.8, 0.6, 0.4), 0.05))
+                    .with_normal_map(NormalMap::Tangent {
+                        map: Texture::Checker(TextureChecker::new_uv(
+                            Color3F::new(0.3, 0.5, 0.9),
+                            Color3F::new(0.7, 0.5, 0.9),
+                            UvTransform::new((24.0, 12.0), 0.0, (0.0, 0.0)),
+                        )),
+                        strength: 1.0,
+                    }),
+            ),
+            Arc::new(gray(0.5).with_normal_map(NormalMap::Bump {
+                height: Texture::Noise(TextureNoise::new(NoiseKind::Perlin, 10)),
+                scale: 0.2,
+            })),
+        ];
+
+        let mut shapes: Vec<Shape> = (0..3)
+            .map(|i| {
+                Shape::Sphere(Sphere::new(
+                    Vec3F::new(-2.2 + 2.2 * i as Fp, 1.0, 0.0),
+                    1.0,
+                    Arc::clone(&materials[i]),
+                ))
+            })
+            .collect();
+        shapes.push(Shape::Quad(Quad::new(
+            Vec3F::new(-10.0, 0.0, -10.0),
+            Vec3F::new(0.0, 0.0, 20.0),
+            Vec3F::new(20.0, 0.0, 0.0),
+            Arc::clone(&materials[3]),
+        )));
+
+        Ok(Self::new(materials, shapes, Vec::new(), true))
+    }
+
+    /// Surfaces with holes cut by their opacity: a fence of slats, a sphere eaten away by noise,
+    /// and a round decal whose edge fades out stochastically, from the alpha of an RGBA image.
+    #[allow(dead_code)]
+    pub fn cutouts() -> Scene {
+        let size = 64;
+        let mut decal = Image::new_rgba(size, size);
+        for row in 0..size {
+            for col in 0..size {
+                let (x, y) = (
+                    (col as Fp + 0.5) / size as Fp - 0.5,
+                    (row as Fp + 0.5) / size as Fp - 0.5,
+                );
+                let r = 2.0 * Fp::hypot(x, y);
+                let alpha = ((1.0 - r) * 4.0).clamp(0.0, 1.0);
+                let color = Color3U8::new(255, (255.0 * (1.0 - r).max(0.0)) as u8, 64);
+                decal.write_pixel_rgba(row, col, color, (255.0 * alpha) as u8);
+            }
+        }
+
+        let wood = Color3F::new(0.6, 0.4, 0.2);
+        let materials = vec![
+            Arc::new(
+                Material::Diffuse(MaterialDiffuse::new_solid_color(wood)).with_opacity(
+                    Texture::Checker(TextureChecker::new_uv(
+                        Color3F::new(1.0, 1.0, 1.0),
+                        Color3F::zero(),
+                        UvTransform::new((28.0, 1.0), 0.0, (0.0, 0.0)),
+                    )),
+                    AlphaMode::Cutout { threshold: 0.5 },
+                ),
+            ),
+            Arc::new(
+                Material::Diffuse(MaterialDiffuse::new_solid_color(Color3F::new(
+                    0.2, 0.6, 0.2,
+                )))
+                .with_opacity(
+                    Texture::Noise(TextureNoise::new(NoiseKind::Fbm, 11).with_scale(3.0)),
+                    AlphaMode::Cutout { threshold: 0.5 },
+                ),
+            ),
+            Arc::new(
+                Material::Diffuse(MaterialDiffuse::from_texture(Texture::Image(
+                    TextureImage::from_image(&decal, ColorSpace::Srgb),
+                )))
+                .with_opacity(
+                    Texture::Image(TextureImage::from_alpha(&decal)),
+                    AlphaMode::Stochastic,
+                ),
+            ),
+            Arc::new(Material::Diffuse(MaterialDiffuse::new_solid_color(
+                Color3F::new(0.5, 0.5, 0.5),
+            ))),
+        ];
+
+        let shapes = vec![
+            Shape::Quad(Quad::new(
+                Vec3F::new(-5.0, 0.0, -1.5),
+                Vec3F::new(10.0, 0.0, 0.0),
+                Vec3F::new(0.0, 1.5, 0.0),
+                Arc::clone(&materials[0]),
+            )),
+            Shape::Sphere(Sphere::new(
+                Vec3F::new(-1.0, 1.0, 0.5),
+                1.0,
+                Arc::clone(&materials[1]),
+            )),
+            Shape::Quad(Quad::new(
+                Vec3F::new(0.6, 0.3, 1.0),
+                Vec3F::new(1.5, 0.0, 0.0),
+                Vec3F::new(0.0, 1.5, 0.0),
+                Arc::clone(&materials[2]),
+            )),
+            Shape::Quad(Quad::new(
+                Vec3F::new(-10.0, 0.0, -10.0),
+                Vec3F::new(0.0, 0.0, 20.0),
+                Vec3F::new(20.0, 0.0, 0.0),
+                Arc::clone(&materials[3]),
+            )),
+        ];
+
+        Self::new(materials, shapes, Vec::new(), true)
+    }
+
+    /// Textures placed by projections instead of texture coordinates. A rotated box with the
+    /// checker test image projected on its three axes, the earth projected on a sphere, around
+    /// its center then around its vertical axis, and a floor tiled by a projection from above.
+    #[allow(dead_code)]
+    pub fn projections() -> Result<Scene, ImageError> {
+        let checker_test = Image::from_file(Path::new("images/checker-test.ppm"))?;
+        let earth = Image::from_file(Path::new("images/earthmap.jpg"))?;
+        let image_texture = |image: &Image, uv_transform| {
+            Material::Diffuse(MaterialDiffuse::from_texture(Texture::Image(
+                TextureImage::from_image(image, ColorSpace::Srgb).with_uv_transform(uv_transform),
+            )))
+        };
+        let left = Vec3F::new(-2.0, 1.0, 0.0);
+        let right = Vec3F::new(2.0, 1.0, 0.0);
+
+        let materials = vec![
+            Arc::new(
+                image_texture(&checker_test, UvTransform::default())
+                    .with_projection(Projection::Triplanar { sharpness: 4.0 }),
+            ),
+            Arc::new(
+                image_texture(&earth, UvTransform::default())
+                    .with_projection(Projection::Spherical { center: left }),
+            ),
+            // The earth spans the height of the sphere.
+            Arc::new(
+                image_texture(&earth, UvTransform::new((1.0, 0.5), 0.0, (0.0, 0.0)))
+                    .with_projection(Projection::Cylindrical {
+                        center: right - Vec3F::new(0.0, 1.0, 0.0),
+                    }),
+            ),
+            Arc::new(
+                image_texture(&checker_test, UvTransform::new((0.5, 0.5), 0.0, (0.0, 0.0)))
+                    .with_projection(Projection::Planar { axis: 1 }),
+            ),
+        ];
+
+        let shapes = vec![
+            create_box_quads(
+                Vec3F::new(-0.6, 0.0, -0.6),
+                Vec3F::new(0.6, 1.2, 0.6),
+                Arc::clone(&materials[0]),
+                Vec3F::new(0.0, 0.0, 0.0),
+                30.0,
+            ),
+            Shape::Sphere(Sphere::new(left, 1.0, Arc::clone(&materials[1]))),
+            Shape::Sphere(Sphere::new(right, 1.0, Arc::clone(&materials[2]))),
+            Shape::Quad(Quad::new(
+                Vec3F::new(-10.0, 0.0, -10.0),
+                Vec3F::new(0.0, 0.0, 20.0),
+                Vec3F::new(20.0, 0.0, 0.0),
+                Arc::clone(&materials[3]),
+            )),
+        ];
+
+        Ok(Self::new(materials, shapes, Vec::new(), true))
     }
 
     #[allow(dead_code)]
@@ -301,13 +619,7 @@ impl Scene {
             )),
         ];
 
-        Self {
-            materials,
-            shapes,
-            lights: Vec::new(),
-            // bvh: build_bvh(&shapes, 0),
-            is_background_sky: true,
-        }
+        Self::new(materials, shapes, Vec::new(), true)
     }
 
     #[allow(dead_code)]
@@ -357,13 +669,7 @@ impl Scene {
             )),
         ];
 
-        Self {
-            materials,
-            shapes,
-            lights: Vec::new(),
-            // bvh: build_bvh(&shapes, 0),
-            is_background_sky: true,
-        }
+        Self::new(materials, shapes, Vec::new(), true)
     }
 
     #[allow(dead_code)]
@@ -421,17 +727,13 @@ impl Scene {
             )),
         ];
 
-        Self {
-            materials,
-            shapes,
-            lights: Vec::new(),
-            // bvh: build_bvh(&shapes, 0),
-            is_background_sky: true,
-        }
+        Self::new(materials, shapes, Vec::new(), true)
     }
 
-    #[allow(dead_code)]
-    pub fn many_spheres() -> Scene {
+    // The ground, three big spheres and a grid of small random ones around them, along with
+    // their materials. If `moving`, the small diffuse spheres bounce upward while the shutter is
+    // open.
+    fn random_spheres(moving: bool) -> (Vec<Arc<Material>>, Vec<Shape>) {
         let mat_diffuse0 = Arc::new(Material::Diffuse(MaterialDiffuse::new_solid_color(
             Color3F::new(0.5, 0.5, 0.5),
         )));
@@ -497,7 +799,13 @@ impl Scene {
 
                         materials.push(Arc::clone(&mat));
 
-                        shapes.push(Shape::Sphere(Sphere::new(pos, 0.2, Arc::clone(&mat))));
+                        let sphere = if moving {
+                            let pos_end = pos + Vec3F::new(0.0, rand.gen_range(0.0..0.5), 0.0);
+                            Sphere::new_moving(pos, pos_end, 0.2, Arc::clone(&mat))
+                        } else {
+                            Sphere::new(pos, 0.2, Arc::clone(&mat))
+                        };
+                        shapes.push(Shape::Sphere(sphere));
                     } else if choose_material < 0.9 {
                         let albedo = Color3F::new(
                             rand.gen_range(0.5..1.0),
@@ -521,13 +829,35 @@ impl Scene {
             }
         }
 
-        Self {
-            materials,
-            shapes,
-            lights: Vec::new(),
-            // bvh: build_bvh(&shapes, 0),
-            is_background_sky: true,
-        }
+        (materials, shapes)
+    }
+
+    #[allow(dead_code)]
+    pub fn many_spheres() -> Scene {
+        let (materials, shapes) = Self::random_spheres(false);
+        Self::new(materials, shapes, Vec::new(), true)
+    }
+
+    #[allow(dead_code)]
+    pub fn bouncing_spheres() -> Scene {
+        let (materials, mut shapes) = Self::random_spheres(true);
+
+        // A panel sliding sideways behind the big spheres.
+        shapes.push(Shape::Instance(
+            Instance::from_shape(
+                Shape::Quad(Quad::new(
+                    Vec3F::zero(),
+                    Vec3F::new(0.0, 0.0, 4.0),
+                    Vec3F::new(0.0, 2.5, 0.0),
+                    // The brown of the big diffuse sphere.
+                    Arc::clone(&materials[1]),
+                )),
+                Mat4F::translate(Vec3F::new(-7.0, 0.0, -2.0)),
+            )
+            .with_motion(Vec3F::new(0.0, 0.0, 1.0)),
+        ));
+
+        Self::new(materials, shapes, Vec::new(), true)
     }
 
     #[allow(dead_code)]
@@ -562,8 +892,6 @@ impl Scene {
                 Vec3F::new(0.0, 0.0, -4.0),
                 Vec3F::new(0.0, 4.0, 0.0),
                 Arc::clone(&mat_diffuse0),
-                Vec3F::new(0.0, 0.0, 0.0),
-                0.0,
             )),
             // back green
             Shape::Quad(Quad::new(
@@ -571,8 +899,6 @@ impl Scene {
                 Vec3F::new(4.0, 0.0, 0.0),
                 Vec3F::new(0.0, 4.0, 0.0),
                 Arc::clone(&mat_diffuse1),
-                Vec3F::new(0.0, 0.0, 0.0),
-                0.0,
             )),
             // right blue
             Shape::Quad(Quad::new(
@@ -580,8 +906,6 @@ impl Scene {
                 Vec3F::new(0.0, 0.0, 4.0),
                 Vec3F::new(0.0, 4.0, 0.0),
                 Arc::clone(&mat_diffuse2),
-                Vec3F::new(0.0, 0.0, 0.0),
-                0.0,
             )),
             // upper orange
             Shape::Quad(Quad::new(
@@ -589,8 +913,6 @@ impl Scene {
                 Vec3F::new(4.0, 0.0, 0.0),
                 Vec3F::new(0.0, 0.0, 4.0),
                 Arc::clone(&mat_diffuse3),
-                Vec3F::new(0.0, 0.0, 0.0),
-                0.0,
             )),
             // lower teal
             Shape::Quad(Quad::new(
@@ -598,18 +920,342 @@ impl Scene {
                 Vec3F::new(4.0, 0.0, 0.0),
                 Vec3F::new(0.0, 0.0, -4.0),
                 Arc::clone(&mat_diffuse4),
+            )),
+        ];
+
+        Self::new(materials, shapes, Vec::new(), true)
+    }
+
+    #[allow(dead_code)]
+    pub fn instances_example() -> Scene {
+        let mat_ground = Arc::new(Material::Diffuse(MaterialDiffuse::new_checker(
+            Color3F::new(0.2, 0.3, 0.1),
+            Color3F::new(0.9, 0.9, 0.9),
+            0.5,
+        )));
+        let mat_red = Arc::new(Material::Diffuse(MaterialDiffuse::new_solid_color(
+            Color3F::new(0.7, 0.2, 0.2),
+        )));
+        let mat_metal = Arc::new(Material::Metal(MaterialMetal::new(
+            Color3F::new(0.8, 0.8, 0.8),
+            0.1,
+        )));
+        let materials = vec![
+            Arc::clone(&mat_ground),
+            Arc::clone(&mat_red),
+            Arc::clone(&mat_metal),
+        ];
+
+        // A small "molecule" whose geometry is shared by all of its copies.
+        let molecule = Arc::new(ShapeGroup::new(vec![
+            Shape::Sphere(Sphere::new(Vec3F::zero(), 0.5, Arc::clone(&mat_red))),
+            Shape::Sphere(Sphere::new(
+                Vec3F::new(0.6, 0.3, 0.0),
+                0.25,
+                Arc::clone(&mat_metal),
+            )),
+            Shape::Sphere(Sphere::new(
+                Vec3F::new(-0.6, 0.3, 0.0),
+                0.25,
+                Arc::clone(&mat_metal),
+            )),
+        ]));
+
+        let mut shapes = vec![Shape::Quad(Quad::new(
+            Vec3F::new(-10.0, -0.5, 10.0),
+            Vec3F::new(20.0, 0.0, 0.0),
+            Vec3F::new(0.0, 0.0, -20.0),
+            Arc::clone(&mat_ground),
+        ))];
+
+        for i in 0..5 {
+            let matrix = Mat4F::translate(Vec3F::new(-3.0 + 1.5 * i as Fp, 0.0, 0.0))
+                * Mat4F::rotate(Vec3F::new(0.0, 0.0, 1.0), 20.0 * i as Fp)
+                * Mat4F::scale(Vec3F::new(1.0, 1.0 + 0.2 * i as Fp, 1.0));
+            shapes.push(Shape::Instance(Instance::new(
+                Arc::clone(&molecule),
+                matrix,
+            )));
+        }
+
+        // An ellipsoid made by non-uniformly scaling a sphere.
+        shapes.push(Shape::Instance(Instance::from_shape(
+            Shape::Sphere(Sphere::new(Vec3F::zero(), 1.0, Arc::clone(&mat_metal))),
+            Mat4F::translate(Vec3F::new(0.0, 1.5, -3.0)) * Mat4F::scale(Vec3F::new(3.0, 0.6, 1.0)),
+        )));
+
+        Self::new(materials, shapes, Vec::new(), true)
+    }
+
+    #[allow(dead_code)]
+    pub fn table_and_lamp() -> Scene {
+        let mat_floor = Arc::new(Material::Diffuse(MaterialDiffuse::new_checker(
+            Color3F::new(0.3, 0.3, 0.3),
+            Color3F::new(0.8, 0.8, 0.8),
+            0.5,
+        )));
+        let mat_wood = Arc::new(Material::Diffuse(MaterialDiffuse::new_solid_color(
+            Color3F::new(0.45, 0.3, 0.15),
+        )));
+        let mat_shade = Arc::new(Material::Diffuse(MaterialDiffuse::new_solid_color(
+            Color3F::new(0.8, 0.7, 0.4),
+        )));
+        let mat_metal = Arc::new(Material::Metal(MaterialMetal::new(
+            Color3F::new(0.8, 0.6, 0.2),
+            0.2,
+        )));
+        let mat_light = Arc::new(Material::DiffuseLight(MaterialDiffuseLight::new(
+            Color3F::new(8.0, 8.0, 6.0),
+        )));
+        let materials = vec![
+            Arc::clone(&mat_floor),
+            Arc::clone(&mat_wood),
+            Arc::clone(&mat_shade),
+            Arc::clone(&mat_metal),
+            Arc::clone(&mat_light),
+        ];
+
+        let lamp_light = Shape::Disk(Disk::new(
+            Vec3F::new(0.6, 1.6, 0.0),
+            Vec3F::new(0.0, -1.0, 0.0),
+            0.15,
+            Arc::clone(&mat_light),
+        ));
+
+        let shapes = vec![
+            // floor
+            Shape::Quad(Quad::new(
+                Vec3F::new(-10.0, 0.0, 10.0),
+                Vec3F::new(20.0, 0.0, 0.0),
+                Vec3F::new(0.0, 0.0, -20.0),
+                Arc::clone(&mat_floor),
+            )),
+            // table top
+            Shape::Cylinder(Cylinder::new(
+                Vec3F::new(0.0, 0.9, 0.0),
+                1.2,
+                0.08,
+                true,
+                Arc::clone(&mat_wood),
+            )),
+            // table leg and foot
+            Shape::Cylinder(Cylinder::new(
                 Vec3F::new(0.0, 0.0, 0.0),
-                0.0,
+                0.08,
+                0.9,
+                false,
+                Arc::clone(&mat_wood),
+            )),
+            Shape::Disk(Disk::new(
+                Vec3F::new(0.0, 0.01, 0.0),
+                Vec3F::new(0.0, 1.0, 0.0),
+                0.5,
+                Arc::clone(&mat_wood),
+            )),
+            // lamp stand and shade
+            Shape::Cylinder(Cylinder::new(
+                Vec3F::new(0.6, 0.98, 0.0),
+                0.03,
+                0.62,
+                true,
+                Arc::clone(&mat_metal),
+            )),
+            Shape::Cone(Cone::new(
+                Vec3F::new(0.6, 1.35, 0.0),
+                0.35,
+                0.6,
+                false,
+                Arc::clone(&mat_shade),
+            )),
+            lamp_light,
+            // a ring lying on the table, and another one standing up
+            Shape::Torus(Torus::new(
+                Vec3F::new(-0.5, 1.03, 0.3),
+                0.2,
+                0.05,
+                Arc::clone(&mat_metal),
+            )),
+            Shape::Instance(Instance::from_shape(
+                Shape::Torus(Torus::new(Vec3F::zero(), 0.2, 0.05, Arc::clone(&mat_metal))),
+                Mat4F::translate(Vec3F::new(-0.3, 1.23, -0.4))
+                    * Mat4F::rotate(Vec3F::new(1.0, 0.0, 0.0), 90.0),
             )),
         ];
 
-        Self {
-            materials,
-            shapes,
-            lights: Vec::new(),
-            // bvh: build_bvh(&shapes, 0),
-            is_background_sky: true,
+        Self::new(materials, shapes, Vec::new(), true)
+    }
+
+    #[allow(dead_code)]
+    pub fn csg_example() -> Scene {
+        let mat_ground = Arc::new(Material::Diffuse(MaterialDiffuse::new_checker(
+            Color3F::new(0.2, 0.3, 0.1),
+            Color3F::new(0.9, 0.9, 0.9),
+            0.5,
+        )));
+        let mat_glass = Arc::new(Material::Dielectric(MaterialDielectric::new(1.5)));
+        let mat_red = Arc::new(Material::Diffuse(MaterialDiffuse::new_solid_color(
+            Color3F::new(0.7, 0.2, 0.2),
+        )));
+        let mat_blue = Arc::new(Material::Diffuse(MaterialDiffuse::new_solid_color(
+            Color3F::new(0.2, 0.3, 0.7),
+        )));
+        let mat_metal = Arc::new(Material::Metal(MaterialMetal::new(
+            Color3F::new(0.8, 0.8, 0.8),
+            0.05,
+        )));
+        let materials = vec![
+            Arc::clone(&mat_ground),
+            Arc::clone(&mat_glass),
+            Arc::clone(&mat_red),
+            Arc::clone(&mat_blue),
+            Arc::clone(&mat_metal),
+        ];
+
+        // A biconvex lens is the intersection of two large spheres.
+        let lens = Csg::new(
+            CsgOperation::Intersection,
+            Shape::Sphere(Sphere::new(
+                Vec3F::new(-1.8, 1.0, -1.6),
+                2.0,
+                Arc::clone(&mat_glass),
+            )),
+            Shape::Sphere(Sphere::new(
+                Vec3F::new(-1.8, 1.0, 1.6),
+                2.0,
+                Arc::clone(&mat_glass),
+            )),
+        );
+
+        // A sphere drilled through by a cylinder along the Z-axis.
+        let drill = Instance::from_shape(
+            Shape::Cylinder(Cylinder::new(
+                Vec3F::new(0.0, -1.0, 0.0),
+                0.3,
+                2.0,
+                true,
+                Arc::clone(&mat_blue),
+            )),
+            Mat4F::translate(Vec3F::new(0.5, 0.6, 0.0))
+                * Mat4F::rotate(Vec3F::new(1.0, 0.0, 0.0), 90.0),
+        );
+        let drilled_sphere = Csg::new(
+            CsgOperation::Difference,
+            Shape::Sphere(Sphere::new(
+                Vec3F::new(0.5, 0.6, 0.0),
+                0.6,
+                Arc::clone(&mat_red),
+            )),
+            Shape::Instance(drill),
+        );
+
+        // A box hollowed by a sphere that's slightly larger than the box.
+        let hollow_box = Csg::new(
+            CsgOperation::Difference,
+            create_box_quads(
+                Vec3F::new(-0.5, 0.0, -0.5),
+                Vec3F::new(0.5, 1.0, 0.5),
+                Arc::clone(&mat_metal),
+                Vec3F::new(2.2, 0.0, 0.0),
+                30.0,
+            ),
+            Shape::Sphere(Sphere::new(
+                Vec3F::new(2.2, 0.5, 0.0),
+                0.65,
+                Arc::clone(&mat_blue),
+            )),
+        );
+
+        // Two overlapping glass spheres merged into one solid, without the inner surfaces that
+        // would refract the rays twice.
+        let peanut = Csg::new(
+            CsgOperation::Union,
+            Shape::Sphere(Sphere::new(
+                Vec3F::new(0.6, 0.5, -2.5),
+                0.5,
+                Arc::clone(&mat_glass),
+            )),
+            Shape::Sphere(Sphere::new(
+                Vec3F::new(1.2, 0.5, -2.5),
+                0.5,
+                Arc::clone(&mat_glass),
+            )),
+        );
+
+        let shapes = vec![
+            Shape::Quad(Quad::new(
+                Vec3F::new(-10.0, 0.0, 10.0),
+                Vec3F::new(20.0, 0.0, 0.0),
+                Vec3F::new(0.0, 0.0, -20.0),
+                Arc::clone(&mat_ground),
+            )),
+            Shape::Csg(lens),
+            Shape::Csg(peanut),
+            Shape::Csg(drilled_sphere),
+            Shape::Csg(hollow_box),
+        ];
+
+        Self::new(materials, shapes, Vec::new(), true)
+    }
+
+    /// A floor scattered with a few hundred small lights of different colors, like a city seen
+    /// from above, to exercise the light BVH.
+    #[allow(dead_code)]
+    pub fn many_lights() -> Scene {
+        let mat_floor = Arc::new(Material::Diffuse(MaterialDiffuse::new_solid_color(
+            Color3F::new(0.5, 0.5, 0.5),
+        )));
+        let mat_diffuse = Arc::new(Material::Diffuse(MaterialDiffuse::new_solid_color(
+            Color3F::new(0.7, 0.7, 0.7),
+        )));
+        let mut materials = vec![Arc::clone(&mat_floor), Arc::clone(&mat_diffuse)];
+
+        let mut shapes = vec![
+            Shape::Quad(Quad::new(
+                Vec3F::new(-20.0, 0.0, 20.0),
+                Vec3F::new(40.0, 0.0, 0.0),
+                Vec3F::new(0.0, 0.0, -40.0),
+                Arc::clone(&mat_floor),
+            )),
+            Shape::Sphere(Sphere::new(
+                Vec3F::new(-2.0, 1.0, 0.0),
+                1.0,
+                Arc::clone(&mat_diffuse),
+            )),
+            Shape::Sphere(Sphere::new(
+                Vec3F::new(2.0, 1.0, 0.0),
+                1.0,
+                Arc::clone(&mat_diffuse),
+            )),
+        ];
+        let mut rand = SmallRng::seed_from_u64(877);
+
+        for x in -10..10 {
+            for z in -10..10 {
+                let pos = Vec3F::new(
+                    x as Fp + 0.9 * rand.gen_range(0.0..1.0),
+                    0.1,
+                    z as Fp + 0.9 * rand.gen_range(0.0..1.0),
+                );
+                if Fp::abs(pos.x) < 3.2 && Fp::abs(pos.z) < 1.2 {
+                    continue;
+                }
+
+                let intensity = rand.gen_range(2.0..20.0);
+                let color = if rand.gen_range(0.0..1.0) < 0.7 {
+                    Color3F::new(1.0, 0.7, 0.4)
+                } else {
+                    Color3F::new(0.5, 0.7, 1.0)
+                };
+                let mat = Arc::new(Material::DiffuseLight(MaterialDiffuseLight::new(
+                    intensity * color,
+                )));
+                materials.push(Arc::clone(&mat));
+
+                shapes.push(Shape::Sphere(Sphere::new(pos, 0.1, Arc::clone(&mat))));
+            }
         }
+
+        Self::new(materials, shapes, Vec::new(), false)
     }
 
     #[allow(dead_code)]
@@ -641,48 +1287,36 @@ impl Scene {
                 Vec3F::new(0.0, 0.0, 555.0),
                 Vec3F::new(0.0, 555.0, 0.0),
                 Arc::clone(&mat_green),
-                Vec3F::new(0.0, 0.0, 0.0),
-                0.0,
             )),
             Shape::Quad(Quad::new(
                 Vec3F::new(0.0, 0.0, 0.0),
                 Vec3F::new(0.0, 555.0, 0.0),
                 Vec3F::new(0.0, 0.0, 555.0),
                 Arc::clone(&mat_red),
-                Vec3F::new(0.0, 0.0, 0.0),
-                0.0,
             )),
             Shape::Quad(Quad::new(
                 Vec3F::new(343.0, 554.0, 332.0),
                 Vec3F::new(-130.0, 0.0, 0.0),
                 Vec3F::new(0.0, 0.0, -105.0),
                 Arc::clone(&mat_light),
-                Vec3F::new(0.0, 0.0, 0.0),
-                0.0,
             )),
             Shape::Quad(Quad::new(
                 Vec3F::new(0.0, 0.0, 0.0),
                 Vec3F::new(0.0, 0.0, 555.0),
                 Vec3F::new(555.0, 0.0, 0.0),
                 Arc::clone(&mat_white),
-                Vec3F::new(0.0, 0.0, 0.0),
-                0.0,
             )),
             Shape::Quad(Quad::new(
                 Vec3F::new(555.0, 555.0, 555.0),
                 Vec3F::new(-555.0, 0.0, 0.0),
                 Vec3F::new(0.0, 0.0, -555.0),
                 Arc::clone(&mat_white),
-                Vec3F::new(0.0, 0.0, 0.0),
-                0.0,
             )),
             Shape::Quad(Quad::new(
                 Vec3F::new(0.0, 0.0, 555.0),
                 Vec3F::new(0.0, 555.0, 0.0),
                 Vec3F::new(555.0, 0.0, 0.0),
                 Arc::clone(&mat_white),
-                Vec3F::new(0.0, 0.0, 0.0),
-                0.0,
             )),
             Shape::Sphere(Sphere::new(
                 Vec3F::new(190.0, 90.0, 190.0),
@@ -697,7 +1331,7 @@ impl Scene {
             Vec3F::new(265.0, 0.0, 295.0),
             15.0,
         );
-        shapes.extend_from_slice(&box0);
+        shapes.push(box0);
 
         /*
         let box1 = create_box_quads(
@@ -707,34 +1341,96 @@ impl Scene {
             Vec3F::new(130.0, 0.0, 65.0),
             -18.0,
         );
-        shapes.extend_from_slice(&box1);
+        shapes.push(box1);
         */
 
-        // These light shapes are used for generating random direction towards light sources. The
-        // materials attached are ignored.
-        let lights = vec![
-            Shape::Quad(Quad::new(
-                Vec3F::new(343.0, 554.0, 332.0),
-                Vec3F::new(-130.0, 0.0, 0.0),
-                Vec3F::new(0.0, 0.0, -105.0),
-                Arc::clone(&mat_light),
-                Vec3F::new(0.0, 0.0, 0.0),
-                0.0,
-            )),
-            Shape::Sphere(Sphere::new(
-                Vec3F::new(190.0, 90.0, 190.0),
-                90.0,
-                Arc::clone(&mat_light),
-            )),
-        ];
+        // The glass sphere focuses the light onto the floor. Send more rays towards it so that the
+        // caustic converges faster.
+        let attractors = vec![Shape::Sphere(Sphere::new(
+            Vec3F::new(190.0, 90.0, 190.0),
+            90.0,
+            Arc::clone(&mat_dielectric),
+        ))];
 
-        Self {
-            materials,
-            shapes,
-            lights,
-            // bvh: build_bvh(&shapes, 0),
-            is_background_sky: false,
-        }
+        Self::new(materials, shapes, attractors, false)
+    }
+
+    #[allow(dead_code)]
+    pub fn texture_filters_camera(image_width: u32, image_height: u32) -> Camera {
+        Camera::builder()
+            .pixel_dimension(image_width, image_height)
+            .fov(40.0 / 180.0)
+            .focus_length(10.0)
+            .defocus_angle(0.0)
+            .position(Vec3F::new(0.0, 1.0, 4.0))
+            .lookat(Vec3F::new(0.0, 0.0, -10.0))
+            .up(Vec3F::new(0.0, 1.0, 0.0))
+            .build()
+    }
+
+    #[allow(dead_code)]
+    pub fn noise_textures_camera(image_width: u32, image_height: u32) -> Camera {
+        Camera::builder()
+            .pixel_dimension(image_width, image_height)
+            .fov(30.0 / 180.0)
+            .focus_length(10.0)
+            .defocus_angle(0.0)
+            .position(Vec3F::new(0.0, 3.0, 14.0))
+            .lookat(Vec3F::new(0.0, 1.0, 0.0))
+            .up(Vec3F::new(0.0, 1.0, 0.0))
+            .build()
+    }
+
+    #[allow(dead_code)]
+    pub fn texture_nodes_camera(image_width: u32, image_height: u32) -> Camera {
+        Camera::builder()
+            .pixel_dimension(image_width, image_height)
+            .fov(30.0 / 180.0)
+            .focus_length(10.0)
+            .defocus_angle(0.0)
+            .position(Vec3F::new(0.0, 2.2, 16.0))
+            .lookat(Vec3F::new(0.0, 2.2, 0.0))
+            .up(Vec3F::new(0.0, 1.0, 0.0))
+            .build()
+    }
+
+    #[allow(dead_code)]
+    pub fn normal_maps_camera(image_width: u32, image_height: u32) -> Camera {
+        Camera::builder()
+            .pixel_dimension(image_width, image_height)
+            .fov(30.0 / 180.0)
+            .focus_length(10.0)
+            .defocus_angle(0.0)
+            .position(Vec3F::new(0.0, 2.5, 10.0))
+            .lookat(Vec3F::new(0.0, 1.0, 0.0))
+            .up(Vec3F::new(0.0, 1.0, 0.0))
+            .build()
+    }
+
+    #[allow(dead_code)]
+    pub fn cutouts_camera(image_width: u32, image_height: u32) -> Camera {
+        Camera::builder()
+            .pixel_dimension(image_width, image_height)
+            .fov(35.0 / 180.0)
+            .focus_length(10.0)
+            .defocus_angle(0.0)
+            .position(Vec3F::new(0.0, 2.0, 8.0))
+            .lookat(Vec3F::new(0.0, 0.8, 0.0))
+            .up(Vec3F::new(0.0, 1.0, 0.0))
+            .build()
+    }
+
+    #[allow(dead_code)]
+    pub fn projections_camera(image_width: u32, image_height: u32) -> Camera {
+        Camera::builder()
+            .pixel_dimension(image_width, image_height)
+            .fov(35.0 / 180.0)
+            .focus_length(10.0)
+            .defocus_angle(0.0)
+            .position(Vec3F::new(0.0, 3.0, 9.0))
+            .lookat(Vec3F::new(0.0, 0.8, 0.0))
+            .up(Vec3F::new(0.0, 1.0, 0.0))
+            .build()
     }
 
     #[allow(dead_code)]
@@ -751,37 +1447,89 @@ impl Scene {
     }
 
     #[allow(dead_code)]
-    pub fn cornell_box_camera(image_width: u32, image_height: u32) -> Camera {
+    pub fn bouncing_spheres_camera(image_width: u32, image_height: u32) -> Camera {
+        Camera::builder()
+            .pixel_dimension(image_width, image_height)
+            .fov(20.0 / 180.0)
+            .focus_length(10.0)
+            .defocus_angle(0.6 / 180.0)
+            .position(Vec3F::new(13.0, 2.0, 3.0))
+            .lookat(Vec3F::zero())
+            .up(Vec3F::new(0.0, 1.0, 0.0))
+            .shutter(0.0, 1.0)
+            .build()
+    }
+
+    #[allow(dead_code)]
+    pub fn instances_example_camera(image_width: u32, image_height: u32) -> Camera {
         Camera::builder()
             .pixel_dimension(image_width, image_height)
             .fov(40.0 / 180.0)
             .focus_length(10.0)
             .defocus_angle(0.0)
-            .position(Vec3F::new(278.0, 278.0, -800.0))
-            .lookat(Vec3F::new(278.0, 278.0, 0.0))
+            .position(Vec3F::new(0.0, 2.0, 8.0))
+            .lookat(Vec3F::new(0.0, 0.3, 0.0))
             .up(Vec3F::new(0.0, 1.0, 0.0))
             .build()
     }
 
-    fn pdf_mixure_sample<R: rand::Rng>(&self, origin: &Vec3F, rand: &mut R) -> (Vec3F, Fp) {
-        if self.lights.len() > 0 {
-            let light_index: usize = rand.gen_range(0..self.lights.len());
-            let random_dir = self.lights[light_index].gen_random_dir(origin, rand);
+    #[allow(dead_code)]
+    pub fn table_and_lamp_camera(image_width: u32, image_height: u32) -> Camera {
+        Camera::builder()
+            .pixel_dimension(image_width, image_height)
+            .fov(35.0 / 180.0)
+            .focus_length(10.0)
+            .defocus_angle(0.0)
+            .position(Vec3F::new(0.5, 2.5, 4.5))
+            .lookat(Vec3F::new(0.0, 1.0, 0.0))
+            .up(Vec3F::new(0.0, 1.0, 0.0))
+            .build()
+    }
 
-            let weight = 1.0 / (self.lights.len() as Fp);
-            let mut sum = 0.0;
-            for shape in &self.lights {
-                let ray = Ray::new(origin.clone(), random_dir);
-                sum += weight * shape.pdf_value(&ray);
-            }
+    #[allow(dead_code)]
+    pub fn csg_example_camera(image_width: u32, image_height: u32) -> Camera {
+        Camera::builder()
+            .pixel_dimension(image_width, image_height)
+            .fov(35.0 / 180.0)
+            .focus_length(10.0)
+            .defocus_angle(0.0)
+            .position(Vec3F::new(0.5, 2.5, 6.0))
+            .lookat(Vec3F::new(0.5, 0.6, 0.0))
+            .up(Vec3F::new(0.0, 1.0, 0.0))
+            .build()
+    }
 
-            (random_dir, sum)
-        } else {
-            (Vec3F::zero(), 0.0)
-        }
+    #[allow(dead_code)]
+    pub fn many_lights_camera(image_width: u32, image_height: u32) -> Camera {
+        Camera::builder()
+            .pixel_dimension(image_width, image_height)
+            .fov(45.0 / 180.0)
+            .focus_length(10.0)
+            .defocus_angle(0.0)
+            .position(Vec3F::new(0.0, 6.0, 10.0))
+            .lookat(Vec3F::new(0.0, 0.5, 0.0))
+            .up(Vec3F::new(0.0, 1.0, 0.0))
+            .build()
+    }
+
+    #[allow(dead_code)]
+    pub fn cornell_box_camera(image_width: u32, image_height: u32) -> Camera {
+        Camera::builder()
+            .pixel_dimension(image_width, image_height)
+            .fov(40.0 / 180.0)
+            .focus_length(10.0)
+            .defocus_angle(0.0)
+            .position(Vec3F::new(278.0, 278.0, -800.0))
+            .lookat(Vec3F::new(278.0, 278.0, 0.0))
+            .up(Vec3F::new(0.0, 1.0, 0.0))
+            .build()
     }
 
-    fn scatter<R: rand::Rng>(incident_ray: &Ray, intersection: &RayIntersection, material: &Material, rand: &mut R) -> Option<ScatterResult> {
+    /// Scatter `incident_ray` off `material` at `intersection`. The scattered ray carries on the
+    /// cone of the incident one, see `Ray::with_cone`.
+    pub fn scatter<R: rand::Rng>(incident_ray: &Ray, intersection: &RayIntersection, material: &Material, rand: &mut R) -> Option<ScatterResult> {
+        let cone_width = incident_ray.cone_width_at(intersection.t);
+        let cone_spread = incident_ray.cone_spread;
         match material {
             Material::Diffuse(mat) => {
                 let pdf = PdfCosineHemisphere::new();
@@ -789,17 +1537,35 @@ impl Scene {
 
                 // The sample direction generated is in the local space where the surface normal is the
                 // z-axis. Need to transform it into world space.
-                let scattered_ray = from_local_to_world_space(&intersection.normal, &sample.dir);
+                let scattered_ray =
+                    from_local_to_world_space(&intersection.shading_normal, &sample.dir);
+                // A bent shading normal can send the ray into the surface. Absorbing it would
+                // darken bumpy surfaces, so mirror it out across the geometric surface instead.
+                // Directions above the surface are then reached two ways, which the density of
+                // the material accounts for.
+                let geometric_cos = dot(&scattered_ray, &intersection.normal);
+                let scattered_ray = if geometric_cos < 0.0 {
+                    scattered_ray - 2.0 * geometric_cos * intersection.normal
+                } else {
+                    scattered_ray
+                };
+                let ray = Ray::new(intersection.hit_point, scattered_ray, incident_ray.time)
+                    .with_cone(cone_width, cone_spread);
 
+                // Diffuse surfaces are lit through the mixture of light and material samples.
                 Some(ScatterResult {
-                    ray: Ray::new(intersection.hit_point, scattered_ray),
-                    albedo: mat.tex_color(intersection.u, intersection.v, intersection.hit_point),
-                    probability: sample.probability,
-                    skip_pdf: true,
+                    probability: mat.scattering_pdf(
+                        &intersection.shading_normal,
+                        &intersection.normal,
+                        &ray,
+                    ),
+                    ray,
+                    albedo: mat.tex_color(incident_ray, intersection),
+                    skip_pdf: false,
                 })
             }
             Material::Metal(mat) => {
-                let reflected_dir = reflect(&incident_ray.direction, &intersection.normal);
+                let reflected_dir = reflect(&incident_ray.direction, &intersection.shading_normal);
 
                 let random_unit_dir = loop {
                     let rand_dir = Vec3F::random_fp_range(rand, -1.0..1.0);
@@ -815,7 +1581,8 @@ impl Scene {
                 // discard it (as if the surface absorbs the `incident_ray`).
                 if dot(&scattered_ray, &intersection.normal) > 0.0 {
                     Some(ScatterResult {
-                        ray: Ray::new(intersection.hit_point, scattered_ray),
+                        ray: Ray::new(intersection.hit_point, scattered_ray, incident_ray.time)
+                            .with_cone(cone_width, cone_spread),
                         albedo: mat.albedo,
                         probability: 1.0,
                         skip_pdf: true,
@@ -834,20 +1601,22 @@ impl Scene {
                 };
 
                 let in_dir_normalized = incident_ray.direction.normalized();
-                let cos_in_angle = Fp::min(dot(&in_dir_normalized, &(-intersection.normal)), 1.0);
+                let normal = intersection.shading_normal;
+                let cos_in_angle = Fp::min(dot(&in_dir_normalized, &(-normal)), 1.0);
                 let sin_in_angle = Fp::sqrt(1.0 - cos_in_angle * cos_in_angle);
 
                 let no_refract = (refrac_index * sin_in_angle) > 1.0;
                 let no_refract = no_refract
                     || (MaterialDielectric::reflectance(cos_in_angle, refrac_index) > rand.gen_range(0.0..1.0));
                 let out_dir = if no_refract {
-                    reflect(&incident_ray.direction, &intersection.normal)
+                    reflect(&incident_ray.direction, &normal)
                 } else {
-                    refract(&incident_ray.direction, &intersection.normal, refrac_index)
+                    refract(&incident_ray.direction, &normal, refrac_index)
                 };
 
                 Some(ScatterResult {
-                    ray: Ray::new(intersection.hit_point, out_dir),
+                    ray: Ray::new(intersection.hit_point, out_dir, incident_ray.time)
+                        .with_cone(cone_width, cone_spread),
                     albedo: attenuation,
                     probability: 1.0,
                     skip_pdf: true,
@@ -857,90 +1626,147 @@ impl Scene {
         }
     }
 
-    pub fn trace<R: rand::Rng>(&self, ray: &Ray, rand: &mut R, depth: u32) -> Color3F {
-        if depth > Self::TRACE_MAX_DEPTH {
-            return Color3F::zero();
-        }
-
-        let mut nearest_intersection = RayIntersection {
-            t: Fp::MAX,
-            ..Default::default()
-        };
-        let mut nearest_material: Option<Arc<Material>> = None;
-
-        for shape in self.shapes.iter() {
-            let limits = 0.001..Fp::MAX;
-            let intersection = shape.ray_intersect(ray, &limits);
-            if intersection.hit && intersection.t < nearest_intersection.t {
-                nearest_intersection = intersection;
-                nearest_material = Some(shape.get_material());
-            }
-        }
-
-        let color = if nearest_intersection.hit {
-            let material = nearest_material.unwrap();
-            let emission_color = material.emit();
-            match Self::scatter(ray, &nearest_intersection, &material, rand) {
-                Some(scattered) => {
-                    if scattered.skip_pdf {
-                        scattered.albedo * self.trace(&scattered.ray, rand, depth + 1)
-                    } else {
-                        let (random_dir, pdf_value) = self.pdf_mixure_sample(&nearest_intersection.hit_point, rand);
-                        let scattered_ray = Ray::new(nearest_intersection.hit_point, random_dir);
-
-                        let scattering_pdf =
-                            material.scattering_pdf(&nearest_intersection.normal, &scattered_ray);
+    /// The nearest intersection of `ray` with the shapes of the scene within `limits`.
+    pub fn ray_intersect(&self, ray: &Ray, limits: &Range<Fp>) -> RayIntersection<'_> {
+        let mut intersection = self.shapes.ray_intersect(ray, limits);
+        apply_normal_map(ray, &mut intersection);
+        intersection
+    }
 
-                        let scatter_color = (scattered.albedo
-                            * scattering_pdf
-                            * self.trace(&scattered_ray, rand, depth + 1))
-                            / pdf_value;
+    /// Like `ray_intersect`, also returning an index identifying the top-level shape hit. It is
+    /// meaningless when nothing was hit.
+    pub fn ray_intersect_object(
+        &self,
+        ray: &Ray,
+        limits: &Range<Fp>,
+    ) -> (RayIntersection<'_>, usize) {
+        let (mut intersection, index) = self.shapes.ray_intersect_with_index(ray, limits);
+        apply_normal_map(ray, &mut intersection);
+        (intersection, index)
+    }
 
-                        scatter_color + emission_color
-                    }
-                }
-                None => emission_color,
-            }
-        } else {
-            if self.is_background_sky {
-                // simulate the sky color
-                let ray_dir_normalized = ray.direction.normalized();
-                let a = 0.5 * (ray_dir_normalized.y + 1.0);
-                Color3F::new(1.0, 1.0, 1.0) * (1.0 - a) + Color3F::new(0.5, 0.7, 1.0) * a
-            } else {
-                Color3F::zero()
-            }
-        };
+    /// The number of AABBs and shapes tested to find the nearest intersection with `ray`.
+    pub fn ray_intersect_cost(&self, ray: &Ray, limits: &Range<Fp>) -> usize {
+        self.shapes.ray_intersect_cost(ray, limits)
+    }
 
-        color
+    /// The index of `material` among the materials of the scene, if it's one of them.
+    pub fn material_id(&self, material: &Material) -> Option<usize> {
+        self.materials
+            .iter()
+            .position(|m| std::ptr::eq(Arc::as_ptr(m), material))
     }
 
-    /*
-    pub fn trace_bvh<R: rand::Rng>(&self, ray: &Ray, rand: &mut R, depth: u32) -> Color3F {
-        if depth > Self::TRACE_MAX_DEPTH {
-            return Color3F::zero();
-        }
+    /// The shapes of the scene that emit light.
+    pub fn lights(&self) -> &LightSampler {
+        &self.lights
+    }
 
-        let limits = 0.001..Fp::MAX;
-        let (intersection, sphere_index) =
-            bvh_ray_intersect(Arc::clone(&self.bvh), &self.shapes, &ray, &limits);
+    /// Shapes that scattered rays are sent towards more often, besides the lights.
+    pub fn attractors(&self) -> &LightSampler {
+        &self.attractors
+    }
 
-        let color = if intersection.hit {
-            let material = &self.shapes[sphere_index].get_material();
-            match material.scatter(ray, &intersection, rand) {
-                Some((scattered_ray, albedo)) => {
-                    albedo * self.trace_bvh(&scattered_ray, rand, depth + 1)
-                }
-                None => Color3F::zero(),
-            }
-        } else {
+    /// Radiance arriving along `ray` when it doesn't hit any shape.
+    pub fn background(&self, ray: &Ray) -> Color3F {
+        if self.is_background_sky {
             // simulate the sky color
             let ray_dir_normalized = ray.direction.normalized();
             let a = 0.5 * (ray_dir_normalized.y + 1.0);
             Color3F::new(1.0, 1.0, 1.0) * (1.0 - a) + Color3F::new(0.5, 0.7, 1.0) * a
-        };
+        } else {
+            Color3F::zero()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bent_diffuse_samples_follow_their_density() {
+        let material = Material::Diffuse(MaterialDiffuse::new_solid_color(Color3F::new(
+            0.5, 0.5, 0.5,
+        )));
+        let quad = Quad::new(
+            Vec3F::zero(),
+            Vec3F::new(1.0, 0.0, 0.0),
+            Vec3F::new(0.0, 1.0, 0.0),
+            Arc::new(Material::Diffuse(MaterialDiffuse::new_solid_color(
+                Color3F::zero(),
+            ))),
+        );
+        let ray = Ray::new(Vec3F::new(0.5, 0.5, 1.0), Vec3F::new(0.0, 0.0, -1.0), 0.0);
+        let mut intersection = quad.ray_intersect(&ray, &(0.001..Fp::MAX));
+        // Leaning 60 degrees away from the geometric normal, so that a third of the cosine
+        // samples around it head into the surface.
+        intersection.shading_normal = Vec3F::new(Fp::sqrt(3.0) / 2.0, 0.0, 0.5);
+
+        // Bin the scattered directions by their cosine and azimuth around the geometric normal,
+        // bins of equal solid angle, and compare the counts to the integral of the density the
+        // material reports over each bin.
+        const COS_BINS: usize = 4;
+        const AZIMUTH_BINS: usize = 8;
+        const SAMPLES: usize = 100000;
+        let bin_size = 1.0 / COS_BINS as Fp;
+        let azimuth_size = 2.0 * PI / AZIMUTH_BINS as Fp;
+
+        let mut counts = [[0usize; AZIMUTH_BINS]; COS_BINS];
+        let mut rand = SmallRng::seed_from_u64(5);
+        for _ in 0..SAMPLES {
+            let scattered = Scene::scatter(&ray, &intersection, &material, &mut rand).unwrap();
+            let pdf = material.scattering_pdf(
+                &intersection.shading_normal,
+                &intersection.normal,
+                &scattered.ray,
+            );
+            assert!((scattered.probability - pdf).abs() < 1e-4);
+
+            let dir = scattered.ray.direction.normalized();
+            assert!(dir.z >= 0.0);
+            let azimuth = Fp::atan2(dir.y, dir.x) + PI;
+            let cos_bin = usize::min((dir.z / bin_size) as usize, COS_BINS - 1);
+            let azimuth_bin = usize::min((azimuth / azimuth_size) as usize, AZIMUTH_BINS - 1);
+            counts[cos_bin][azimuth_bin] += 1;
+        }
 
-        color
+        const STEPS: usize = 16;
+        for (cos_bin, row) in counts.iter().enumerate() {
+            for (azimuth_bin, &count) in row.iter().enumerate() {
+                // Midpoint rule in cosine and azimuth, in which the solid angle is uniform.
+                let mut integral = 0.0;
+                for i in 0..STEPS {
+                    for j in 0..STEPS {
+                        let cos_theta = (cos_bin as Fp + (i as Fp + 0.5) / STEPS as Fp) * bin_size;
+                        let azimuth =
+                            (azimuth_bin as Fp + (j as Fp + 0.5) / STEPS as Fp) * azimuth_size - PI;
+                        let sin_theta = Fp::sqrt(1.0 - cos_theta * cos_theta);
+                        let dir = Vec3F::new(
+                            sin_theta * Fp::cos(azimuth),
+                            sin_theta * Fp::sin(azimuth),
+                            cos_theta,
+                        );
+                        integral += material.scattering_pdf(
+                            &intersection.shading_normal,
+                            &intersection.normal,
+                            &Ray::new(intersection.hit_point, dir, 0.0),
+                        );
+                    }
+                }
+                integral *= bin_size * azimuth_size / (STEPS * STEPS) as Fp;
+
+                let expected = integral * SAMPLES as Fp;
+                let error = (count as Fp - expected).abs();
+                assert!(
+                    error < 5.0 * Fp::sqrt(expected) + 0.01 * expected + 10.0,
+                    "bin ({}, {}): {} samples, expected {}",
+                    cos_bin,
+                    azimuth_bin,
+                    count,
+                    expected
+                );
+            }
+        }
     }
-    */
 }
@@ -1,6 +1,12 @@
+use crate::bvh::{
+    build_bvh, bvh_for_each_candidate, bvh_ray_intersect, bvh_ray_intersect_with_cost, BvhNode,
+};
 use crate::materials::Material;
 use crate::types::Fp;
-use crate::vecmath::{cross, dot, Vec3F, from_local_to_world_space};
+use crate::vecmath::{
+    cross, dot, from_local_to_world_space, local_basis, luminance, solve_quartic, Mat4F, Transform,
+    Vec3F,
+};
 use std::ops::{Range, RangeInclusive};
 use std::sync::Arc;
 
@@ -9,15 +15,24 @@ use std::f32::consts::PI;
 #[cfg(feature = "use-f64")]
 use std::f64::consts::PI;
 
+#[derive(Copy, Clone)]
 pub struct Ray {
     pub origin: Vec3F,
     pub direction: Vec3F,
     pub inv_dir: Vec3F,
     pub signs: [u8; 3],
+    /// The moment the ray is cast, within the camera's shutter interval. Moving shapes are
+    /// intersected at their position at this time.
+    pub time: Fp,
+    /// Width of the cone of the rays the ray stands for, at its origin. It sizes the footprint
+    /// textures are filtered over, see `UvFootprint`.
+    pub cone_width: Fp,
+    /// How much the width of the cone grows per unit of distance, i.e. its spread angle.
+    pub cone_spread: Fp,
 }
 
 #[derive(Copy, Clone, Default)]
-pub struct RayIntersection {
+pub struct RayIntersection<'a> {
     pub hit: bool,
     pub t: Fp,
 
@@ -28,14 +43,33 @@ pub struct RayIntersection {
     pub hit_point: Vec3F,
     /// normal always points the opposite direction as the ray.
     pub normal: Vec3F,
+    /// Normal used for shading, bent by the normal map of the material if it has one. On the same
+    /// side as `normal`, which stays the normal of the geometry.
+    pub shading_normal: Vec3F,
+    /// The hit point and the normal pointing outward, in the space of the shape: before the
+    /// transform of the `Instance` it's in and the motion of a moving sphere.
+    pub object_point: Vec3F,
+    pub object_normal: Vec3F,
+    /// How much lengths shrink from the world space to the space of `object_point`. Exact for
+    /// rotations and uniform scales, see `Instance::area_scale`.
+    pub object_scale: Fp,
 
     pub u: Fp,
     pub v: Fp,
+    /// Partial derivatives of the hit point with respect to `u` and `v`.
+    pub dpdu: Vec3F,
+    pub dpdv: Vec3F,
+
+    /// Material of the shape that was hit.
+    pub material: Option<&'a Material>,
 }
 
 #[derive(Clone)]
 pub struct Sphere {
+    /// Center at time 0.0.
     pub position: Vec3F,
+    /// Displacement of the center from time 0.0 to time 1.0.
+    pub motion: Vec3F,
     pub radius: Fp,
     pub material: Arc<Material>,
 }
@@ -49,9 +83,84 @@ pub struct Quad {
     pub d: Fp,
     pub area: Fp,
     pub material: Arc<Material>,
-    pub translate: Vec3F,
-    pub rot_y_sin: Fp,
-    pub rot_y_cos: Fp,
+}
+
+#[derive(Clone)]
+pub struct Disk {
+    pub center: Vec3F,
+    pub normal: Vec3F,
+    pub radius: Fp,
+    pub material: Arc<Material>,
+}
+
+/// A cylinder standing on `base` along the Y-axis. Use an `Instance` to orient it otherwise.
+#[derive(Clone)]
+pub struct Cylinder {
+    pub base: Vec3F,
+    pub radius: Fp,
+    pub height: Fp,
+    /// Whether the top and bottom are closed by disks.
+    pub capped: bool,
+    pub material: Arc<Material>,
+}
+
+/// A cone standing on `base` with its apex `height` above along the Y-axis.
+#[derive(Clone)]
+pub struct Cone {
+    pub base: Vec3F,
+    pub radius: Fp,
+    pub height: Fp,
+    /// Whether the bottom is closed by a disk.
+    pub capped: bool,
+    pub material: Arc<Material>,
+}
+
+/// A torus lying on the XZ-plane around `center`, i.e. the Y-axis goes through its hole.
+#[derive(Clone)]
+pub struct Torus {
+    pub center: Vec3F,
+    /// Distance from `center` to the center of the tube.
+    pub major_radius: Fp,
+    /// Radius of the tube.
+    pub minor_radius: Fp,
+    pub material: Arc<Material>,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    /// The left shape with the right shape carved out.
+    Difference,
+}
+
+/// Constructive solid geometry combining two closed shapes. Each surface of the result keeps the
+/// material of the child it comes from.
+#[derive(Clone)]
+pub struct Csg {
+    pub operation: CsgOperation,
+    pub left: Arc<Shape>,
+    pub right: Arc<Shape>,
+}
+
+/// Shapes sharing one BVH. A group is meant to be put behind an `Arc` and referenced by many
+/// `Instance`s, so that all copies of the same geometry share one buffer.
+pub struct ShapeGroup {
+    shapes: Vec<Shape>,
+    bvh: Arc<BvhNode>,
+    area: Fp,
+    /// Cumulative sum of the shapes' areas divided by `area`. When a group is sampled as a light,
+    /// e.g. an emissive mesh, its shapes are picked in proportion to their area.
+    area_cdf: Vec<Fp>,
+}
+
+/// A shape group placed in the world space by an affine transform.
+#[derive(Clone)]
+pub struct Instance {
+    pub group: Arc<ShapeGroup>,
+    pub transform: Transform,
+    /// Translation applied on top of `transform` at time 1.0, interpolated linearly in between.
+    pub motion: Vec3F,
 }
 
 #[derive(Copy, Clone)]
@@ -63,10 +172,56 @@ pub struct Aabb {
 pub enum Shape {
     Sphere(Sphere),
     Quad(Quad),
+    Disk(Disk),
+    Cylinder(Cylinder),
+    Cone(Cone),
+    Torus(Torus),
+    Csg(Csg),
+    Instance(Instance),
+}
+
+impl<'a> RayIntersection<'a> {
+    // Build a hit whose normal faces against `ray`, given the normalized normal that points
+    // outward away from the shape.
+    #[allow(clippy::too_many_arguments)]
+    fn facing_ray(
+        ray: &Ray,
+        t: Fp,
+        outward_normal: Vec3F,
+        u: Fp,
+        v: Fp,
+        dpdu: Vec3F,
+        dpdv: Vec3F,
+        material: &'a Material,
+    ) -> Self {
+        let is_normal_outward = dot(&outward_normal, &ray.direction) <= 0.0;
+        let normal = if is_normal_outward {
+            outward_normal
+        } else {
+            -outward_normal
+        };
+        let hit_point = ray.origin + t * ray.direction;
+        Self {
+            hit: true,
+            t,
+            is_normal_outward,
+            hit_point,
+            normal,
+            shading_normal: normal,
+            object_point: hit_point,
+            object_normal: outward_normal,
+            object_scale: 1.0,
+            u,
+            v,
+            dpdu,
+            dpdv,
+            material: Some(material),
+        }
+    }
 }
 
 impl Ray {
-    pub fn new(origin: Vec3F, dir: Vec3F) -> Self {
+    pub fn new(origin: Vec3F, dir: Vec3F, time: Fp) -> Self {
         let inv_dir = Vec3F::new(1.0 / dir.x, 1.0 / dir.y, 1.0 / dir.z);
         Self {
             origin,
@@ -77,21 +232,62 @@ impl Ray {
                 if inv_dir.y < 0.0 { 1 } else { 0 },
                 if inv_dir.z < 0.0 { 1 } else { 0 },
             ],
+            time,
+            cone_width: 0.0,
+            cone_spread: 0.0,
         }
     }
+
+    /// Give the ray a cone of `width` at its origin that grows by `spread` per unit of distance.
+    pub fn with_cone(mut self, width: Fp, spread: Fp) -> Self {
+        self.cone_width = width;
+        self.cone_spread = spread;
+        self
+    }
+
+    /// Width of the cone of the ray at `origin + t * direction`.
+    pub fn cone_width_at(&self, t: Fp) -> Fp {
+        self.cone_width + self.cone_spread * t * self.direction.length()
+    }
 }
 
 impl Sphere {
     pub fn new(position: Vec3F, radius: Fp, material: Arc<Material>) -> Sphere {
         Sphere {
             position,
+            motion: Vec3F::zero(),
             radius,
             material,
         }
     }
 
-    pub fn ray_intersect(&self, ray: &Ray, limits: &Range<Fp>) -> RayIntersection {
-        let center_to_origin = ray.origin - self.position;
+    /// A sphere whose center moves linearly from `position_start` at time 0.0 to `position_end`
+    /// at time 1.0.
+    pub fn new_moving(
+        position_start: Vec3F,
+        position_end: Vec3F,
+        radius: Fp,
+        material: Arc<Material>,
+    ) -> Sphere {
+        Sphere {
+            position: position_start,
+            motion: position_end - position_start,
+            radius,
+            material,
+        }
+    }
+
+    pub fn position_at(&self, time: Fp) -> Vec3F {
+        self.position + time * self.motion
+    }
+
+    pub fn area(&self) -> Fp {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    pub fn ray_intersect(&self, ray: &Ray, limits: &Range<Fp>) -> RayIntersection<'_> {
+        let position = self.position_at(ray.time);
+        let center_to_origin = ray.origin - position;
 
         // Calculate sphere quadratic coefficients.
         let a = dot(&ray.direction, &ray.direction);
@@ -119,7 +315,7 @@ impl Sphere {
         }
 
         let hit_point = ray.origin + (t * ray.direction);
-        let normal = (&hit_point - &self.position) / self.radius;
+        let normal = (&hit_point - &position) / self.radius;
         let (normal, is_normal_outward) = if dot(&normal, &ray.direction) > 0.0 {
             // Make `normal` point to the opposite direction as `ray`.
             (normal * -1.0, false)
@@ -128,6 +324,7 @@ impl Sphere {
         };
 
         let (u, v) = self.get_sphere_uv(&normal);
+        let (dpdu, dpdv) = self.sphere_uv_derivatives(&((hit_point - position) / self.radius));
 
         RayIntersection {
             hit,
@@ -135,8 +332,15 @@ impl Sphere {
             hit_point,
             is_normal_outward,
             normal,
+            shading_normal: normal,
+            object_point: hit_point - position + self.position,
+            object_normal: (hit_point - position) / self.radius,
+            object_scale: 1.0,
             u,
             v,
+            dpdu,
+            dpdv,
+            material: Some(&self.material),
         }
     }
 
@@ -152,11 +356,23 @@ impl Sphere {
         (phi / (2.0 * PI), theta / PI)
     }
 
+    // Derivatives of the point at `unit_sphere_p` with respect to the UVs of `get_sphere_uv`.
+    fn sphere_uv_derivatives(&self, unit_sphere_p: &Vec3F) -> (Vec3F, Vec3F) {
+        let n = unit_sphere_p;
+        // Sine of the latitude, which |n.x| and |n.z| don't exceed.
+        let sin_theta = Fp::sqrt(n.x * n.x + n.z * n.z).max(1e-12);
+        let dpdu = longitude_tangent(&(self.radius * *n));
+        let dpdv = PI
+            * self.radius
+            * Vec3F::new(-n.y * n.x / sin_theta, sin_theta, -n.y * n.z / sin_theta);
+        (dpdu, dpdv)
+    }
+
     pub fn pdf_value(&self, ray: &Ray) -> Fp {
         let limits = 0.001..Fp::MAX;
         let intersection = self.ray_intersect(ray, &limits);
         if intersection.hit {
-            let distance_sqr = (self.position - ray.origin).length_squared();
+            let distance_sqr = (self.position_at(ray.time) - ray.origin).length_squared();
             let cos_theta_max = Fp::sqrt(1.0 - self.radius * self.radius / distance_sqr);
             let solid_angle = 2.0 * PI * (1.0 - cos_theta_max);
             1.0 / solid_angle
@@ -165,14 +381,14 @@ impl Sphere {
         }
     }
 
-    pub fn gen_random_dir<R: rand::Rng>(&self, origin: &Vec3F, rand: &mut R) -> Vec3F {
+    pub fn gen_random_dir<R: rand::Rng>(&self, origin: &Vec3F, time: Fp, rand: &mut R) -> Vec3F {
         // Use two uniform random numbers to generated a random point on the sphere's surface area
         // defined by a solid angle.
 
         let r1 = rand.gen_range(0.0..1.0);
         let r2 = rand.gen_range(0.0..1.0);
 
-        let direction = self.position - origin;
+        let direction = self.position_at(time) - origin;
         let distance_sqr = direction.length_squared();
         let z = 1.0 + r2 * (Fp::sqrt(1.0 - self.radius * self.radius/distance_sqr) - 1.0);
 
@@ -185,24 +401,25 @@ impl Sphere {
         // Transform the point from the local space (where `direction` is the z-axis) to the world space.
         from_local_to_world_space(&direction, &random_dir)
     }
+
+    /// A point picked uniformly over the surface at `time`, and the outward normal there.
+    pub fn sample_point<R: rand::Rng>(&self, time: Fp, rand: &mut R) -> (Vec3F, Vec3F) {
+        let z = rand.gen_range(-1.0..1.0);
+        let phi = 2.0 * PI * rand.gen_range(0.0..1.0);
+        let r = Fp::sqrt(1.0 - z * z);
+        let normal = Vec3F::new(r * phi.cos(), r * phi.sin(), z);
+        (self.position_at(time) + self.radius * normal, normal)
+    }
 }
 
 impl Quad {
-    pub fn new(
-        corner: Vec3F,
-        edge0: Vec3F,
-        edge1: Vec3F,
-        material: Arc<Material>,
-        translate: Vec3F,
-        rot_y_degree: Fp,
-    ) -> Self {
+    pub fn new(corner: Vec3F, edge0: Vec3F, edge1: Vec3F, material: Arc<Material>) -> Self {
         let n = cross(&edge0, &edge1);
         let area = n.length();
         let n_len_sqr = n.length_squared();
         let normal = n / n_len_sqr.sqrt();
         let w = n / n_len_sqr;
         let d = dot(&normal, &corner);
-        let (rot_y_sin, rot_y_cos) = Fp::sin_cos(rot_y_degree / 180.0 * PI);
         Self {
             corner,
             edges: [edge0, edge1],
@@ -211,9 +428,6 @@ impl Quad {
             d,
             area,
             material,
-            translate,
-            rot_y_sin,
-            rot_y_cos,
         }
     }
 
@@ -221,34 +435,12 @@ impl Quad {
         self.corner + self.edges[0] + self.edges[1]
     }
 
-    fn rotate_y(&self, v: Vec3F) -> Vec3F {
-        Vec3F::new(
-            self.rot_y_cos * v.x + self.rot_y_sin * v.z,
-            v.y,
-            -self.rot_y_sin * v.x + self.rot_y_cos * v.z,
-        )
-    }
-
-    fn reverse_rotate_y(&self, v: Vec3F) -> Vec3F {
-        Vec3F::new(
-            self.rot_y_cos * v.x - self.rot_y_sin * v.z,
-            v.y,
-            self.rot_y_sin * v.x + self.rot_y_cos * v.z,
-        )
-    }
-
-    pub fn ray_intersect(&self, ray: &Ray, limits: &Range<Fp>) -> RayIntersection {
+    pub fn ray_intersect(&self, ray: &Ray, limits: &Range<Fp>) -> RayIntersection<'_> {
         let mut intersection = RayIntersection {
             hit: false,
             ..Default::default()
         };
 
-        // Transform ray from world space to object space.
-        let ray = Ray::new(
-            self.reverse_rotate_y(ray.origin - self.translate),
-            self.reverse_rotate_y(ray.direction),
-        );
-
         let denominator = dot(&self.normal, &ray.direction);
         if Fp::abs(denominator) > 1e-8 {
             let t = (self.d - dot(&self.normal, &ray.origin)) / denominator;
@@ -261,20 +453,18 @@ impl Quad {
                 let beta = dot(&self.w, &cross(&self.edges[0], &corner_to_hit_point));
                 let unit_interval = RangeInclusive::new(0.0, 1.0);
                 if unit_interval.contains(&alpha) && unit_interval.contains(&beta) {
-                    // Rotate hit point around Y-axis (transform hit point from object space to
-                    // world space).
-                    let hit_point = self.rotate_y(hit_point) + self.translate;
-                    let normal = self.rotate_y(self.normal);
-
-                    intersection = RayIntersection {
-                        hit: true,
+                    // `self.normal` is considered pointing outward, so quads bounding a closed
+                    // shape (e.g. `create_box_quads`) should have their normals point outside.
+                    intersection = RayIntersection::facing_ray(
+                        ray,
                         t,
-                        is_normal_outward: false,
-                        hit_point,
-                        normal,
-                        u: alpha,
-                        v: beta,
-                    }
+                        self.normal,
+                        alpha,
+                        beta,
+                        self.edges[0],
+                        self.edges[1],
+                        &self.material,
+                    );
                 }
             }
         }
@@ -302,6 +492,782 @@ impl Quad {
             + rand.gen_range(0.0..1.0) * self.edges[1];
         p - origin
     }
+
+    /// A point picked uniformly over the quad, and the quad's normal.
+    pub fn sample_point<R: rand::Rng>(&self, rand: &mut R) -> (Vec3F, Vec3F) {
+        let p = self.corner
+            + rand.gen_range(0.0..1.0) * self.edges[0]
+            + rand.gen_range(0.0..1.0) * self.edges[1];
+        (p, self.normal)
+    }
+}
+
+// Solid angle pdf of sampling a point uniformly on a surface of `area` and ending up in the
+// direction of `ray`. Every point of the surface along `ray` could have been the sampled one, so
+// they all contribute.
+fn area_sampling_pdf<'a, F>(ray: &Ray, area: Fp, ray_intersect: F) -> Fp
+where
+    F: Fn(&Range<Fp>) -> RayIntersection<'a>,
+{
+    let dir_len = ray.direction.length();
+    let mut pdf = 0.0;
+    let mut limits = 0.001..Fp::MAX;
+    // None of the shapes sampled by area can be hit more than 4 times by a ray.
+    for _ in 0..4 {
+        let intersection = ray_intersect(&limits);
+        if !intersection.hit {
+            break;
+        }
+        let distance_sqr = intersection.t * intersection.t * ray.direction.length_squared();
+        let cosine = dot(&ray.direction, &intersection.normal).abs() / dir_len;
+        pdf += distance_sqr / (cosine * area);
+        limits.start = intersection.t * (1.0 + 1e-4);
+    }
+    pdf
+}
+
+// Longitude of `p` around the Y-axis, mapped to [0, 1]. Same as `Sphere::get_sphere_uv`.
+fn longitude_around_y(p: &Vec3F) -> Fp {
+    ((-p.z).atan2(p.x) + PI) / (2.0 * PI)
+}
+
+// Derivative of `p` with respect to `longitude_around_y`.
+fn longitude_tangent(p: &Vec3F) -> Vec3F {
+    2.0 * PI * Vec3F::new(p.z, 0.0, -p.x)
+}
+
+// Derivatives of a point on a cap of radius `radius` with respect to its planar UVs.
+fn cap_uv_derivatives(radius: Fp) -> (Vec3F, Vec3F) {
+    (
+        Vec3F::new(2.0 * radius, 0.0, 0.0),
+        Vec3F::new(0.0, 0.0, 2.0 * radius),
+    )
+}
+
+impl Disk {
+    pub fn new(center: Vec3F, normal: Vec3F, radius: Fp, material: Arc<Material>) -> Self {
+        Self {
+            center,
+            normal: normal.normalized(),
+            radius,
+            material,
+        }
+    }
+
+    pub fn area(&self) -> Fp {
+        PI * self.radius * self.radius
+    }
+
+    pub fn ray_intersect(&self, ray: &Ray, limits: &Range<Fp>) -> RayIntersection<'_> {
+        let denominator = dot(&self.normal, &ray.direction);
+        if Fp::abs(denominator) > 1e-8 {
+            let t = dot(&self.normal, &(self.center - ray.origin)) / denominator;
+            if limits.start < t && t < limits.end {
+                let center_to_hit_point = ray.origin + t * ray.direction - self.center;
+                let distance_sqr = center_to_hit_point.length_squared();
+                if distance_sqr <= self.radius * self.radius {
+                    // Polar coordinates on the disk.
+                    let [axis_x, axis_y, _] = local_basis(&self.normal);
+                    let angle = dot(&center_to_hit_point, &axis_y)
+                        .atan2(dot(&center_to_hit_point, &axis_x))
+                        + PI;
+                    let u = angle / (2.0 * PI);
+                    let distance = distance_sqr.sqrt();
+                    let v = distance / self.radius;
+                    let dpdu = 2.0
+                        * PI
+                        * (dot(&center_to_hit_point, &axis_x) * axis_y
+                            - dot(&center_to_hit_point, &axis_y) * axis_x);
+                    let dpdv = if distance > 0.0 {
+                        center_to_hit_point * (self.radius / distance)
+                    } else {
+                        axis_x * self.radius
+                    };
+                    return RayIntersection::facing_ray(
+                        ray,
+                        t,
+                        self.normal,
+                        u,
+                        v,
+                        dpdu,
+                        dpdv,
+                        &self.material,
+                    );
+                }
+            }
+        }
+
+        RayIntersection {
+            hit: false,
+            ..Default::default()
+        }
+    }
+
+    pub fn pdf_value(&self, ray: &Ray) -> Fp {
+        area_sampling_pdf(ray, self.area(), |limits| self.ray_intersect(ray, limits))
+    }
+
+    pub fn gen_random_dir<R: rand::Rng>(&self, origin: &Vec3F, rand: &mut R) -> Vec3F {
+        self.sample_point(rand).0 - origin
+    }
+
+    /// A point picked uniformly over the disk, and the disk's normal.
+    pub fn sample_point<R: rand::Rng>(&self, rand: &mut R) -> (Vec3F, Vec3F) {
+        let r = self.radius * Fp::sqrt(rand.gen_range(0.0..1.0));
+        let phi = 2.0 * PI * rand.gen_range(0.0..1.0);
+        let p =
+            from_local_to_world_space(&self.normal, &Vec3F::new(r * phi.cos(), r * phi.sin(), 0.0));
+        (self.center + p, self.normal)
+    }
+}
+
+impl Cylinder {
+    pub fn new(base: Vec3F, radius: Fp, height: Fp, capped: bool, material: Arc<Material>) -> Self {
+        Self {
+            base,
+            radius,
+            height,
+            capped,
+            material,
+        }
+    }
+
+    fn side_area(&self) -> Fp {
+        2.0 * PI * self.radius * self.height
+    }
+
+    fn cap_area(&self) -> Fp {
+        if self.capped {
+            PI * self.radius * self.radius
+        } else {
+            0.0
+        }
+    }
+
+    pub fn area(&self) -> Fp {
+        self.side_area() + 2.0 * self.cap_area()
+    }
+
+    pub fn ray_intersect(&self, ray: &Ray, limits: &Range<Fp>) -> RayIntersection<'_> {
+        let o = ray.origin - self.base;
+        let d = ray.direction;
+
+        let mut nearest = RayIntersection {
+            hit: false,
+            t: limits.end,
+            ..Default::default()
+        };
+        let mut consider = |t: Fp, outward_normal: Vec3F, u: Fp, v: Fp, (dpdu, dpdv)| {
+            if limits.start < t && t < nearest.t {
+                nearest = RayIntersection::facing_ray(
+                    ray,
+                    t,
+                    outward_normal,
+                    u,
+                    v,
+                    dpdu,
+                    dpdv,
+                    &self.material,
+                );
+            }
+        };
+
+        // The side: x^2 + z^2 = radius^2, 0 <= y <= height.
+        let a = d.x * d.x + d.z * d.z;
+        let half_b = o.x * d.x + o.z * d.z;
+        let c = o.x * o.x + o.z * o.z - self.radius * self.radius;
+        let discriminant = half_b * half_b - a * c;
+        if a > 1e-12 && discriminant >= 0.0 {
+            let discriminant_sqrt = discriminant.sqrt();
+            for t in [
+                (-half_b - discriminant_sqrt) / a,
+                (-half_b + discriminant_sqrt) / a,
+            ] {
+                let p = o + t * d;
+                if (0.0..=self.height).contains(&{ p.y }) {
+                    let outward_normal = Vec3F::new(p.x, 0.0, p.z) / self.radius;
+                    let derivatives = (longitude_tangent(&p), Vec3F::new(0.0, self.height, 0.0));
+                    let (u, v) = (longitude_around_y(&p), p.y / self.height);
+                    consider(t, outward_normal, u, v, derivatives);
+                }
+            }
+        }
+
+        // The caps: planar mapping of the disks.
+        if self.capped && Fp::abs(d.y) > 1e-8 {
+            for (cap_y, normal_y) in [(0.0, -1.0), (self.height, 1.0)] {
+                let t = (cap_y - o.y) / d.y;
+                let p = o + t * d;
+                if p.x * p.x + p.z * p.z <= self.radius * self.radius {
+                    let u = 0.5 * (p.x / self.radius + 1.0);
+                    let v = 0.5 * (p.z / self.radius + 1.0);
+                    let derivatives = cap_uv_derivatives(self.radius);
+                    consider(t, Vec3F::new(0.0, normal_y, 0.0), u, v, derivatives);
+                }
+            }
+        }
+
+        nearest
+    }
+
+    pub fn pdf_value(&self, ray: &Ray) -> Fp {
+        area_sampling_pdf(ray, self.area(), |limits| self.ray_intersect(ray, limits))
+    }
+
+    pub fn gen_random_dir<R: rand::Rng>(&self, origin: &Vec3F, rand: &mut R) -> Vec3F {
+        self.sample_point(rand).0 - origin
+    }
+
+    /// A point picked uniformly over the surface, and the outward normal there.
+    pub fn sample_point<R: rand::Rng>(&self, rand: &mut R) -> (Vec3F, Vec3F) {
+        // Pick the side or one of the caps in proportion to their area.
+        let phi = 2.0 * PI * rand.gen_range(0.0..1.0);
+        let pick = rand.gen_range(0.0..self.area());
+        let (p, normal) = if pick < self.side_area() {
+            let normal = Vec3F::new(phi.cos(), 0.0, phi.sin());
+            let p = self.radius * normal + Vec3F::new(0.0, rand.gen_range(0.0..self.height), 0.0);
+            (p, normal)
+        } else {
+            let r = self.radius * Fp::sqrt(rand.gen_range(0.0..1.0));
+            let (y, normal_y) = if pick < self.side_area() + self.cap_area() {
+                (0.0, -1.0)
+            } else {
+                (self.height, 1.0)
+            };
+            (
+                Vec3F::new(r * phi.cos(), y, r * phi.sin()),
+                Vec3F::new(0.0, normal_y, 0.0),
+            )
+        };
+        (self.base + p, normal)
+    }
+}
+
+impl Cone {
+    pub fn new(base: Vec3F, radius: Fp, height: Fp, capped: bool, material: Arc<Material>) -> Self {
+        Self {
+            base,
+            radius,
+            height,
+            capped,
+            material,
+        }
+    }
+
+    fn slant_height(&self) -> Fp {
+        Fp::sqrt(self.radius * self.radius + self.height * self.height)
+    }
+
+    fn side_area(&self) -> Fp {
+        PI * self.radius * self.slant_height()
+    }
+
+    fn cap_area(&self) -> Fp {
+        if self.capped {
+            PI * self.radius * self.radius
+        } else {
+            0.0
+        }
+    }
+
+    pub fn area(&self) -> Fp {
+        self.side_area() + self.cap_area()
+    }
+
+    pub fn ray_intersect(&self, ray: &Ray, limits: &Range<Fp>) -> RayIntersection<'_> {
+        let o = ray.origin - self.base;
+        let d = ray.direction;
+
+        let mut nearest = RayIntersection {
+            hit: false,
+            t: limits.end,
+            ..Default::default()
+        };
+        let mut consider = |t: Fp, outward_normal: Vec3F, u: Fp, v: Fp, (dpdu, dpdv)| {
+            if limits.start < t && t < nearest.t {
+                nearest = RayIntersection::facing_ray(
+                    ray,
+                    t,
+                    outward_normal,
+                    u,
+                    v,
+                    dpdu,
+                    dpdv,
+                    &self.material,
+                );
+            }
+        };
+
+        // The side: x^2 + z^2 = (k * (height - y))^2, 0 <= y <= height, where k is the ratio of
+        // radius to height.
+        let k = self.radius / self.height;
+        let k_sqr = k * k;
+        let h = self.height - o.y;
+        let a = d.x * d.x + d.z * d.z - k_sqr * d.y * d.y;
+        let half_b = o.x * d.x + o.z * d.z + k_sqr * h * d.y;
+        let c = o.x * o.x + o.z * o.z - k_sqr * h * h;
+        let side_ts = if Fp::abs(a) > 1e-12 {
+            let discriminant = half_b * half_b - a * c;
+            if discriminant >= 0.0 {
+                let discriminant_sqrt = discriminant.sqrt();
+                [
+                    Some((-half_b - discriminant_sqrt) / a),
+                    Some((-half_b + discriminant_sqrt) / a),
+                ]
+            } else {
+                [None, None]
+            }
+        } else if Fp::abs(half_b) > 1e-12 {
+            // The ray is parallel to the slant, and only hits the cone once.
+            [Some(-c / (2.0 * half_b)), None]
+        } else {
+            [None, None]
+        };
+        for t in side_ts.into_iter().flatten() {
+            let p = o + t * d;
+            if (0.0..=self.height).contains(&{ p.y }) {
+                // Gradient of the implicit surface.
+                let radial = Fp::sqrt(p.x * p.x + p.z * p.z);
+                let outward_normal = Vec3F::new(p.x, k * radial, p.z).normalized();
+                // Going up the slant, towards the apex.
+                let up_slant = if radial > 0.0 {
+                    Vec3F::new(-k * p.x / radial, 1.0, -k * p.z / radial)
+                } else {
+                    Vec3F::new(0.0, 1.0, 0.0)
+                };
+                let derivatives = (longitude_tangent(&p), self.height * up_slant);
+                let (u, v) = (longitude_around_y(&p), p.y / self.height);
+                consider(t, outward_normal, u, v, derivatives);
+            }
+        }
+
+        if self.capped && Fp::abs(d.y) > 1e-8 {
+            let t = -o.y / d.y;
+            let p = o + t * d;
+            if p.x * p.x + p.z * p.z <= self.radius * self.radius {
+                let u = 0.5 * (p.x / self.radius + 1.0);
+                let v = 0.5 * (p.z / self.radius + 1.0);
+                let derivatives = cap_uv_derivatives(self.radius);
+                consider(t, Vec3F::new(0.0, -1.0, 0.0), u, v, derivatives);
+            }
+        }
+
+        nearest
+    }
+
+    pub fn pdf_value(&self, ray: &Ray) -> Fp {
+        area_sampling_pdf(ray, self.area(), |limits| self.ray_intersect(ray, limits))
+    }
+
+    pub fn gen_random_dir<R: rand::Rng>(&self, origin: &Vec3F, rand: &mut R) -> Vec3F {
+        self.sample_point(rand).0 - origin
+    }
+
+    /// A point picked uniformly over the surface, and the outward normal there.
+    pub fn sample_point<R: rand::Rng>(&self, rand: &mut R) -> (Vec3F, Vec3F) {
+        let phi = 2.0 * PI * rand.gen_range(0.0..1.0);
+        let (p, normal) = if rand.gen_range(0.0..self.area()) < self.side_area() {
+            // The area of the side grows linearly with the distance from the apex, so the
+            // distance is sampled by the square root of a uniform random number.
+            let s = Fp::sqrt(rand.gen_range(0.0..1.0));
+            let p = Vec3F::new(
+                s * self.radius * phi.cos(),
+                (1.0 - s) * self.height,
+                s * self.radius * phi.sin(),
+            );
+            // Same as the gradient in `ray_intersect`, which is the same all along the slant.
+            let k = self.radius / self.height;
+            (p, Vec3F::new(phi.cos(), k, phi.sin()).normalized())
+        } else {
+            let r = self.radius * Fp::sqrt(rand.gen_range(0.0..1.0));
+            (
+                Vec3F::new(r * phi.cos(), 0.0, r * phi.sin()),
+                Vec3F::new(0.0, -1.0, 0.0),
+            )
+        };
+        (self.base + p, normal)
+    }
+}
+
+impl Torus {
+    pub fn new(center: Vec3F, major_radius: Fp, minor_radius: Fp, material: Arc<Material>) -> Self {
+        Self {
+            center,
+            major_radius,
+            minor_radius,
+            material,
+        }
+    }
+
+    pub fn area(&self) -> Fp {
+        4.0 * PI * PI * self.major_radius * self.minor_radius
+    }
+
+    pub fn ray_intersect(&self, ray: &Ray, limits: &Range<Fp>) -> RayIntersection<'_> {
+        let mut intersection = RayIntersection {
+            hit: false,
+            ..Default::default()
+        };
+
+        // Solve the quartic with a unit length direction, which keeps the coefficients in a
+        // sensible range. `s` below is the distance along the ray, `t = s / |direction|`.
+        let dir_len = ray.direction.length() as f64;
+        let o = ray.origin - self.center;
+        let (ox, oy, oz) = (o.x as f64, o.y as f64, o.z as f64);
+        let (dx, dy, dz) = (
+            ray.direction.x as f64 / dir_len,
+            ray.direction.y as f64 / dir_len,
+            ray.direction.z as f64 / dir_len,
+        );
+        let major_sqr = (self.major_radius * self.major_radius) as f64;
+        let minor_sqr = (self.minor_radius * self.minor_radius) as f64;
+
+        // Cheap rejection by the bounding sphere.
+        let bound = (self.major_radius + self.minor_radius) as f64;
+        let f = ox * dx + oy * dy + oz * dz;
+        let o_len_sqr = ox * ox + oy * oy + oz * oz;
+        if f * f - (o_len_sqr - bound * bound) < 0.0 {
+            return intersection;
+        }
+
+        // (|p|^2 + R^2 - r^2)^2 = 4 * R^2 * (p.x^2 + p.z^2), where p = o + s * d.
+        let e = o_len_sqr + major_sqr - minor_sqr;
+        let coefficients = [
+            e * e - 4.0 * major_sqr * (ox * ox + oz * oz),
+            4.0 * f * e - 8.0 * major_sqr * (ox * dx + oz * dz),
+            4.0 * f * f + 2.0 * e - 4.0 * major_sqr * (dx * dx + dz * dz),
+            4.0 * f,
+            1.0,
+        ];
+
+        let nearest_t = solve_quartic(coefficients)
+            .into_iter()
+            .map(|s| (s / dir_len) as Fp)
+            .filter(|t| limits.start < *t && *t < limits.end)
+            .min_by(|a, b| a.total_cmp(b));
+
+        if let Some(t) = nearest_t {
+            let p = o + t * ray.direction;
+            // The closest point on the center line of the tube. On the axis, which a tube at least
+            // as thick as `major_radius` crosses, all the points of the center line are.
+            let axis_distance = Fp::sqrt(p.x * p.x + p.z * p.z);
+            let outward = if axis_distance > 0.0 {
+                Vec3F::new(p.x, 0.0, p.z) / axis_distance
+            } else {
+                Vec3F::new(1.0, 0.0, 0.0)
+            };
+            let tube_center = outward * self.major_radius;
+            let outward_normal = (p - tube_center).normalized();
+            let radial = axis_distance - self.major_radius;
+            let u = longitude_around_y(&p);
+            let v = (p.y.atan2(radial) + PI) / (2.0 * PI);
+            let dpdu = longitude_tangent(&p);
+            let dpdv = 2.0 * PI * (radial * Vec3F::new(0.0, 1.0, 0.0) - p.y * outward);
+            intersection = RayIntersection::facing_ray(
+                ray,
+                t,
+                outward_normal,
+                u,
+                v,
+                dpdu,
+                dpdv,
+                &self.material,
+            );
+        }
+
+        intersection
+    }
+
+    pub fn pdf_value(&self, ray: &Ray) -> Fp {
+        area_sampling_pdf(ray, self.area(), |limits| self.ray_intersect(ray, limits))
+    }
+
+    pub fn gen_random_dir<R: rand::Rng>(&self, origin: &Vec3F, rand: &mut R) -> Vec3F {
+        self.sample_point(rand).0 - origin
+    }
+
+    /// A point picked uniformly over the surface, and the outward normal there.
+    pub fn sample_point<R: rand::Rng>(&self, rand: &mut R) -> (Vec3F, Vec3F) {
+        // The outer side of the tube has more area than the inner side. Sample the angle around
+        // the tube by rejection to keep the points uniform over the area.
+        let theta = loop {
+            let theta = 2.0 * PI * rand.gen_range(0.0..1.0);
+            let accept = (self.major_radius + self.minor_radius * theta.cos())
+                / (self.major_radius + self.minor_radius);
+            if rand.gen_range(0.0..1.0) < accept {
+                break theta;
+            }
+        };
+        let phi = 2.0 * PI * rand.gen_range(0.0..1.0);
+        let radial = self.major_radius + self.minor_radius * theta.cos();
+        let p = Vec3F::new(
+            radial * phi.cos(),
+            self.minor_radius * theta.sin(),
+            radial * phi.sin(),
+        );
+        let normal = Vec3F::new(
+            theta.cos() * phi.cos(),
+            theta.sin(),
+            theta.cos() * phi.sin(),
+        );
+        (self.center + p, normal)
+    }
+}
+
+impl CsgOperation {
+    fn is_inside(&self, inside_left: bool, inside_right: bool) -> bool {
+        match self {
+            CsgOperation::Union => inside_left || inside_right,
+            CsgOperation::Intersection => inside_left && inside_right,
+            CsgOperation::Difference => inside_left && !inside_right,
+        }
+    }
+}
+
+impl Csg {
+    pub fn new(operation: CsgOperation, left: Shape, right: Shape) -> Self {
+        Self {
+            operation,
+            left: Arc::new(left),
+            right: Arc::new(right),
+        }
+    }
+
+    // All the intersections of `ray` with `shape` beyond `start`, from near to far.
+    fn all_intersections<'a>(shape: &'a Shape, ray: &Ray, start: Fp) -> Vec<RayIntersection<'a>> {
+        const MAX_INTERSECTIONS: usize = 16;
+
+        let mut intersections = Vec::new();
+        let mut limits = start..Fp::MAX;
+        while intersections.len() < MAX_INTERSECTIONS {
+            let intersection = shape.ray_intersect(ray, &limits);
+            if !intersection.hit {
+                break;
+            }
+            limits.start = intersection.t * (1.0 + 1e-5) + 1e-5;
+            intersections.push(intersection);
+        }
+        intersections
+    }
+
+    pub fn ray_intersect(&self, ray: &Ray, limits: &Range<Fp>) -> RayIntersection<'_> {
+        // Walk along the ray through the entries and exits of both children, and report the first
+        // one where the ray enters or leaves the combined solid.
+        let left = Self::all_intersections(&self.left, ray, limits.start);
+        let right = Self::all_intersections(&self.right, ray, limits.start);
+
+        // If the first intersection with a child leaves it, the ray starts inside the child.
+        let mut inside_left = left.first().is_some_and(|i| !i.is_normal_outward);
+        let mut inside_right = right.first().is_some_and(|i| !i.is_normal_outward);
+        let mut inside = self.operation.is_inside(inside_left, inside_right);
+
+        let (mut li, mut ri) = (0, 0);
+        while li < left.len() || ri < right.len() {
+            let from_left = ri >= right.len() || (li < left.len() && left[li].t <= right[ri].t);
+            let mut intersection = if from_left {
+                inside_left = left[li].is_normal_outward;
+                li += 1;
+                left[li - 1]
+            } else {
+                inside_right = right[ri].is_normal_outward;
+                ri += 1;
+                right[ri - 1]
+            };
+
+            if intersection.t >= limits.end {
+                break;
+            }
+
+            let now_inside = self.operation.is_inside(inside_left, inside_right);
+            if now_inside != inside {
+                // The normal already faces against the ray, only which side of the combined
+                // solid it's on needs to be corrected (e.g. entering the carved out shape means
+                // leaving the result).
+                intersection.is_normal_outward = now_inside;
+                return intersection;
+            }
+            inside = now_inside;
+        }
+
+        RayIntersection {
+            hit: false,
+            ..Default::default()
+        }
+    }
+
+    // Light sampling picks either child with equal probability. The surface of the combined solid
+    // is a part of the children's surfaces, so the mixture still covers it.
+    pub fn pdf_value(&self, ray: &Ray) -> Fp {
+        0.5 * (self.left.pdf_value(ray) + self.right.pdf_value(ray))
+    }
+
+    pub fn gen_random_dir<R: rand::Rng>(&self, origin: &Vec3F, time: Fp, rand: &mut R) -> Vec3F {
+        if rand.gen_range(0.0..1.0) < 0.5 {
+            self.left.gen_random_dir(origin, time, rand)
+        } else {
+            self.right.gen_random_dir(origin, time, rand)
+        }
+    }
+}
+
+impl ShapeGroup {
+    pub fn new(mut shapes: Vec<Shape>) -> Self {
+        assert!(!shapes.is_empty());
+        let bvh = build_bvh(&mut shapes, 0);
+
+        let area: Fp = shapes.iter().map(|shape| shape.area()).sum();
+        let mut sum = 0.0;
+        let area_cdf = shapes
+            .iter()
+            .map(|shape| {
+                sum += shape.area() / area;
+                sum
+            })
+            .collect();
+
+        Self {
+            shapes,
+            bvh,
+            area,
+            area_cdf,
+        }
+    }
+
+    pub fn ray_intersect(&self, ray: &Ray, limits: &Range<Fp>) -> RayIntersection<'_> {
+        bvh_ray_intersect(&self.bvh, &self.shapes, ray, limits).0
+    }
+
+    /// Like `ray_intersect`, also returning the index of the shape hit in the group. Indices are
+    /// stable for a given group, but not in the order the shapes were given in.
+    pub fn ray_intersect_with_index(
+        &self,
+        ray: &Ray,
+        limits: &Range<Fp>,
+    ) -> (RayIntersection<'_>, usize) {
+        bvh_ray_intersect(&self.bvh, &self.shapes, ray, limits)
+    }
+
+    /// The number of AABBs and shapes tested to find the nearest intersection with `ray`.
+    pub fn ray_intersect_cost(&self, ray: &Ray, limits: &Range<Fp>) -> usize {
+        let mut cost = 0;
+        bvh_ray_intersect_with_cost(&self.bvh, &self.shapes, ray, limits, &mut cost);
+        cost
+    }
+
+    pub fn aabb(&self) -> Aabb {
+        *self.bvh.aabb()
+    }
+
+    pub fn area(&self) -> Fp {
+        self.area
+    }
+
+    pub fn emitted_power(&self) -> Fp {
+        self.shapes.iter().map(|shape| shape.emitted_power()).sum()
+    }
+
+    pub fn pdf_value(&self, ray: &Ray) -> Fp {
+        let mut pdf = 0.0;
+        // Only the shapes whose AABB the ray passes through can contribute.
+        bvh_for_each_candidate(&self.bvh, ray, &mut |shape_index| {
+            let weight = self.shapes[shape_index].area() / self.area;
+            pdf += weight * self.shapes[shape_index].pdf_value(ray);
+        });
+        pdf
+    }
+
+    pub fn gen_random_dir<R: rand::Rng>(&self, origin: &Vec3F, time: Fp, rand: &mut R) -> Vec3F {
+        let r = rand.gen_range(0.0..1.0);
+        let shape_index = self
+            .area_cdf
+            .partition_point(|&c| c <= r)
+            .min(self.shapes.len() - 1);
+        self.shapes[shape_index].gen_random_dir(origin, time, rand)
+    }
+}
+
+impl Instance {
+    pub fn new(group: Arc<ShapeGroup>, matrix: Mat4F) -> Self {
+        Self {
+            group,
+            transform: Transform::new(matrix),
+            motion: Vec3F::zero(),
+        }
+    }
+
+    pub fn from_shape(shape: Shape, matrix: Mat4F) -> Self {
+        Self::new(Arc::new(ShapeGroup::new(vec![shape])), matrix)
+    }
+
+    /// Make the instance move linearly by `motion` from time 0.0 to time 1.0.
+    pub fn with_motion(mut self, motion: Vec3F) -> Self {
+        self.motion = motion;
+        self
+    }
+
+    // Transform `ray` from the world space to the object space.
+    fn ray_to_object(&self, ray: &Ray) -> Ray {
+        let offset = ray.time * self.motion;
+        Ray::new(
+            self.transform.point_to_object(&(ray.origin - offset)),
+            self.transform.vector_to_object(&ray.direction),
+            ray.time,
+        )
+    }
+
+    pub fn ray_intersect(&self, ray: &Ray, limits: &Range<Fp>) -> RayIntersection<'_> {
+        // The object space ray direction isn't normalized, so `t` is the same in both spaces.
+        let mut intersection = self.group.ray_intersect(&self.ray_to_object(ray), limits);
+        if intersection.hit {
+            intersection.hit_point =
+                self.transform.point_to_world(&intersection.hit_point) + ray.time * self.motion;
+            intersection.normal = self
+                .transform
+                .normal_to_world(&intersection.normal)
+                .normalized();
+            intersection.shading_normal = self
+                .transform
+                .normal_to_world(&intersection.shading_normal)
+                .normalized();
+            intersection.dpdu = self.transform.vector_to_world(&intersection.dpdu);
+            intersection.dpdv = self.transform.vector_to_world(&intersection.dpdv);
+            intersection.object_scale *= self.transform.inverse.determinant().abs().cbrt();
+        }
+        intersection
+    }
+
+    /// How much the transform scales areas. Exact for rotations and uniform scales. A non-uniform
+    /// scale gets the one of the uniform scale with the same volume, an estimate that only weighs
+    /// how often the instance is picked as a light.
+    fn area_scale(&self) -> Fp {
+        self.transform.matrix.determinant().abs().powf(2.0 / 3.0)
+    }
+
+    pub fn area(&self) -> Fp {
+        self.group.area() * self.area_scale()
+    }
+
+    pub fn emitted_power(&self) -> Fp {
+        self.group.emitted_power() * self.area_scale()
+    }
+
+    // The group gives the density over the directions of the object space. The linear part A of
+    // the inverse transform maps a world direction w to A w / |A w|, which scales solid angles by
+    // |det A| / |A w|^3 for any affine transform.
+    pub fn pdf_value(&self, ray: &Ray) -> Fp {
+        let object_ray = self.ray_to_object(ray);
+        let object_dir = object_ray.direction / ray.direction.length();
+        self.group.pdf_value(&object_ray) * self.transform.inverse.determinant().abs()
+            / object_dir.length().powi(3)
+    }
+
+    pub fn gen_random_dir<R: rand::Rng>(&self, origin: &Vec3F, time: Fp, rand: &mut R) -> Vec3F {
+        let object_origin = self
+            .transform
+            .point_to_object(&(origin - time * self.motion));
+        let dir = self.group.gen_random_dir(&object_origin, time, rand);
+        self.transform.vector_to_world(&dir)
+    }
 }
 
 pub fn create_box_quads(
@@ -310,7 +1276,7 @@ pub fn create_box_quads(
     material: Arc<Material>,
     translate: Vec3F,
     rot_y_degree: Fp,
-) -> [Shape; 6] {
+) -> Shape {
     let min = Vec3F::new(
         Fp::min(corner_a.x, corner_b.x),
         Fp::min(corner_a.y, corner_b.y),
@@ -328,99 +1294,194 @@ pub fn create_box_quads(
 
     // We use right-handed coordinate system. Make sure the normal of each is pointing
     // outside the box.
-    [
+    let quads = vec![
         Shape::Quad(Quad::new(
             Vec3F::new(min.x, min.y, max.z),
             dx,
             dy,
             Arc::clone(&material),
-            translate,
-            rot_y_degree,
         )), // front
         Shape::Quad(Quad::new(
             Vec3F::new(max.x, min.y, max.z),
             -dz,
             dy,
             Arc::clone(&material),
-            translate,
-            rot_y_degree,
         )), // right
         Shape::Quad(Quad::new(
             Vec3F::new(max.x, min.y, min.z),
             -dx,
             dy,
             Arc::clone(&material),
-            translate,
-            rot_y_degree,
         )), // back
         Shape::Quad(Quad::new(
             Vec3F::new(min.x, min.y, min.z),
             dz,
             dy,
             Arc::clone(&material),
-            translate,
-            rot_y_degree,
         )), // left
         Shape::Quad(Quad::new(
             Vec3F::new(min.x, max.y, max.z),
             dx,
             -dz,
             Arc::clone(&material),
-            translate,
-            rot_y_degree,
         )), // top
         Shape::Quad(Quad::new(
             Vec3F::new(min.x, min.y, min.z),
             dx,
             dz,
             Arc::clone(&material),
-            translate,
-            rot_y_degree,
         )), // bottom
-    ]
+    ];
+
+    let matrix =
+        Mat4F::translate(translate) * Mat4F::rotate(Vec3F::new(0.0, 1.0, 0.0), rot_y_degree);
+    Shape::Instance(Instance::new(Arc::new(ShapeGroup::new(quads)), matrix))
 }
 
 impl Aabb {
     pub fn from_sphere(s: &Sphere) -> Self {
+        // Enclose the sphere at both ends of its motion.
         let extent = Vec3F::new(s.radius, s.radius, s.radius);
-        Self {
-            bounds: [s.position - extent, s.position + extent],
-        }
+        let start = s.position_at(0.0);
+        let end = s.position_at(1.0);
+        Aabb::merge(
+            &Self {
+                bounds: [start - extent, start + extent],
+            },
+            &Self {
+                bounds: [end - extent, end + extent],
+            },
+        )
     }
 
     pub fn from_quad(q: &Quad) -> Self {
-        let p0 = q.corner;
-        let p1 = q.other_corner();
-        let mut bounds = [
-            Vec3F::new(
-                Fp::min(p0.x, p1.x),
-                Fp::min(p0.y, p1.y),
-                Fp::min(p0.z, p1.z),
-            ),
-            Vec3F::new(
-                Fp::max(p0.x, p1.x),
-                Fp::max(p0.y, p1.y),
-                Fp::max(p0.z, p1.z),
-            ),
-        ];
+        Self::from_points(&[
+            q.corner,
+            q.corner + q.edges[0],
+            q.corner + q.edges[1],
+            q.other_corner(),
+        ])
+    }
+
+    pub fn from_disk(d: &Disk) -> Self {
+        // The extent along each axis is the radius scaled by the sine of the angle between the
+        // axis and the normal.
+        let n = d.normal;
+        let extent = Vec3F::new(
+            d.radius * Fp::sqrt(Fp::max(1.0 - n.x * n.x, 0.0)),
+            d.radius * Fp::sqrt(Fp::max(1.0 - n.y * n.y, 0.0)),
+            d.radius * Fp::sqrt(Fp::max(1.0 - n.z * n.z, 0.0)),
+        );
+        Self::from_points(&[d.center - extent, d.center + extent])
+    }
+
+    pub fn from_cylinder(c: &Cylinder) -> Self {
+        Self::from_points(&[
+            c.base + Vec3F::new(-c.radius, 0.0, -c.radius),
+            c.base + Vec3F::new(c.radius, c.height, c.radius),
+        ])
+    }
+
+    pub fn from_cone(c: &Cone) -> Self {
+        Self::from_points(&[
+            c.base + Vec3F::new(-c.radius, 0.0, -c.radius),
+            c.base + Vec3F::new(c.radius, c.height, c.radius),
+        ])
+    }
+
+    pub fn from_torus(t: &Torus) -> Self {
+        let extent = t.major_radius + t.minor_radius;
+        let extent = Vec3F::new(extent, t.minor_radius, extent);
+        Self::from_points(&[t.center - extent, t.center + extent])
+    }
 
-        // pad the AABB if any side is too narrow.
-        let delta = 0.0001;
-        if (bounds[1].x - bounds[0].x) < delta {
-            bounds[0].x -= delta / 2.0;
-            bounds[1].x += delta / 2.0;
+    pub fn from_csg(csg: &Csg) -> Self {
+        let left = csg.left.calc_aabb();
+        let right = csg.right.calc_aabb();
+        match csg.operation {
+            CsgOperation::Union => Aabb::merge(&left, &right),
+            CsgOperation::Intersection => Aabb::overlap(&left, &right).unwrap_or(left),
+            CsgOperation::Difference => left,
         }
-        if (bounds[1].y - bounds[0].y) < delta {
-            bounds[0].y -= delta / 2.0;
-            bounds[1].y += delta / 2.0;
+    }
+
+    pub fn from_instance(instance: &Instance) -> Self {
+        // Transform the corners of the group's AABB to the world space at both ends of the
+        // instance's motion.
+        let bounds = instance.group.aabb().bounds;
+        let mut points = Vec::with_capacity(16);
+        for time in [0.0, 1.0] {
+            for i in 0..8 {
+                let corner = Vec3F::new(
+                    bounds[i & 1].x,
+                    bounds[(i >> 1) & 1].y,
+                    bounds[(i >> 2) & 1].z,
+                );
+                points.push(instance.transform.point_to_world(&corner) + time * instance.motion);
+            }
         }
-        if (bounds[1].z - bounds[0].z) < delta {
-            bounds[0].z -= delta / 2.0;
-            bounds[1].z += delta / 2.0;
+        Self::from_points(&points)
+    }
+
+    pub fn from_points(points: &[Vec3F]) -> Self {
+        let mut bounds = [
+            Vec3F::new(Fp::MAX, Fp::MAX, Fp::MAX),
+            Vec3F::new(Fp::MIN, Fp::MIN, Fp::MIN),
+        ];
+        for p in points.iter() {
+            bounds[0] = Vec3F::new(
+                Fp::min(bounds[0].x, p.x),
+                Fp::min(bounds[0].y, p.y),
+                Fp::min(bounds[0].z, p.z),
+            );
+            bounds[1] = Vec3F::new(
+                Fp::max(bounds[1].x, p.x),
+                Fp::max(bounds[1].y, p.y),
+                Fp::max(bounds[1].z, p.z),
+            );
         }
+
+        // pad the AABB if any side is too narrow. The padding grows with the magnitude of the
+        // coordinates, otherwise it gets lost to floating point precision far away from the
+        // origin.
+        let pad = |min: Fp, max: Fp| -> (Fp, Fp) {
+            let delta = Fp::max(0.0001, Fp::max(min.abs(), max.abs()) * 1e-5);
+            if (max - min) < delta {
+                (min - delta / 2.0, max + delta / 2.0)
+            } else {
+                (min, max)
+            }
+        };
+        (bounds[0].x, bounds[1].x) = pad(bounds[0].x, bounds[1].x);
+        (bounds[0].y, bounds[1].y) = pad(bounds[0].y, bounds[1].y);
+        (bounds[0].z, bounds[1].z) = pad(bounds[0].z, bounds[1].z);
         Self { bounds }
     }
 
+    pub fn centroid(&self) -> Vec3F {
+        0.5 * (self.bounds[0] + self.bounds[1])
+    }
+
+    /// The center and radius of a sphere enclosing the AABB.
+    pub fn bounding_sphere(&self) -> (Vec3F, Fp) {
+        (
+            self.centroid(),
+            0.5 * (self.bounds[1] - self.bounds[0]).length(),
+        )
+    }
+
+    /// Index of the axis along which the AABB is the longest, 0 for X, 1 for Y, 2 for Z.
+    pub fn longest_axis(&self) -> usize {
+        let extent = self.bounds[1] - self.bounds[0];
+        if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        }
+    }
+
     pub fn merge(a: &Aabb, b: &Aabb) -> Self {
         const MIN: usize = 0;
         const MAX: usize = 1;
@@ -441,6 +1502,24 @@ impl Aabb {
         }
     }
 
+    pub fn overlap(a: &Aabb, b: &Aabb) -> Option<Self> {
+        let min = Vec3F::new(
+            Fp::max(a.bounds[0].x, b.bounds[0].x),
+            Fp::max(a.bounds[0].y, b.bounds[0].y),
+            Fp::max(a.bounds[0].z, b.bounds[0].z),
+        );
+        let max = Vec3F::new(
+            Fp::min(a.bounds[1].x, b.bounds[1].x),
+            Fp::min(a.bounds[1].y, b.bounds[1].y),
+            Fp::min(a.bounds[1].z, b.bounds[1].z),
+        );
+        if min.x <= max.x && min.y <= max.y && min.z <= max.z {
+            Some(Self::from_points(&[min, max]))
+        } else {
+            None
+        }
+    }
+
     pub fn ray_intersect(&self, ray: &Ray) -> bool {
         // Intersection exists only if all three segments overlap. I can intuitively, visually understand
         // this in 2D, but I'm not sure about this in 3D.
@@ -486,15 +1565,43 @@ impl Aabb {
         tmin = Fp::max(tmin, tz_min);
         tmax = Fp::min(tmax, tz_max);
 
-        tmin < tmax
+        // Flat AABBs (e.g. of quads) far away from the ray origin can end up with tmin == tmax
+        // after rounding, even though they are padded.
+        tmin <= tmax
     }
 }
 
+// Power emitted by a surface of `area` with `material`, assuming it emits equally in every
+// direction.
+fn surface_emitted_power(material: &Material, area: Fp) -> Fp {
+    luminance(&material.emit()) * area * PI
+}
+
 impl Shape {
-    pub fn ray_intersect(&self, ray: &Ray, limits: &Range<Fp>) -> RayIntersection {
-        match self {
-            Shape::Sphere(s) => s.ray_intersect(ray, limits),
-            Shape::Quad(q) => q.ray_intersect(ray, limits),
+    /// The nearest intersection of `ray` within `limits`, skipping the holes cut by the opacity
+    /// of the materials, see `Material::with_opacity`.
+    pub fn ray_intersect(&self, ray: &Ray, limits: &Range<Fp>) -> RayIntersection<'_> {
+        let mut limits = limits.clone();
+        loop {
+            let intersection = match self {
+                Shape::Sphere(s) => s.ray_intersect(ray, &limits),
+                Shape::Quad(q) => q.ray_intersect(ray, &limits),
+                Shape::Disk(d) => d.ray_intersect(ray, &limits),
+                Shape::Cylinder(c) => c.ray_intersect(ray, &limits),
+                Shape::Cone(c) => c.ray_intersect(ray, &limits),
+                Shape::Torus(t) => t.ray_intersect(ray, &limits),
+                // The shapes they're made of already skipped their holes.
+                Shape::Csg(c) => return c.ray_intersect(ray, &limits),
+                Shape::Instance(i) => return i.ray_intersect(ray, &limits),
+            };
+            let passes_through = intersection.hit
+                && intersection
+                    .material
+                    .is_some_and(|material| material.surface().passes_through(ray, &intersection));
+            if !passes_through {
+                return intersection;
+            }
+            limits.start = intersection.t * (1.0 + 1e-5) + 1e-5;
         }
     }
 
@@ -502,13 +1609,12 @@ impl Shape {
         match self {
             Shape::Sphere(s) => Aabb::from_sphere(&s),
             Shape::Quad(q) => Aabb::from_quad(&q),
-        }
-    }
-
-    pub fn get_material(&self) -> Arc<Material> {
-        match self {
-            Shape::Sphere(s) => Arc::clone(&s.material),
-            Shape::Quad(q) => Arc::clone(&q.material),
+            Shape::Disk(d) => Aabb::from_disk(d),
+            Shape::Cylinder(c) => Aabb::from_cylinder(c),
+            Shape::Cone(c) => Aabb::from_cone(c),
+            Shape::Torus(t) => Aabb::from_torus(t),
+            Shape::Csg(c) => Aabb::from_csg(c),
+            Shape::Instance(i) => Aabb::from_instance(i),
         }
     }
 
@@ -516,13 +1622,404 @@ impl Shape {
         match self {
             Shape::Sphere(s) => s.pdf_value(ray),
             Shape::Quad(q) => q.pdf_value(ray),
+            Shape::Disk(d) => d.pdf_value(ray),
+            Shape::Cylinder(c) => c.pdf_value(ray),
+            Shape::Cone(c) => c.pdf_value(ray),
+            Shape::Torus(t) => t.pdf_value(ray),
+            Shape::Csg(c) => c.pdf_value(ray),
+            Shape::Instance(i) => i.pdf_value(ray),
         }
     }
 
-    pub fn gen_random_dir<R: rand::Rng>(&self, origin: &Vec3F, rand: &mut R) -> Vec3F {
+    pub fn gen_random_dir<R: rand::Rng>(&self, origin: &Vec3F, time: Fp, rand: &mut R) -> Vec3F {
         match self {
-            Shape::Sphere(s) => s.gen_random_dir(origin, rand),
+            Shape::Sphere(s) => s.gen_random_dir(origin, time, rand),
             Shape::Quad(q) => q.gen_random_dir(origin, rand),
+            Shape::Disk(d) => d.gen_random_dir(origin, rand),
+            Shape::Cylinder(c) => c.gen_random_dir(origin, rand),
+            Shape::Cone(c) => c.gen_random_dir(origin, rand),
+            Shape::Torus(t) => t.gen_random_dir(origin, rand),
+            Shape::Csg(c) => c.gen_random_dir(origin, time, rand),
+            Shape::Instance(i) => i.gen_random_dir(origin, time, rand),
+        }
+    }
+
+    /// What the shape is, for messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Shape::Sphere(_) => "sphere",
+            Shape::Quad(_) => "quad",
+            Shape::Disk(_) => "disk",
+            Shape::Cylinder(_) => "cylinder",
+            Shape::Cone(_) => "cone",
+            Shape::Torus(_) => "torus",
+            Shape::Csg(_) => "CSG shape",
+            Shape::Instance(_) => "instance",
+        }
+    }
+
+    /// A point picked uniformly over the surface at `time`, and the surface normal there. CSG
+    /// shapes and instances can't be sampled this way, `None` is returned for them.
+    pub fn sample_point<R: rand::Rng>(&self, time: Fp, rand: &mut R) -> Option<(Vec3F, Vec3F)> {
+        match self {
+            Shape::Sphere(s) => Some(s.sample_point(time, rand)),
+            Shape::Quad(q) => Some(q.sample_point(rand)),
+            Shape::Disk(d) => Some(d.sample_point(rand)),
+            Shape::Cylinder(c) => Some(c.sample_point(rand)),
+            Shape::Cone(c) => Some(c.sample_point(rand)),
+            Shape::Torus(t) => Some(t.sample_point(rand)),
+            Shape::Csg(_) | Shape::Instance(_) => None,
+        }
+    }
+
+    /// Surface area. A CSG shape reports the sum of its children's areas, and an instance reports
+    /// the area of its group scaled by its transform, see `Instance::area_scale`.
+    pub fn area(&self) -> Fp {
+        match self {
+            Shape::Sphere(s) => s.area(),
+            Shape::Quad(q) => q.area,
+            Shape::Disk(d) => d.area(),
+            Shape::Cylinder(c) => c.area(),
+            Shape::Cone(c) => c.area(),
+            Shape::Torus(t) => t.area(),
+            Shape::Csg(c) => c.left.area() + c.right.area(),
+            Shape::Instance(i) => i.area(),
+        }
+    }
+
+    /// Total power emitted by the shape, measured by luminance.
+    pub fn emitted_power(&self) -> Fp {
+        match self {
+            Shape::Sphere(s) => surface_emitted_power(&s.material, s.area()),
+            Shape::Quad(q) => surface_emitted_power(&q.material, q.area),
+            Shape::Disk(d) => surface_emitted_power(&d.material, d.area()),
+            Shape::Cylinder(c) => surface_emitted_power(&c.material, c.area()),
+            Shape::Cone(c) => surface_emitted_power(&c.material, c.area()),
+            Shape::Torus(t) => surface_emitted_power(&t.material, t.area()),
+            Shape::Csg(c) => c.left.emitted_power() + c.right.emitted_power(),
+            Shape::Instance(i) => i.emitted_power(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::materials::MaterialDiffuseLight;
+    use crate::vecmath::Color3F;
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    fn light() -> Arc<Material> {
+        Arc::new(Material::DiffuseLight(MaterialDiffuseLight::new(
+            Color3F::new(1.0, 1.0, 1.0),
+        )))
+    }
+
+    fn uniform_dir<R: Rng>(rand: &mut R) -> Vec3F {
+        let z: Fp = rand.gen_range(-1.0..1.0);
+        let phi = 2.0 * PI * rand.gen_range(0.0..1.0);
+        let r = Fp::sqrt(1.0 - z * z);
+        Vec3F::new(r * phi.cos(), r * phi.sin(), z)
+    }
+
+    #[test]
+    fn scaled_instance_matches_larger_sphere() {
+        let matrix =
+            Mat4F::translate(Vec3F::new(0.0, 0.0, -5.0)) * Mat4F::scale(Vec3F::new(2.0, 2.0, 2.0));
+        let instance = Instance::from_shape(
+            Shape::Sphere(Sphere::new(Vec3F::zero(), 1.0, light())),
+            matrix,
+        );
+        let sphere = Sphere::new(Vec3F::new(0.0, 0.0, -5.0), 2.0, light());
+
+        assert!((instance.area() - sphere.area()).abs() < 1e-3);
+        let ray = Ray::new(Vec3F::zero(), Vec3F::new(0.1, 0.0, -1.0), 0.0);
+        let expected = sphere.pdf_value(&ray);
+        assert!(expected > 0.0);
+        assert!((instance.pdf_value(&ray) - expected).abs() < 1e-3 * expected);
+        // Lengths on the hit surface are halved in the space of the unit sphere.
+        let intersection = instance.ray_intersect(&ray, &(0.001..Fp::MAX));
+        assert!((intersection.object_scale - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn sampled_points_lie_on_the_surface_with_its_outward_normal() {
+        let shapes = [
+            Shape::Disk(Disk::new(
+                Vec3F::new(1.0, 2.0, 3.0),
+                Vec3F::new(1.0, 1.0, 0.0),
+                1.5,
+                light(),
+            )),
+            Shape::Cylinder(Cylinder::new(
+                Vec3F::new(0.0, -1.0, 0.0),
+                1.0,
+                2.0,
+                true,
+                light(),
+            )),
+            Shape::Cone(Cone::new(
+                Vec3F::new(0.0, -1.0, 0.0),
+                1.0,
+                2.0,
+                true,
+                light(),
+            )),
+            Shape::Torus(Torus::new(Vec3F::zero(), 2.0, 0.5, light())),
+        ];
+
+        let mut rand = SmallRng::seed_from_u64(11);
+        for shape in shapes.iter() {
+            for _ in 0..1000 {
+                let (p, normal) = shape.sample_point(0.0, &mut rand).unwrap();
+                assert!((normal.length() - 1.0).abs() < 1e-4, "{}", shape.name());
+
+                // Shooting back at the point from just outside hits it from the outside.
+                let offset = 1e-3;
+                let ray = Ray::new(p + offset * normal, -normal, 0.0);
+                let intersection = shape.ray_intersect(&ray, &(1e-5..Fp::MAX));
+                assert!(intersection.hit, "{}", shape.name());
+                assert!((intersection.t - offset).abs() < 1e-4, "{}", shape.name());
+                assert!(intersection.is_normal_outward, "{}", shape.name());
+                let cos = dot(&intersection.normal, &normal);
+                assert!(cos > 0.999, "{}", shape.name());
+            }
+        }
+    }
+
+    #[test]
+    fn non_uniformly_scaled_instance_pdf_integrates_to_one() {
+        let matrix = Mat4F::translate(Vec3F::new(0.0, 0.0, -3.0))
+            * Mat4F::rotate(Vec3F::new(0.0, 1.0, 0.0), 30.0)
+            * Mat4F::scale(Vec3F::new(2.0, 1.0, 0.5));
+        let instance = Instance::from_shape(
+            Shape::Sphere(Sphere::new(Vec3F::zero(), 1.0, light())),
+            matrix,
+        );
+
+        // Monte Carlo estimate of the integral of the pdf over all directions.
+        let mut rand = SmallRng::seed_from_u64(1);
+        let samples = 200_000;
+        let mut sum = 0.0;
+        for _ in 0..samples {
+            let ray = Ray::new(Vec3F::zero(), uniform_dir(&mut rand), 0.0);
+            sum += instance.pdf_value(&ray);
+        }
+        let integral = sum * 4.0 * PI / samples as Fp;
+        assert!((integral - 1.0).abs() < 0.03, "integral {}", integral);
+    }
+
+    // Two unit spheres overlapping between x = -0.5 and x = 0.5.
+    fn overlapping_spheres(operation: CsgOperation) -> Csg {
+        Csg::new(
+            operation,
+            Shape::Sphere(Sphere::new(Vec3F::new(-0.5, 0.0, 0.0), 1.0, light())),
+            Shape::Sphere(Sphere::new(Vec3F::new(0.5, 0.0, 0.0), 1.0, light())),
+        )
+    }
+
+    // The entries and exits of a ray along the x axis from x = -5, as (x, entering).
+    fn csg_crossings(csg: &Csg) -> Vec<(Fp, bool)> {
+        let ray = Ray::new(Vec3F::new(-5.0, 0.0, 0.0), Vec3F::new(1.0, 0.0, 0.0), 0.0);
+        let mut crossings = Vec::new();
+        let mut limits = 0.001..Fp::MAX;
+        loop {
+            let intersection = csg.ray_intersect(&ray, &limits);
+            if !intersection.hit {
+                return crossings;
+            }
+            crossings.push((intersection.hit_point.x, intersection.is_normal_outward));
+            limits.start = intersection.t + 1e-3;
+        }
+    }
+
+    fn assert_crossings(crossings: &[(Fp, bool)], expected: &[(Fp, bool)]) {
+        assert_eq!(crossings.len(), expected.len(), "{:?}", crossings);
+        for ((x, entering), (expected_x, expected_entering)) in crossings.iter().zip(expected) {
+            assert!((x - expected_x).abs() < 1e-3, "{:?}", crossings);
+            assert_eq!(entering, expected_entering, "{:?}", crossings);
+        }
+    }
+
+    #[test]
+    fn csg_union_merges_overlapping_intervals() {
+        let crossings = csg_crossings(&overlapping_spheres(CsgOperation::Union));
+        assert_crossings(&crossings, &[(-1.5, true), (1.5, false)]);
+    }
+
+    #[test]
+    fn csg_intersection_keeps_the_overlap() {
+        let crossings = csg_crossings(&overlapping_spheres(CsgOperation::Intersection));
+        assert_crossings(&crossings, &[(-0.5, true), (0.5, false)]);
+    }
+
+    #[test]
+    fn csg_difference_carves_out_the_right_shape() {
+        let crossings = csg_crossings(&overlapping_spheres(CsgOperation::Difference));
+        assert_crossings(&crossings, &[(-1.5, true), (-0.5, false)]);
+    }
+
+    #[test]
+    fn csg_ray_starting_inside() {
+        let csg = overlapping_spheres(CsgOperation::Union);
+        let ray = Ray::new(Vec3F::zero(), Vec3F::new(1.0, 0.0, 0.0), 0.0);
+        let intersection = csg.ray_intersect(&ray, &(0.001..Fp::MAX));
+        assert!(intersection.hit);
+        assert!((intersection.t - 1.5).abs() < 1e-3);
+        assert!(!intersection.is_normal_outward);
+    }
+
+    #[test]
+    fn csg_intersection_of_disjoint_shapes_is_empty() {
+        let csg = Csg::new(
+            CsgOperation::Intersection,
+            Shape::Sphere(Sphere::new(Vec3F::new(-2.0, 0.0, 0.0), 1.0, light())),
+            Shape::Sphere(Sphere::new(Vec3F::new(2.0, 0.0, 0.0), 1.0, light())),
+        );
+        assert!(csg_crossings(&csg).is_empty());
+    }
+
+    // Moving a ray a little must move the hit point by the derivatives times the change of UVs.
+    #[test]
+    fn uv_derivatives_match_finite_differences() {
+        let shapes = [
+            Shape::Sphere(Sphere::new(Vec3F::zero(), 1.0, light())),
+            Shape::Quad(Quad::new(
+                Vec3F::new(-1.0, -1.0, 0.0),
+                Vec3F::new(2.0, 0.0, 0.0),
+                Vec3F::new(0.5, 1.5, 0.5),
+                light(),
+            )),
+            Shape::Disk(Disk::new(
+                Vec3F::zero(),
+                Vec3F::new(0.3, 1.0, 0.2),
+                1.0,
+                light(),
+            )),
+            Shape::Cylinder(Cylinder::new(
+                Vec3F::new(0.0, -1.0, 0.0),
+                0.8,
+                2.0,
+                true,
+                light(),
+            )),
+            Shape::Cone(Cone::new(
+                Vec3F::new(0.0, -1.0, 0.0),
+                1.0,
+                2.0,
+                true,
+                light(),
+            )),
+            Shape::Torus(Torus::new(Vec3F::zero(), 1.0, 0.4, light())),
+            Shape::Instance(Instance::from_shape(
+                Shape::Sphere(Sphere::new(Vec3F::zero(), 1.0, light())),
+                Mat4F::scale(Vec3F::new(2.0, 1.0, 0.5)),
+            )),
+        ];
+
+        let mut rand = SmallRng::seed_from_u64(5);
+        let limits = 0.001..Fp::MAX;
+        for (index, shape) in shapes.iter().enumerate() {
+            let mut checked = 0;
+            for _ in 0..200 {
+                let origin = 5.0 * uniform_dir(&mut rand);
+                let target = 0.5 * uniform_dir(&mut rand);
+                let ray = Ray::new(origin, target - origin, 0.0);
+                let hit = shape.ray_intersect(&ray, &limits);
+                if !hit.hit {
+                    continue;
+                }
+
+                // Longitudes are singular on the Y-axis, at the poles of the sphere and the apex
+                // of the cone.
+                if Fp::hypot(hit.hit_point.x, hit.hit_point.z) < 0.2 {
+                    continue;
+                }
+
+                let offset = 1e-3 * uniform_dir(&mut rand);
+                let nearby =
+                    shape.ray_intersect(&Ray::new(origin + offset, ray.direction, 0.0), &limits);
+                let (du, dv) = (nearby.u - hit.u, nearby.v - hit.v);
+                // Skip seams, edges and jumps to another part of the shape.
+                if !nearby.hit || du.abs() > 0.05 || dv.abs() > 0.05 {
+                    continue;
+                }
+                if dot(&nearby.normal, &hit.normal) < 0.99 {
+                    continue;
+                }
+
+                let moved = nearby.hit_point - hit.hit_point;
+                let predicted = du * hit.dpdu + dv * hit.dpdv;
+                assert!(
+                    (moved - predicted).length() < 0.05 * moved.length() + 1e-5,
+                    "shape {}: moved by {:?}, predicted {:?}",
+                    index,
+                    moved,
+                    predicted
+                );
+                checked += 1;
+            }
+            assert!(checked > 20, "shape {}: only {} checked", index, checked);
+        }
+    }
+
+    #[test]
+    fn rays_pass_through_the_holes_cut_by_opacity() {
+        use crate::materials::{AlphaMode, MaterialDiffuse};
+        use crate::textures::{Texture, TextureChecker, TextureSolidColor, UvTransform};
+
+        let diffuse = || Material::Diffuse(MaterialDiffuse::new_solid_color(Color3F::zero()));
+        let gray = |opacity| {
+            Texture::Solid(TextureSolidColor::new(Color3F::new(
+                opacity, opacity, opacity,
+            )))
+        };
+        let unit_quad = |z, material| {
+            Shape::Quad(Quad::new(
+                Vec3F::new(0.0, 0.0, z),
+                Vec3F::new(1.0, 0.0, 0.0),
+                Vec3F::new(0.0, 1.0, 0.0),
+                Arc::new(material),
+            ))
+        };
+        let toward_z = |x, y| Ray::new(Vec3F::new(x, y, 1.0), Vec3F::new(0.0, 0.0, -1.0), 0.0);
+        let limits = 0.001..Fp::MAX;
+
+        // A 2x2 checker of holes in front of an opaque quad.
+        let holes = Texture::Checker(TextureChecker::new_uv(
+            Color3F::new(1.0, 1.0, 1.0),
+            Color3F::zero(),
+            UvTransform::new((2.0, 2.0), 0.0, (0.0, 0.0)),
+        ));
+        let group = ShapeGroup::new(vec![
+            unit_quad(
+                0.0,
+                diffuse().with_opacity(holes, AlphaMode::Cutout { threshold: 0.5 }),
+            ),
+            unit_quad(-1.0, diffuse()),
+        ]);
+        assert_eq!(group.ray_intersect(&toward_z(0.75, 0.25), &limits).t, 1.0);
+        assert_eq!(group.ray_intersect(&toward_z(0.25, 0.25), &limits).t, 2.0);
+
+        // Through both sides of a sphere that's all hole.
+        let hollow = diffuse().with_opacity(gray(0.2), AlphaMode::Cutout { threshold: 0.5 });
+        let sphere = Shape::Sphere(Sphere::new(Vec3F::zero(), 0.5, Arc::new(hollow)));
+        assert!(!sphere.ray_intersect(&toward_z(0.0, 0.0), &limits).hit);
+
+        // A quarter opaque film stops about a quarter of the rays.
+        let film = unit_quad(
+            0.0,
+            diffuse().with_opacity(gray(0.25), AlphaMode::Stochastic),
+        );
+        let mut rand = SmallRng::seed_from_u64(9);
+        let count = 4000;
+        let hits = (0..count)
+            .filter(|_| {
+                let ray = toward_z(rand.gen_range(0.0..1.0), rand.gen_range(0.0..1.0));
+                film.ray_intersect(&ray, &limits).hit
+            })
+            .count();
+        let fraction = hits as Fp / count as Fp;
+        assert!((fraction - 0.25).abs() < 0.03, "{}", fraction);
+    }
+}
@@ -1,26 +1,179 @@
+use crate::image::{ColorSpace, Image, ImageError};
+use crate::perlin::Perlin;
+use crate::shapes::{Ray, RayIntersection};
 use crate::types::Fp;
-use crate::vecmath::{Color3F, Vec3F};
-use crate::Image;
+use crate::vecmath::{cross, dot, local_basis, luminance, Color3F, Vec3F};
 use std::path::Path;
 
+#[cfg(not(feature = "use-f64"))]
+use std::f32::consts::PI;
+#[cfg(feature = "use-f64")]
+use std::f64::consts::PI;
+
 pub struct TextureSolidColor {
     color: Color3F,
 }
 
+/// A 2D transform of texture coordinates, applied before a texture is looked up: scale, then
+/// rotation, then offset.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct UvTransform {
+    // Row-major linear part.
+    matrix: [[Fp; 2]; 2],
+    offset: (Fp, Fp),
+}
+
+/// Where the cells of a checker pattern are laid out.
+pub enum CheckerSpace {
+    /// Cubes of side `1 / inv_scale` around the hit point.
+    World { inv_scale: Fp },
+    /// Unit squares of the transformed texture coordinates.
+    Uv(UvTransform),
+}
+
 pub struct TextureChecker {
     odd_color: Color3F,
     even_color: Color3F,
-    inv_scale: Fp,
+    space: CheckerSpace,
+}
+
+/// How texture coordinates outside of [0, 1] are brought back into the image.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum WrapMode {
+    /// Tile the image.
+    Repeat,
+    /// Tile the image, flipping every other tile so that the edges match.
+    Mirror,
+    /// Stretch the pixels on the edges.
+    Clamp,
+}
+
+/// How the texels of an image are combined into the color of a point.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TextureFilter {
+    /// The nearest texel, blocky up close.
+    Nearest,
+    /// Blend the four nearest texels of the full resolution image. Shimmers at a distance.
+    Bilinear,
+    /// Blend the two mipmap levels whose texels are the closest in size to the footprint.
+    Trilinear,
+    /// Weigh the texels within the elliptical footprint with a Gaussian, which keeps textures
+    /// seen at grazing angles sharp. Footprints more than `max_anisotropy` times longer than wide
+    /// are made rounder, to bound the number of texels.
+    Ewa { max_anisotropy: Fp },
+}
+
+impl Default for UvTransform {
+    fn default() -> Self {
+        Self::new((1.0, 1.0), 0.0, (0.0, 0.0))
+    }
+}
+
+/// The area of a texture a ray stands for: an ellipse around the UVs of the hit, given by its two
+/// axes in UV units. Zero axes sample a single point.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct UvFootprint {
+    pub axis0: (Fp, Fp),
+    pub axis1: (Fp, Fp),
+}
+
+// One level of a mipmap.
+struct MipLevel {
+    width: u32,
+    height: u32,
+    texels: Vec<Color3F>,
 }
 
 pub struct TextureImage {
-    image: Image,
+    /// The image, then levels half the size of the previous one down to a single texel.
+    levels: Vec<MipLevel>,
+    wrap: WrapMode,
+    filter: TextureFilter,
+    uv_transform: UvTransform,
+}
+
+/// Texture coordinates made from the position and normal of a hit in object space, instead of the
+/// ones of the shape. See `Material::with_projection`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Projection {
+    /// The coordinates across `axis`, 0 for X, 1 for Y and 2 for Z: (Z, Y), (X, Z) and (X, Y)
+    /// respectively. One unit of texture per unit of length, tile it with a `UvTransform`.
+    Planar { axis: usize },
+    /// Planar along the three axes, blended by how much the normal faces each raised to
+    /// `sharpness`. Boxes and other shapes get textures without stretched sides.
+    Triplanar { sharpness: Fp },
+    /// Longitude and latitude around `center`, laid out like the coordinates of `Sphere`.
+    Spherical { center: Vec3F },
+    /// The angle around the vertical axis through `center` and the height above `center`.
+    Cylindrical { center: Vec3F },
+}
+
+/// The patterns of `TextureNoise`, each a value in [0, 1] at a point.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum NoiseKind {
+    /// A single layer of Perlin noise, soft blobs.
+    Perlin,
+    /// Layers of noise of increasing frequency, like clouds.
+    Fbm,
+    /// Layers of the absolute value of noise, with creases.
+    Turbulence,
+    /// Veins along Z, bent by turbulence.
+    Marble,
+    /// Rings around the Y axis, bent by turbulence.
+    Wood,
+}
+
+/// A solid pattern of Perlin noise, blending between two colors. It's evaluated at the hit point
+/// in 3D, so it doesn't need texture coordinates and doesn't stretch.
+pub struct TextureNoise {
+    perlin: Perlin,
+    kind: NoiseKind,
+    scale: Fp,
+    octaves: u32,
+    low_color: Color3F,
+    high_color: Color3F,
+}
+
+/// Colors at increasing positions, blended linearly in between. Before the first position and
+/// after the last one, the color of the closest stop.
+pub struct ColorRamp {
+    stops: Vec<(Fp, Color3F)>,
+}
+
+/// The part of a color that `TextureNode::Channel` keeps.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ColorChannel {
+    Red,
+    Green,
+    Blue,
+    Luminance,
+}
+
+/// A texture computed from the colors of other textures, at the same point.
+pub enum TextureNode {
+    /// Blend from `a` to `b` by `factor`, channel by channel: `a` where it's black, `b` where
+    /// it's white.
+    Mix {
+        a: Texture,
+        b: Texture,
+        factor: Texture,
+    },
+    Multiply(Texture, Texture),
+    Add(Texture, Texture),
+    /// White minus the color.
+    Invert(Texture),
+    /// The color of the ramp at the luminance of the texture.
+    Ramp(Texture, ColorRamp),
+    /// A single channel of the texture, as gray.
+    Channel(Texture, ColorChannel),
 }
 
 pub enum Texture {
     Solid(TextureSolidColor),
     Checker(TextureChecker),
     Image(TextureImage),
+    Noise(TextureNoise),
+    Node(Box<TextureNode>),
 }
 
 impl TextureSolidColor {
@@ -38,18 +191,39 @@ impl TextureChecker {
         Self {
             odd_color,
             even_color,
-            inv_scale: 1.0 / scale,
+            space: CheckerSpace::World {
+                inv_scale: 1.0 / scale,
+            },
         }
     }
 
-    pub fn value(&self, pos: Vec3F) -> Color3F {
-        let xi = Fp::floor(pos.x * self.inv_scale) as i32;
-        let yi = Fp::floor(pos.y * self.inv_scale) as i32;
-        let zi = Fp::floor(pos.z * self.inv_scale) as i32;
+    /// A checker of unit squares in texture coordinates, after `uv_transform`. Scale it to have
+    /// more squares.
+    pub fn new_uv(odd_color: Color3F, even_color: Color3F, uv_transform: UvTransform) -> Self {
+        Self {
+            odd_color,
+            even_color,
+            space: CheckerSpace::Uv(uv_transform),
+        }
+    }
+
+    pub fn value(&self, u: Fp, v: Fp, pos: Vec3F) -> Color3F {
+        let cell_sum = match &self.space {
+            CheckerSpace::World { inv_scale } => {
+                let xi = Fp::floor(pos.x * inv_scale) as i32;
+                let yi = Fp::floor(pos.y * inv_scale) as i32;
+                let zi = Fp::floor(pos.z * inv_scale) as i32;
+                xi + yi + zi
+            }
+            CheckerSpace::Uv(uv_transform) => {
+                let (u, v) = uv_transform.apply(u, v);
+                Fp::floor(u) as i32 + Fp::floor(v) as i32
+            }
+        };
 
         // It's easy to visualize in 2D why the formula below forms a checker pattern, but I'm not
         // so sure about 3D.
-        let is_even = (xi + yi + zi) % 2 == 0;
+        let is_even = cell_sum % 2 == 0;
         if is_even {
             self.even_color
         } else {
@@ -58,32 +232,821 @@ impl TextureChecker {
     }
 }
 
-impl TextureImage {
-    pub fn from_file<P: AsRef<Path>>(file_path: P) -> Self {
+impl UvTransform {
+    /// Scale the texture coordinates by `scale`, which tiles a repeating texture as many times,
+    /// then rotate them by `rotation_degree` counterclockwise and shift them by `offset`.
+    pub fn new(scale: (Fp, Fp), rotation_degree: Fp, offset: (Fp, Fp)) -> Self {
+        let (sin, cos) = Fp::sin_cos(rotation_degree.to_radians());
+        Self {
+            matrix: [
+                [cos * scale.0, -sin * scale.1],
+                [sin * scale.0, cos * scale.1],
+            ],
+            offset,
+        }
+    }
+
+    pub fn apply(&self, u: Fp, v: Fp) -> (Fp, Fp) {
+        let (u, v) = self.apply_vector((u, v));
+        (u + self.offset.0, v + self.offset.1)
+    }
+
+    // The linear part only, for differences of texture coordinates.
+    fn apply_vector(&self, vector: (Fp, Fp)) -> (Fp, Fp) {
+        let m = &self.matrix;
+        (
+            m[0][0] * vector.0 + m[0][1] * vector.1,
+            m[1][0] * vector.0 + m[1][1] * vector.1,
+        )
+    }
+
+    /// `footprint` in the transformed texture coordinates.
+    pub fn apply_footprint(&self, footprint: &UvFootprint) -> UvFootprint {
+        UvFootprint {
+            axis0: self.apply_vector(footprint.axis0),
+            axis1: self.apply_vector(footprint.axis1),
+        }
+    }
+}
+
+impl WrapMode {
+    // Bring the index `i` of a texel into [0, size).
+    fn wrap(&self, i: i64, size: u32) -> usize {
+        let size = size as i64;
+        let i = match self {
+            WrapMode::Repeat => i.rem_euclid(size),
+            WrapMode::Mirror => {
+                let i = i.rem_euclid(2 * size);
+                if i < size {
+                    i
+                } else {
+                    2 * size - 1 - i
+                }
+            }
+            WrapMode::Clamp => i.clamp(0, size - 1),
+        };
+        i as usize
+    }
+}
+
+impl UvFootprint {
+    /// The footprint of the cone of `ray` on the surface at `intersection`, see `Ray::with_cone`.
+    pub fn from_ray_cone(ray: &Ray, intersection: &RayIntersection) -> Self {
+        let width = ray.cone_width_at(intersection.t);
+        if width <= 0.0 {
+            return Self::default();
+        }
+
+        // The section of the cone is a disk, which the surface stretches along the direction of
+        // the ray by one over the cosine of the incidence angle.
+        let normal = intersection.normal;
+        let dir = ray.direction.normalized();
+        let across = cross(&normal, &dir);
+        let across = if across.length_squared() > 1e-12 {
+            across.normalized()
+        } else {
+            local_basis(&normal)[0]
+        };
+        let along = cross(&across, &normal);
+        let cos_theta = Fp::max(dot(&normal, &dir).abs(), 0.01);
+
         Self {
-            image: Image::from_file(file_path),
+            axis0: Self::to_uv(&(width * across), intersection),
+            axis1: Self::to_uv(&(width / cos_theta * along), intersection),
+        }
+    }
+
+    // The change of UVs that moves the hit point by `offset` along the surface, in the least
+    // squares sense.
+    fn to_uv(offset: &Vec3F, intersection: &RayIntersection) -> (Fp, Fp) {
+        let (dpdu, dpdv) = (&intersection.dpdu, &intersection.dpdv);
+        let a = dot(dpdu, dpdu);
+        let b = dot(dpdu, dpdv);
+        let c = dot(dpdv, dpdv);
+        let det = a * c - b * b;
+        if det <= 1e-12 * a * c || det == 0.0 {
+            // Shapes without UV derivatives, or degenerate ones.
+            return (0.0, 0.0);
+        }
+        let pu = dot(offset, dpdu);
+        let pv = dot(offset, dpdv);
+        ((c * pu - b * pv) / det, (a * pv - b * pu) / det)
+    }
+
+    // Length of the longer axis.
+    fn width(&self) -> Fp {
+        Fp::max(
+            Fp::hypot(self.axis0.0, self.axis0.1),
+            Fp::hypot(self.axis1.0, self.axis1.1),
+        )
+    }
+}
+
+impl MipLevel {
+    fn texel(&self, x: i64, y: i64, wrap: WrapMode) -> Color3F {
+        let x = wrap.wrap(x, self.width);
+        let y = wrap.wrap(y, self.height);
+        self.texels[y * self.width as usize + x]
+    }
+
+    // A level half the size, each texel the average of the four it covers.
+    fn downsample(&self, wrap: WrapMode) -> MipLevel {
+        let width = u32::max(self.width / 2, 1);
+        let height = u32::max(self.height / 2, 1);
+        let mut texels = Vec::with_capacity((width * height) as usize);
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                let sum = self.texel(2 * x, 2 * y, wrap)
+                    + self.texel(2 * x + 1, 2 * y, wrap)
+                    + self.texel(2 * x, 2 * y + 1, wrap)
+                    + self.texel(2 * x + 1, 2 * y + 1, wrap);
+                texels.push(sum * 0.25);
+            }
+        }
+        MipLevel {
+            width,
+            height,
+            texels,
         }
     }
 
-    pub fn value(&self, u: Fp, v: Fp) -> Color3F {
-        if self.image.width == 0 || self.image.height == 0 {
+    fn nearest(&self, u: Fp, v: Fp, wrap: WrapMode) -> Color3F {
+        let x = Fp::floor(u * self.width as Fp) as i64;
+        let y = Fp::floor(v * self.height as Fp) as i64;
+        self.texel(x, y, wrap)
+    }
+
+    fn bilinear(&self, u: Fp, v: Fp, wrap: WrapMode) -> Color3F {
+        // Texel centers are at half integers.
+        let s = u * self.width as Fp - 0.5;
+        let t = v * self.height as Fp - 0.5;
+        let (x, y) = (Fp::floor(s), Fp::floor(t));
+        let (fx, fy) = (s - x, t - y);
+        let (x, y) = (x as i64, y as i64);
+        (1.0 - fy) * ((1.0 - fx) * self.texel(x, y, wrap) + fx * self.texel(x + 1, y, wrap))
+            + fy * ((1.0 - fx) * self.texel(x, y + 1, wrap) + fx * self.texel(x + 1, y + 1, wrap))
+    }
+
+    // Gaussian weighted average of the texels within the ellipse of `footprint`, as in "Creating
+    // Raster Omnimax Images from Multiple Perspective Views Using the Elliptical Weighted Average
+    // Filter" by Greene and Heckbert.
+    fn ewa(&self, u: Fp, v: Fp, footprint: &UvFootprint, wrap: WrapMode) -> Color3F {
+        let (w, h) = (self.width as Fp, self.height as Fp);
+        let s = u * w - 0.5;
+        let t = v * h - 0.5;
+        let (ds0, dt0) = (footprint.axis0.0 * w, footprint.axis0.1 * h);
+        let (ds1, dt1) = (footprint.axis1.0 * w, footprint.axis1.1 * h);
+
+        // The implicit equation of the ellipse, A s^2 + B s t + C t^2 < 1, widened by a texel so
+        // that it never falls between texels.
+        let a = dt0 * dt0 + dt1 * dt1 + 1.0;
+        let b = -2.0 * (ds0 * dt0 + ds1 * dt1);
+        let c = ds0 * ds0 + ds1 * ds1 + 1.0;
+        let inv_f = 1.0 / (a * c - b * b * 0.25);
+        let (a, b, c) = (a * inv_f, b * inv_f, c * inv_f);
+
+        // The bounding box of the ellipse.
+        let det = 4.0 * a * c - b * b;
+        let s_extent = 2.0 * Fp::sqrt(det * c) / det;
+        let t_extent = 2.0 * Fp::sqrt(det * a) / det;
+        let (s0, s1) = (
+            Fp::ceil(s - s_extent) as i64,
+            Fp::floor(s + s_extent) as i64,
+        );
+        let (t0, t1) = (
+            Fp::ceil(t - t_extent) as i64,
+            Fp::floor(t + t_extent) as i64,
+        );
+
+        const ALPHA: Fp = 2.0;
+        let mut sum = Color3F::zero();
+        let mut weight_sum = 0.0;
+        for y in t0..=t1 {
+            let tt = y as Fp - t;
+            for x in s0..=s1 {
+                let ss = x as Fp - s;
+                let r_sqr = a * ss * ss + b * ss * tt + c * tt * tt;
+                if r_sqr < 1.0 {
+                    let weight = Fp::exp(-ALPHA * r_sqr) - Fp::exp(-ALPHA);
+                    sum += weight * self.texel(x, y, wrap);
+                    weight_sum += weight;
+                }
+            }
+        }
+
+        if weight_sum > 0.0 {
+            sum / weight_sum
+        } else {
+            self.bilinear(u, v, wrap)
+        }
+    }
+}
+
+impl TextureImage {
+    /// Loads the image at `file_path`, decoded from its native color space.
+    pub fn from_file<P: AsRef<Path>>(file_path: P) -> Result<Self, ImageError> {
+        let image = Image::from_file(file_path)?;
+        Ok(Self::from_image(&image, image.native_color_space()))
+    }
+
+    /// A repeating texture, filtered trilinearly. The colors of `image` are decoded from
+    /// `color_space` first, so that they are filtered and shaded as linear values.
+    pub fn from_image(image: &Image, color_space: ColorSpace) -> Self {
+        Self::from_texels(image, |x, y| image.texel(x, y, color_space).0)
+    }
+
+    /// A gray texture of the alpha channel of `image`, white where it's opaque. See
+    /// `from_image`.
+    pub fn from_alpha(image: &Image) -> Self {
+        Self::from_texels(image, |x, y| {
+            let alpha = image.texel(x, y, ColorSpace::Linear).1;
+            Color3F::new(alpha, alpha, alpha)
+        })
+    }
+
+    // A texture the size of `image`, with the colors given by `texel` at each pixel.
+    fn from_texels(image: &Image, texel: impl Fn(u32, u32) -> Color3F) -> Self {
+        let mut texture = Self {
+            levels: Vec::new(),
+            wrap: WrapMode::Repeat,
+            filter: TextureFilter::Trilinear,
+            uv_transform: UvTransform::default(),
+        };
+        if image.width > 0 && image.height > 0 {
+            let mut texels = Vec::with_capacity((image.width * image.height) as usize);
+            for y in 0..image.height {
+                for x in 0..image.width {
+                    texels.push(texel(x, y));
+                }
+            }
+            texture.levels.push(MipLevel {
+                width: image.width,
+                height: image.height,
+                texels,
+            });
+            texture.build_mipmap();
+        }
+        texture
+    }
+
+    pub fn with_wrap(mut self, wrap: WrapMode) -> Self {
+        self.wrap = wrap;
+        self.levels.truncate(1);
+        self.build_mipmap();
+        self
+    }
+
+    pub fn with_filter(mut self, filter: TextureFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Look the image up at transformed texture coordinates, to tile, rotate or shift it.
+    pub fn with_uv_transform(mut self, uv_transform: UvTransform) -> Self {
+        self.uv_transform = uv_transform;
+        self
+    }
+
+    // Add the levels below the first one, which depend on the wrap mode at their edges.
+    fn build_mipmap(&mut self) {
+        while let Some(last) = self.levels.last() {
+            if last.width == 1 && last.height == 1 {
+                break;
+            }
+            let level = last.downsample(self.wrap);
+            self.levels.push(level);
+        }
+    }
+
+    // The level whose texels are `width` wide in UV units, fractional between two levels.
+    fn level_of_width(&self, width: Fp) -> Fp {
+        let resolution = u32::max(self.levels[0].width, self.levels[0].height) as Fp;
+        Fp::log2(Fp::max(width * resolution, 1e-8))
+    }
+
+    fn trilinear(&self, u: Fp, v: Fp, width: Fp) -> Color3F {
+        let level = self.level_of_width(width);
+        let last = self.levels.len() - 1;
+        if level <= 0.0 {
+            self.levels[0].bilinear(u, v, self.wrap)
+        } else if level >= last as Fp {
+            self.levels[last].texels[0]
+        } else {
+            let i = level as usize;
+            let f = level - i as Fp;
+            (1.0 - f) * self.levels[i].bilinear(u, v, self.wrap)
+                + f * self.levels[i + 1].bilinear(u, v, self.wrap)
+        }
+    }
+
+    fn ewa(&self, u: Fp, v: Fp, footprint: &UvFootprint, max_anisotropy: Fp) -> Color3F {
+        let length = |axis: (Fp, Fp)| Fp::hypot(axis.0, axis.1);
+        let (mut major, mut minor) = (footprint.axis0, footprint.axis1);
+        if length(major) < length(minor) {
+            std::mem::swap(&mut major, &mut minor);
+        }
+        let (major_length, minor_length) = (length(major), length(minor));
+        if minor_length == 0.0 {
+            return self.trilinear(u, v, major_length);
+        }
+
+        // Widen the ellipse if it's too thin, which blurs rather than aliases.
+        if minor_length * max_anisotropy < major_length {
+            let scale = major_length / (minor_length * max_anisotropy);
+            minor = (minor.0 * scale, minor.1 * scale);
+        }
+        let footprint = UvFootprint {
+            axis0: major,
+            axis1: minor,
+        };
+
+        // The minor axis decides the level, the filter covers the rest.
+        let level = Fp::max(self.level_of_width(length(minor)), 0.0);
+        let last = self.levels.len() - 1;
+        if level >= last as Fp {
+            return self.levels[last].texels[0];
+        }
+        let i = level as usize;
+        let f = level - i as Fp;
+        (1.0 - f) * self.levels[i].ewa(u, v, &footprint, self.wrap)
+            + f * self.levels[i + 1].ewa(u, v, &footprint, self.wrap)
+    }
+
+    pub fn value(&self, u: Fp, v: Fp, footprint: &UvFootprint) -> Color3F {
+        if self.levels.is_empty() {
             // Return solid cyan as debugging aid.
             return Color3F::new(0.0, 1.0, 1.0);
         }
 
-        let u = u.clamp(0.0, 1.0);
-        let v = 1.0 - v.clamp(0.0, 1.0); // Flip to image coordinate.
+        let (u, v) = self.uv_transform.apply(u, v);
+        let footprint = self.uv_transform.apply_footprint(footprint);
+
+        // Flip to image coordinate.
+        let v = 1.0 - v;
+        let footprint = UvFootprint {
+            axis0: (footprint.axis0.0, -footprint.axis0.1),
+            axis1: (footprint.axis1.0, -footprint.axis1.1),
+        };
+
+        match self.filter {
+            TextureFilter::Nearest => self.levels[0].nearest(u, v, self.wrap),
+            TextureFilter::Bilinear => self.levels[0].bilinear(u, v, self.wrap),
+            TextureFilter::Trilinear => self.trilinear(u, v, footprint.width()),
+            TextureFilter::Ewa { max_anisotropy } => self.ewa(u, v, &footprint, max_anisotropy),
+        }
+    }
+}
+
+impl Projection {
+    /// The color of `texture` at `object_point`, where the surface faces `object_normal`. `pos`
+    /// is the world space position for the textures that use it, and textures are filtered over
+    /// a footprint `width` wide in object space.
+    pub fn value(
+        &self,
+        texture: &Texture,
+        object_point: Vec3F,
+        object_normal: Vec3F,
+        pos: Vec3F,
+        width: Fp,
+    ) -> Color3F {
+        // Footprints are approximated by ellipses along the texture axes.
+        let lookup = |(u, v), (width_u, width_v): (Fp, Fp)| {
+            let footprint = UvFootprint {
+                axis0: (width_u, 0.0),
+                axis1: (0.0, width_v),
+            };
+            texture.value(u, v, pos, &footprint)
+        };
+        match self {
+            Projection::Planar { axis } => lookup(planar_uv(object_point, *axis), (width, width)),
+            Projection::Triplanar { sharpness } => {
+                let weights = [object_normal.x, object_normal.y, object_normal.z]
+                    .map(|n| Fp::powf(n.abs(), *sharpness));
+                let total: Fp = weights.iter().sum();
+                (0..3)
+                    .filter(|&axis| weights[axis] > 0.0)
+                    .map(|axis| {
+                        weights[axis] / total
+                            * lookup(planar_uv(object_point, axis), (width, width))
+                    })
+                    .fold(Color3F::zero(), |sum, color| sum + color)
+            }
+            Projection::Spherical { center } => {
+                let d = object_point - *center;
+                let r = d.length();
+                let theta = Fp::acos((-d.y / r).clamp(-1.0, 1.0));
+                let phi = Fp::atan2(-d.z, d.x) + PI;
+                let width_u = width / (2.0 * PI * Fp::hypot(d.x, d.z)).max(1e-8);
+                lookup((phi / (2.0 * PI), theta / PI), (width_u, width / (PI * r)))
+            }
+            Projection::Cylindrical { center } => {
+                let d = object_point - *center;
+                let phi = Fp::atan2(-d.z, d.x) + PI;
+                let width_u = width / (2.0 * PI * Fp::hypot(d.x, d.z)).max(1e-8);
+                lookup((phi / (2.0 * PI), d.y), (width_u, width))
+            }
+        }
+    }
+}
+
+// The coordinates of `p` across `axis`, see `Projection::Planar`.
+fn planar_uv(p: Vec3F, axis: usize) -> (Fp, Fp) {
+    match axis {
+        0 => (p.z, p.y),
+        1 => (p.x, p.z),
+        _ => (p.x, p.y),
+    }
+}
 
-        self.image.pixel_at_uv(u, v)
+impl TextureNoise {
+    /// Noise from black to white, with features about one unit apart and 7 octaves.
+    pub fn new(kind: NoiseKind, seed: u64) -> Self {
+        Self {
+            perlin: Perlin::new(seed),
+            kind,
+            scale: 1.0,
+            octaves: 7,
+            low_color: Color3F::zero(),
+            high_color: Color3F::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    /// Multiply the positions by `scale`, so larger scales give smaller features.
+    pub fn with_scale(mut self, scale: Fp) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// The number of layers of noise of the patterns other than `NoiseKind::Perlin`.
+    pub fn with_octaves(mut self, octaves: u32) -> Self {
+        self.octaves = octaves;
+        self
+    }
+
+    /// Blend from `low_color` where the pattern is 0 to `high_color` where it's 1.
+    pub fn with_colors(mut self, low_color: Color3F, high_color: Color3F) -> Self {
+        self.low_color = low_color;
+        self.high_color = high_color;
+        self
+    }
+
+    /// The pattern at `pos`, in [0, 1].
+    pub fn pattern(&self, pos: Vec3F) -> Fp {
+        let p = self.scale * pos;
+        let value = match self.kind {
+            NoiseKind::Perlin => 0.5 * (1.0 + self.perlin.noise(p)),
+            NoiseKind::Fbm => 0.5 * (1.0 + self.perlin.fbm(p, self.octaves)),
+            NoiseKind::Turbulence => self.perlin.turbulence(p, self.octaves),
+            NoiseKind::Marble => {
+                let turbulence = self.perlin.turbulence(p, self.octaves);
+                0.5 * (1.0 + Fp::sin(p.z + 10.0 * turbulence))
+            }
+            NoiseKind::Wood => {
+                let rings = Fp::hypot(p.x, p.z) + 2.0 * self.perlin.turbulence(p, self.octaves);
+                rings - Fp::floor(rings)
+            }
+        };
+        value.clamp(0.0, 1.0)
+    }
+
+    pub fn value(&self, pos: Vec3F) -> Color3F {
+        let t = self.pattern(pos);
+        (1.0 - t) * self.low_color + t * self.high_color
+    }
+}
+
+impl ColorRamp {
+    /// A ramp through `stops`, pairs of position and color in any order.
+    pub fn new(mut stops: Vec<(Fp, Color3F)>) -> Self {
+        assert!(!stops.is_empty());
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self { stops }
+    }
+
+    pub fn value(&self, t: Fp) -> Color3F {
+        let next = self.stops.partition_point(|stop| stop.0 <= t);
+        if next == 0 {
+            return self.stops[0].1;
+        }
+        if next == self.stops.len() {
+            return self.stops[next - 1].1;
+        }
+        let ((t0, color0), (t1, color1)) = (self.stops[next - 1], self.stops[next]);
+        let f = (t - t0) / (t1 - t0);
+        (1.0 - f) * color0 + f * color1
+    }
+}
+
+impl ColorChannel {
+    fn extract(&self, color: Color3F) -> Fp {
+        match self {
+            ColorChannel::Red => color.x,
+            ColorChannel::Green => color.y,
+            ColorChannel::Blue => color.z,
+            ColorChannel::Luminance => luminance(&color),
+        }
+    }
+}
+
+impl TextureNode {
+    pub fn value(&self, u: Fp, v: Fp, pos: Vec3F, footprint: &UvFootprint) -> Color3F {
+        let value = |tex: &Texture| tex.value(u, v, pos, footprint);
+        match self {
+            TextureNode::Mix { a, b, factor } => {
+                let (a, factor) = (value(a), value(factor));
+                a + factor * (value(b) - a)
+            }
+            TextureNode::Multiply(a, b) => value(a) * value(b),
+            TextureNode::Add(a, b) => value(a) + value(b),
+            TextureNode::Invert(tex) => Color3F::new(1.0, 1.0, 1.0) - value(tex),
+            TextureNode::Ramp(tex, ramp) => ramp.value(luminance(&value(tex))),
+            TextureNode::Channel(tex, channel) => {
+                let gray = channel.extract(value(tex));
+                Color3F::new(gray, gray, gray)
+            }
+        }
     }
 }
 
 impl Texture {
-    pub fn value(&self, u: Fp, v: Fp, pos: Vec3F) -> Color3F {
+    /// `a` where `factor` is black, `b` where it's white, see `TextureNode::Mix`.
+    pub fn mix(a: Texture, b: Texture, factor: Texture) -> Self {
+        Texture::Node(Box::new(TextureNode::Mix { a, b, factor }))
+    }
+
+    pub fn product(a: Texture, b: Texture) -> Self {
+        Texture::Node(Box::new(TextureNode::Multiply(a, b)))
+    }
+
+    pub fn sum(a: Texture, b: Texture) -> Self {
+        Texture::Node(Box::new(TextureNode::Add(a, b)))
+    }
+
+    pub fn inverted(self) -> Self {
+        Texture::Node(Box::new(TextureNode::Invert(self)))
+    }
+
+    /// Recolor the texture through `ramp`, by luminance.
+    pub fn remapped(self, ramp: ColorRamp) -> Self {
+        Texture::Node(Box::new(TextureNode::Ramp(self, ramp)))
+    }
+
+    pub fn channel(self, channel: ColorChannel) -> Self {
+        Texture::Node(Box::new(TextureNode::Channel(self, channel)))
+    }
+
+    /// The color at (`u`, `v`) or `pos`. Image textures are filtered over `footprint`.
+    pub fn value(&self, u: Fp, v: Fp, pos: Vec3F, footprint: &UvFootprint) -> Color3F {
         match self {
             Texture::Solid(tex) => tex.value(),
-            Texture::Checker(tex) => tex.value(pos),
-            Texture::Image(tex) => tex.value(u, v),
+            Texture::Checker(tex) => tex.value(u, v, pos),
+            Texture::Image(tex) => tex.value(u, v, footprint),
+            Texture::Noise(tex) => tex.value(pos),
+            Texture::Node(node) => node.value(u, v, pos, footprint),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::PixelData;
+
+    // A gray image whose texels alternate between black and white.
+    fn texel_checker(size: u32) -> Image {
+        let data = (0..size * size)
+            .map(|i| {
+                if (i % size + i / size).is_multiple_of(2) {
+                    0
+                } else {
+                    255
+                }
+            })
+            .collect();
+        Image {
+            width: size,
+            height: size,
+            channels: 1,
+            data: PixelData::U8(data),
+        }
+    }
+
+    #[test]
+    fn ray_cone_footprint_stretches_at_grazing_angles() {
+        use crate::materials::{Material, MaterialDiffuse};
+        use crate::shapes::Quad;
+        use std::sync::Arc;
+
+        let material = Arc::new(Material::Diffuse(MaterialDiffuse::new_solid_color(
+            Color3F::zero(),
+        )));
+        // 4 by 2 units, UVs along X and Y.
+        let quad = Quad::new(
+            Vec3F::new(-2.0, -1.0, 0.0),
+            Vec3F::new(4.0, 0.0, 0.0),
+            Vec3F::new(0.0, 2.0, 0.0),
+            material,
+        );
+        let limits = 0.001..Fp::MAX;
+
+        // Head-on from 10 units away, the cone is 0.1 wide.
+        let ray = Ray::new(Vec3F::new(0.0, 0.0, 10.0), Vec3F::new(0.0, 0.0, -2.0), 0.0)
+            .with_cone(0.0, 0.01);
+        let footprint = UvFootprint::from_ray_cone(&ray, &quad.ray_intersect(&ray, &limits));
+        let lengths = |f: &UvFootprint| {
+            let mut lengths = [
+                Fp::hypot(f.axis0.0 * 4.0, f.axis0.1 * 2.0),
+                Fp::hypot(f.axis1.0 * 4.0, f.axis1.1 * 2.0),
+            ];
+            lengths.sort_by(|a, b| a.total_cmp(b));
+            lengths
+        };
+        let [minor, major] = lengths(&footprint);
+        assert!((minor - 0.1).abs() < 1e-4 && (major - 0.1).abs() < 1e-4);
+
+        // At 60 degrees from the normal, the footprint is twice as long as wide.
+        let dir = Vec3F::new(Fp::sqrt(3.0), 0.0, -1.0);
+        let ray = Ray::new(-5.0 * dir, dir, 0.0).with_cone(0.0, 0.01);
+        let footprint = UvFootprint::from_ray_cone(&ray, &quad.ray_intersect(&ray, &limits));
+        let [minor, major] = lengths(&footprint);
+        assert!((minor - 0.1).abs() < 1e-3 && (major - 0.2).abs() < 1e-3);
+    }
+
+    #[test]
+    fn wrap_modes_bring_texels_into_the_image() {
+        let indices = [-5, -1, 0, 3, 4, 9];
+        let wrapped = |wrap: WrapMode| indices.map(|i| wrap.wrap(i, 4));
+        assert_eq!(wrapped(WrapMode::Repeat), [3, 3, 0, 3, 0, 1]);
+        assert_eq!(wrapped(WrapMode::Mirror), [3, 0, 0, 3, 3, 1]);
+        assert_eq!(wrapped(WrapMode::Clamp), [0, 0, 0, 3, 3, 3]);
+    }
+
+    #[test]
+    fn wide_footprints_average_the_texels() {
+        let image = texel_checker(64);
+        let gray = Color3F::new(0.5, 0.5, 0.5);
+        let point = UvFootprint::default();
+        // Covers 8 by 8 texels, or 32 by 2 at a grazing angle.
+        let square = UvFootprint {
+            axis0: (0.125, 0.0),
+            axis1: (0.0, 0.125),
+        };
+        let thin = UvFootprint {
+            axis0: (0.5, 0.0),
+            axis1: (0.0, 1.0 / 32.0),
+        };
+        // The center of a white texel.
+        let (u, v) = (1.5 / 64.0, 1.0 - 0.5 / 64.0);
+
+        for filter in [
+            TextureFilter::Nearest,
+            TextureFilter::Bilinear,
+            TextureFilter::Trilinear,
+            TextureFilter::Ewa {
+                max_anisotropy: 16.0,
+            },
+        ] {
+            let texture = TextureImage::from_image(&image, ColorSpace::Linear).with_filter(filter);
+            let white = texture.value(u, v, &point);
+            assert!((white.x - 1.0).abs() < 1e-4, "{:?}: {:?}", filter, white);
+
+            if matches!(filter, TextureFilter::Trilinear | TextureFilter::Ewa { .. }) {
+                for footprint in [square, thin] {
+                    let color = texture.value(u, v, &footprint);
+                    assert!((color - gray).length() < 0.05, "{:?}: {:?}", filter, color);
+                }
+            }
         }
     }
+
+    #[test]
+    fn uv_transforms_tile_rotate_and_shift() {
+        let close = |a: (Fp, Fp), b: (Fp, Fp)| (a.0 - b.0).abs() < 1e-5 && (a.1 - b.1).abs() < 1e-5;
+        let tiled = UvTransform::new((2.0, 3.0), 0.0, (0.5, 0.0));
+        assert!(close(tiled.apply(0.25, 0.5), (1.0, 1.5)));
+        // Footprints scale with the texture coordinates, but don't shift.
+        let footprint = tiled.apply_footprint(&UvFootprint {
+            axis0: (0.1, 0.0),
+            axis1: (0.0, 0.1),
+        });
+        assert!(close(footprint.axis0, (0.2, 0.0)) && close(footprint.axis1, (0.0, 0.3)));
+        let rotated = UvTransform::new((1.0, 1.0), 90.0, (0.0, 0.0));
+        assert!(close(rotated.apply(1.0, 0.0), (0.0, 1.0)));
+
+        let white = Color3F::new(1.0, 1.0, 1.0);
+        // Half a texel checker in u shows a whole one once tiled twice.
+        let image = texel_checker(2);
+        let point = UvFootprint::default();
+        let texture = TextureImage::from_image(&image, ColorSpace::Linear)
+            .with_filter(TextureFilter::Nearest);
+        assert_eq!(texture.value(0.3, 0.9, &point), Color3F::zero());
+        let texture = texture.with_uv_transform(UvTransform::new((2.0, 1.0), 0.0, (0.0, 0.0)));
+        assert_eq!(texture.value(0.3, 0.9, &point), white);
+
+        let checker = TextureChecker::new_uv(white, Color3F::zero(), tiled);
+        // (1.1, 0.3) and (1.5, 1.5) after the transform.
+        assert_eq!(checker.value(0.3, 0.1, Vec3F::zero()), white);
+        assert_eq!(checker.value(0.5, 0.5, Vec3F::zero()), Color3F::zero());
+    }
+
+    #[test]
+    fn nodes_combine_the_colors_of_textures() {
+        let solid = |r, g, b| Texture::Solid(TextureSolidColor::new(Color3F::new(r, g, b)));
+        let value = |tex: &Texture| tex.value(0.6, 0.1, Vec3F::zero(), &UvFootprint::default());
+        let checker = || {
+            Texture::Checker(TextureChecker::new_uv(
+                Color3F::new(1.0, 1.0, 1.0),
+                Color3F::zero(),
+                UvTransform::new((2.0, 2.0), 0.0, (0.0, 0.0)),
+            ))
+        };
+
+        // The checker is white at (0.6, 0.1), so it picks the second texture.
+        let mixed = Texture::mix(solid(1.0, 0.0, 0.0), solid(0.0, 0.0, 1.0), checker());
+        assert_eq!(value(&mixed), Color3F::new(0.0, 0.0, 1.0));
+        let half = Texture::mix(
+            solid(1.0, 0.0, 0.0),
+            solid(0.0, 0.0, 1.0),
+            solid(0.5, 0.5, 0.5),
+        );
+        assert_eq!(value(&half), Color3F::new(0.5, 0.0, 0.5));
+
+        let tinted = Texture::product(checker(), solid(0.2, 0.4, 0.6));
+        assert_eq!(value(&tinted), Color3F::new(0.2, 0.4, 0.6));
+        let brightened = Texture::sum(solid(0.25, 0.0, 0.5), solid(0.25, 0.5, 0.0));
+        assert_eq!(value(&brightened), Color3F::new(0.5, 0.5, 0.5));
+        assert_eq!(value(&checker().inverted()), Color3F::zero());
+        let green = solid(0.1, 0.7, 0.3).channel(ColorChannel::Green);
+        assert_eq!(value(&green), Color3F::new(0.7, 0.7, 0.7));
+
+        let ramp = ColorRamp::new(vec![
+            (1.0, Color3F::new(1.0, 0.0, 0.0)),
+            (0.0, Color3F::new(0.0, 0.0, 1.0)),
+        ]);
+        assert_eq!(ramp.value(-1.0), Color3F::new(0.0, 0.0, 1.0));
+        assert_eq!(ramp.value(0.25), Color3F::new(0.25, 0.0, 0.75));
+        assert_eq!(ramp.value(2.0), Color3F::new(1.0, 0.0, 0.0));
+        let remapped = solid(0.5, 0.5, 0.5).remapped(ramp);
+        assert!((value(&remapped) - Color3F::new(0.5, 0.0, 0.5)).length() < 1e-5);
+    }
+
+    #[test]
+    fn projections_make_coordinates_from_object_space() {
+        // White in the odd cells of a checker of quarter unit squares, black in the even ones.
+        let checker = Texture::Checker(TextureChecker::new_uv(
+            Color3F::new(1.0, 1.0, 1.0),
+            Color3F::zero(),
+            UvTransform::new((4.0, 4.0), 0.0, (0.0, 0.0)),
+        ));
+        let white = Color3F::new(1.0, 1.0, 1.0);
+        let value = |projection: Projection, p: Vec3F, n: Vec3F| {
+            projection.value(&checker, p, n, Vec3F::zero(), 0.0)
+        };
+        let up = Vec3F::new(0.0, 1.0, 0.0);
+
+        let planar = |axis| Projection::Planar { axis };
+        assert_eq!(
+            value(planar(2), Vec3F::new(0.1, 0.1, 7.0), up),
+            Color3F::zero()
+        );
+        assert_eq!(value(planar(2), Vec3F::new(0.3, 0.1, 7.0), up), white);
+        assert_eq!(value(planar(0), Vec3F::new(7.0, 0.1, 0.3), up), white);
+        assert_eq!(value(planar(1), Vec3F::new(0.1, 7.0, 0.3), up), white);
+
+        // (Z, Y) is an even cell and (X, Z) an odd one, the normal picks or blends them.
+        let triplanar = Projection::Triplanar { sharpness: 1.0 };
+        let p = Vec3F::new(0.3, 0.1, 0.1);
+        assert_eq!(
+            value(triplanar, p, Vec3F::new(1.0, 0.0, 0.0)),
+            Color3F::zero()
+        );
+        assert_eq!(value(triplanar, p, up), white);
+        let diagonal = Vec3F::new(1.0, 1.0, 0.0) / Fp::sqrt(2.0);
+        let blend = value(triplanar, p, diagonal);
+        assert!((blend - Color3F::new(0.5, 0.5, 0.5)).length() < 1e-5);
+        let sharp = value(
+            Projection::Triplanar { sharpness: 8.0 },
+            p,
+            Vec3F::new(0.6, 0.8, 0.0),
+        );
+        assert!(sharp.x > 0.9);
+
+        // (0.57, 0.37) around the center, cells 2 and 1, and (0.57, 0.63) below it, cells 2 and 2.
+        let p = Vec3F::new(1.0, 0.0, -0.5);
+        let spherical = |y| Projection::Spherical {
+            center: Vec3F::new(0.0, y, 0.0),
+        };
+        assert_eq!(value(spherical(0.5), p, up), white);
+        assert_eq!(value(spherical(-0.5), p, up), Color3F::zero());
+
+        // The height above the center gives the V coordinate.
+        let cylindrical = Projection::Cylindrical {
+            center: Vec3F::zero(),
+        };
+        assert_eq!(value(cylindrical, Vec3F::new(1.0, 0.3, -0.5), up), white);
+        assert_eq!(
+            value(cylindrical, Vec3F::new(1.0, 0.6, -0.5), up),
+            Color3F::zero()
+        );
+    }
 }
@@ -56,6 +56,15 @@ impl Vec3<Fp> {
         *self / self.length()
     }
 
+    /// Component along axis `i`, 0 for X, 1 for Y, 2 for Z.
+    pub fn axis(&self, i: usize) -> Fp {
+        match i {
+            0 => self.x,
+            1 => self.y,
+            _ => self.z,
+        }
+    }
+
     pub fn approx_zero(&self) -> bool {
         let threshold = 1e-8;
 
@@ -292,10 +301,8 @@ where
     )
 }
 
-// Transform a vector in local space (where `n` is the z-axis) to the world space.
-pub fn from_local_to_world_space(n: &Vec3F, local_v: &Vec3F) -> Vec3F {
-    // Construct the local space basis based `n`.
-
+// Construct an orthonormal basis [x, y, z] of a local space where `n` is the z-axis.
+pub fn local_basis(n: &Vec3F) -> [Vec3F; 3] {
     let local_axis_z = n.normalized();
     // Check if world x-axis is almost parallel with n.
     let tmp = if local_axis_z.x.abs() > 0.9 {
@@ -305,12 +312,430 @@ pub fn from_local_to_world_space(n: &Vec3F, local_v: &Vec3F) -> Vec3F {
     };
     let local_axis_y = cross(&local_axis_z, &tmp).normalized();
     let local_axis_x = cross(&local_axis_z, &local_axis_y);
+    [local_axis_x, local_axis_y, local_axis_z]
+}
+
+// Transform a vector in local space (where `n` is the z-axis) to the world space.
+pub fn from_local_to_world_space(n: &Vec3F, local_v: &Vec3F) -> Vec3F {
+    let [local_axis_x, local_axis_y, local_axis_z] = local_basis(n);
 
     // express local_v in the world space
     (local_v.x * local_axis_x) + (local_v.y * local_axis_y) + (local_v.z * local_axis_z)
 }
 
+// Perceived brightness of a linear sRGB color (Rec. 709 weights).
+pub fn luminance(color: &Color3F) -> Fp {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
 pub fn reflect(in_dir: &Vec3F, normal: &Vec3F) -> Vec3F {
     in_dir - 2.0 * dot(in_dir, normal) * normal
 }
 
+// The polynomial solvers below are adapted from Jochen Schwarze's "Cubic and Quartic Roots" in
+// Graphics Gems I. Coefficients are ordered from the constant term up, i.e. c[0] + c[1] * x +
+// c[2] * x^2 + ... They always work in f64 because quartics are numerically sensitive.
+
+const EQN_EPS: f64 = 1e-9;
+
+fn is_zero(x: f64) -> bool {
+    x > -EQN_EPS && x < EQN_EPS
+}
+
+// Real roots of a normalized quadratic (c[2] == 1).
+fn solve_quadratic_normalized(c: [f64; 3], roots: &mut Vec<f64>) {
+    let p = c[1] / 2.0;
+    let q = c[0];
+    let d = p * p - q;
+    if is_zero(d) {
+        roots.push(-p);
+    } else if d > 0.0 {
+        let sqrt_d = d.sqrt();
+        roots.push(sqrt_d - p);
+        roots.push(-sqrt_d - p);
+    }
+}
+
+// Real roots of a normalized cubic (c[3] == 1).
+fn solve_cubic_normalized(c: [f64; 4], roots: &mut Vec<f64>) {
+    let a = c[2];
+    let b = c[1];
+    let c = c[0];
+
+    // Substitute x = y - a / 3 to eliminate the quadratic term: y^3 + 3 * p * y + 2 * q = 0.
+    let sq_a = a * a;
+    let p = (-sq_a / 3.0 + b) / 3.0;
+    let q = (2.0 / 27.0 * a * sq_a - a * b / 3.0 + c) / 2.0;
+
+    // Cardano's formula.
+    let cb_p = p * p * p;
+    let d = q * q + cb_p;
+
+    let first = roots.len();
+    if is_zero(d) {
+        if is_zero(q) {
+            // One triple root.
+            roots.push(0.0);
+        } else {
+            // One single and one double root.
+            let u = (-q).cbrt();
+            roots.push(2.0 * u);
+            roots.push(-u);
+        }
+    } else if d < 0.0 {
+        // Three real roots, use the trigonometric solution.
+        let phi = (-q / (-cb_p).sqrt()).clamp(-1.0, 1.0).acos() / 3.0;
+        let t = 2.0 * (-p).sqrt();
+        roots.push(t * phi.cos());
+        roots.push(-t * (phi + std::f64::consts::PI / 3.0).cos());
+        roots.push(-t * (phi - std::f64::consts::PI / 3.0).cos());
+    } else {
+        // One real root.
+        let sqrt_d = d.sqrt();
+        let u = (sqrt_d - q).cbrt();
+        let v = -(sqrt_d + q).cbrt();
+        roots.push(u + v);
+    }
+
+    for root in roots[first..].iter_mut() {
+        *root -= a / 3.0;
+    }
+}
+
+/// Real roots of c[0] + c[1] * x + c[2] * x^2 + c[3] * x^3 + c[4] * x^4 = 0, in no particular
+/// order.
+pub fn solve_quartic(c: [f64; 5]) -> Vec<f64> {
+    let mut roots = Vec::with_capacity(4);
+
+    let a = c[3] / c[4];
+    let b = c[2] / c[4];
+    let cc = c[1] / c[4];
+    let d = c[0] / c[4];
+
+    // Substitute x = y - a / 4 to eliminate the cubic term: y^4 + p * y^2 + q * y + r = 0.
+    let sq_a = a * a;
+    let p = -3.0 / 8.0 * sq_a + b;
+    let q = sq_a * a / 8.0 - a * b / 2.0 + cc;
+    let r = -3.0 / 256.0 * sq_a * sq_a + sq_a * b / 16.0 - a * cc / 4.0 + d;
+
+    if is_zero(r) {
+        // No absolute term: y * (y^3 + p * y + q) = 0.
+        solve_cubic_normalized([q, p, 0.0, 1.0], &mut roots);
+        roots.push(0.0);
+    } else {
+        // Solve the resolvent cubic, and take one of its roots to build two quadratics.
+        let mut cubic_roots = Vec::with_capacity(3);
+        solve_cubic_normalized(
+            [r * p / 2.0 - q * q / 8.0, -r, -p / 2.0, 1.0],
+            &mut cubic_roots,
+        );
+        let z = cubic_roots[0];
+
+        let u = z * z - r;
+        let v = 2.0 * z - p;
+        let u = if is_zero(u) {
+            0.0
+        } else if u > 0.0 {
+            u.sqrt()
+        } else {
+            return roots;
+        };
+        let v = if is_zero(v) {
+            0.0
+        } else if v > 0.0 {
+            v.sqrt()
+        } else {
+            return roots;
+        };
+
+        let v_signed = if q < 0.0 { -v } else { v };
+        solve_quadratic_normalized([z - u, v_signed, 1.0], &mut roots);
+        solve_quadratic_normalized([z + u, -v_signed, 1.0], &mut roots);
+    }
+
+    for root in roots.iter_mut() {
+        *root -= a / 4.0;
+
+        // Polish the root with a few Newton iterations, the closed form loses a lot of precision.
+        for _ in 0..2 {
+            let x = *root;
+            let f = (((c[4] * x + c[3]) * x + c[2]) * x + c[1]) * x + c[0];
+            let df = ((4.0 * c[4] * x + 3.0 * c[3]) * x + 2.0 * c[2]) * x + c[1];
+            if df != 0.0 {
+                *root = x - f / df;
+            }
+        }
+    }
+
+    roots
+}
+
+/// A 4x4 matrix that transforms column vectors, stored in row-major order.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Mat4F {
+    pub m: [[Fp; 4]; 4],
+}
+
+impl Mat4F {
+    pub fn translate(v: Vec3F) -> Self {
+        Self {
+            m: [
+                [1.0, 0.0, 0.0, v.x],
+                [0.0, 1.0, 0.0, v.y],
+                [0.0, 0.0, 1.0, v.z],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+        }
+    }
+
+    pub fn scale(v: Vec3F) -> Self {
+        Self {
+            m: [
+                [v.x, 0.0, 0.0, 0.0],
+                [0.0, v.y, 0.0, 0.0],
+                [0.0, 0.0, v.z, 0.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+        }
+    }
+
+    /// Counter-clockwise rotation around `axis` (when looking from the tip of `axis` towards the
+    /// origin), in degrees.
+    pub fn rotate(axis: Vec3F, degree: Fp) -> Self {
+        // Rodrigues' rotation formula in matrix form.
+        let a = axis.normalized();
+        let (sin, cos) = Fp::sin_cos(degree.to_radians());
+        let k = 1.0 - cos;
+        Self {
+            m: [
+                [
+                    cos + a.x * a.x * k,
+                    a.x * a.y * k - a.z * sin,
+                    a.x * a.z * k + a.y * sin,
+                    0.0,
+                ],
+                [
+                    a.y * a.x * k + a.z * sin,
+                    cos + a.y * a.y * k,
+                    a.y * a.z * k - a.x * sin,
+                    0.0,
+                ],
+                [
+                    a.z * a.x * k - a.y * sin,
+                    a.z * a.y * k + a.x * sin,
+                    cos + a.z * a.z * k,
+                    0.0,
+                ],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+        }
+    }
+
+    /// Determinant of the upper-left 3x3 linear part, by how much the matrix scales volumes.
+    pub fn determinant(&self) -> Fp {
+        let m = &self.m;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            + m[0][1] * (m[1][2] * m[2][0] - m[1][0] * m[2][2])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    /// Inverse of an affine matrix, i.e. the last row must be [0, 0, 0, 1].
+    pub fn inverse_affine(&self) -> Self {
+        let m = &self.m;
+        debug_assert!(m[3] == [0.0, 0.0, 0.0, 1.0]);
+
+        // Invert the upper-left 3x3 linear part by its adjugate.
+        let c00 = m[1][1] * m[2][2] - m[1][2] * m[2][1];
+        let c01 = m[1][2] * m[2][0] - m[1][0] * m[2][2];
+        let c02 = m[1][0] * m[2][1] - m[1][1] * m[2][0];
+        let det = m[0][0] * c00 + m[0][1] * c01 + m[0][2] * c02;
+        assert!(det != 0.0, "singular transform");
+        let inv_det = 1.0 / det;
+
+        let mut inv = [[0.0; 4]; 4];
+        inv[0][0] = c00 * inv_det;
+        inv[0][1] = (m[0][2] * m[2][1] - m[0][1] * m[2][2]) * inv_det;
+        inv[0][2] = (m[0][1] * m[1][2] - m[0][2] * m[1][1]) * inv_det;
+        inv[1][0] = c01 * inv_det;
+        inv[1][1] = (m[0][0] * m[2][2] - m[0][2] * m[2][0]) * inv_det;
+        inv[1][2] = (m[0][2] * m[1][0] - m[0][0] * m[1][2]) * inv_det;
+        inv[2][0] = c02 * inv_det;
+        inv[2][1] = (m[0][1] * m[2][0] - m[0][0] * m[2][1]) * inv_det;
+        inv[2][2] = (m[0][0] * m[1][1] - m[0][1] * m[1][0]) * inv_det;
+
+        // The inverse translation is the original translation transformed by the inverse linear
+        // part, negated.
+        for inv_row in inv.iter_mut().take(3) {
+            inv_row[3] = -(inv_row[0] * m[0][3] + inv_row[1] * m[1][3] + inv_row[2] * m[2][3]);
+        }
+        inv[3][3] = 1.0;
+
+        Self { m: inv }
+    }
+
+    pub fn transform_point(&self, p: &Vec3F) -> Vec3F {
+        let m = &self.m;
+        Vec3F::new(
+            m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3],
+            m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3],
+            m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3],
+        )
+    }
+
+    pub fn transform_vector(&self, v: &Vec3F) -> Vec3F {
+        let m = &self.m;
+        Vec3F::new(
+            m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        )
+    }
+
+    /// Transform `v` by the transpose of this matrix. Surface normals are transformed by the
+    /// inverse transpose of the matrix that transforms points, so that they stay perpendicular to
+    /// the (possibly non-uniformly scaled) surface.
+    pub fn transform_vector_transposed(&self, v: &Vec3F) -> Vec3F {
+        let m = &self.m;
+        Vec3F::new(
+            m[0][0] * v.x + m[1][0] * v.y + m[2][0] * v.z,
+            m[0][1] * v.x + m[1][1] * v.y + m[2][1] * v.z,
+            m[0][2] * v.x + m[1][2] * v.y + m[2][2] * v.z,
+        )
+    }
+}
+
+impl Mul for Mat4F {
+    type Output = Mat4F;
+
+    fn mul(self, other: Mat4F) -> Self::Output {
+        let mut m = [[0.0; 4]; 4];
+        for (row, m_row) in m.iter_mut().enumerate() {
+            for (col, value) in m_row.iter_mut().enumerate() {
+                *value = (0..4).map(|k| self.m[row][k] * other.m[k][col]).sum();
+            }
+        }
+        Mat4F { m }
+    }
+}
+
+/// An affine transform from object space to world space, along with its inverse.
+#[derive(Debug, Copy, Clone)]
+pub struct Transform {
+    pub matrix: Mat4F,
+    pub inverse: Mat4F,
+}
+
+impl Transform {
+    pub fn new(matrix: Mat4F) -> Self {
+        Self {
+            matrix,
+            inverse: matrix.inverse_affine(),
+        }
+    }
+
+    pub fn point_to_world(&self, p: &Vec3F) -> Vec3F {
+        self.matrix.transform_point(p)
+    }
+
+    pub fn point_to_object(&self, p: &Vec3F) -> Vec3F {
+        self.inverse.transform_point(p)
+    }
+
+    pub fn vector_to_world(&self, v: &Vec3F) -> Vec3F {
+        self.matrix.transform_vector(v)
+    }
+
+    pub fn vector_to_object(&self, v: &Vec3F) -> Vec3F {
+        self.inverse.transform_vector(v)
+    }
+
+    /// The returned normal isn't normalized.
+    pub fn normal_to_world(&self, n: &Vec3F) -> Vec3F {
+        self.inverse.transform_vector_transposed(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted_roots(c: [f64; 5]) -> Vec<f64> {
+        let mut roots = solve_quartic(c);
+        roots.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        roots
+    }
+
+    fn assert_roots(roots: &[f64], expected: &[f64]) {
+        assert_eq!(roots.len(), expected.len(), "roots: {:?}", roots);
+        for (root, expected) in roots.iter().zip(expected.iter()) {
+            assert!((root - expected).abs() < 1e-9, "roots: {:?}", roots);
+        }
+    }
+
+    #[test]
+    fn quartic_with_four_roots() {
+        // (x - 1)(x - 2)(x - 3)(x - 4)
+        let roots = sorted_roots([24.0, -50.0, 35.0, -10.0, 1.0]);
+        assert_roots(&roots, &[1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn quartic_without_absolute_term() {
+        // x (x - 1)(x - 2)(x + 3), which is already depressed.
+        let roots = sorted_roots([0.0, 6.0, -7.0, 0.0, 1.0]);
+        assert_roots(&roots, &[-3.0, 0.0, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn quartic_with_two_or_no_roots() {
+        // 2 (x^2 - 1)(x^2 + 1), not monic.
+        let roots = sorted_roots([-2.0, 0.0, 0.0, 0.0, 2.0]);
+        assert_roots(&roots, &[-1.0, 1.0]);
+
+        assert!(solve_quartic([1.0, 0.0, 0.0, 0.0, 1.0]).is_empty());
+    }
+
+    #[test]
+    fn quartic_with_double_roots() {
+        // (x - 1)^2 (x - 3)^2, as for rays grazing a torus.
+        let roots = sorted_roots([9.0, -24.0, 22.0, -8.0, 1.0]);
+        assert!(!roots.is_empty());
+        for root in roots.iter() {
+            assert!(
+                (root - 1.0).abs() < 1e-6 || (root - 3.0).abs() < 1e-6,
+                "roots: {:?}",
+                roots
+            );
+        }
+    }
+
+    fn assert_matrix_eq(a: &Mat4F, b: &Mat4F) {
+        for (row_a, row_b) in a.m.iter().zip(b.m.iter()) {
+            for (x, y) in row_a.iter().zip(row_b.iter()) {
+                assert!((x - y).abs() < 1e-4, "{:?} != {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn inverse_affine_undoes_the_transform() {
+        let matrix = Mat4F::translate(Vec3F::new(1.0, -2.0, 3.0))
+            * Mat4F::rotate(Vec3F::new(1.0, 1.0, 0.0), 30.0)
+            * Mat4F::scale(Vec3F::new(2.0, 0.5, -3.0));
+        let inverse = matrix.inverse_affine();
+
+        let identity = Mat4F::scale(Vec3F::new(1.0, 1.0, 1.0));
+        assert_matrix_eq(&(matrix * inverse), &identity);
+        assert_matrix_eq(&(inverse * matrix), &identity);
+
+        let p = Vec3F::new(0.5, 4.0, -1.0);
+        let q = inverse.transform_point(&matrix.transform_point(&p));
+        assert!((q - p).length() < 1e-4);
+    }
+
+    #[test]
+    fn inverse_affine_of_a_translation() {
+        let inverse = Mat4F::translate(Vec3F::new(1.0, 2.0, 3.0)).inverse_affine();
+        assert_matrix_eq(&inverse, &Mat4F::translate(Vec3F::new(-1.0, -2.0, -3.0)));
+    }
+}